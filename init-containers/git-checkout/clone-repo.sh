@@ -6,11 +6,21 @@ echo "Cloning down the repo..."
 
 mkdir repo
 
-git clone $REPO_URL repo
+# The credentials are only passed on the command line so they are never written to the
+# .git/config that gets copied into the container volumes
+git -c credential.helper="store --file=$GIT_CREDENTIALS_FILE" clone $REPO_URL repo
 
 cd repo
 
-git checkout $COMMIT_SHA
+if [ -n "${GIT_REF:-}" ]; then
+    echo "Fetching $GIT_REF..."
+
+    git -c credential.helper="store --file=$GIT_CREDENTIALS_FILE" fetch origin $GIT_REF
+
+    git checkout FETCH_HEAD
+else
+    git checkout $COMMIT_SHA
+fi
 
 echo "Successfully cloned the repo! Copying files to container volumes..."
 
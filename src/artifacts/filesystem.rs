@@ -0,0 +1,239 @@
+use crate::artifacts::{Artifact, ContentStream};
+use futures::TryStreamExt;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use warp::hyper::body::Bytes;
+
+// Streamed uploads are written here first, outside of every key, so nothing sees them half written
+const PARTIAL_UPLOADS_DIRECTORY: &str = ".partial";
+
+static PARTIAL_UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+// Artifacts on a local directory, usually a persistent volume mounted into the controller
+#[derive(Clone)]
+pub struct FilesystemArtifactStore {
+    pub root: PathBuf,
+}
+
+impl FilesystemArtifactStore {
+    pub async fn put(&self, key: &str, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path(key)?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::write(path, contents).await?;
+
+        Ok(())
+    }
+
+    pub async fn put_stream(
+        &self,
+        key: &str,
+        contents: ContentStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path(key)?;
+
+        let partial_directory = self.root.join(PARTIAL_UPLOADS_DIRECTORY);
+
+        tokio::fs::create_dir_all(&partial_directory).await?;
+
+        let partial_path = partial_directory.join(format!(
+            "{}-{}",
+            std::process::id(),
+            PARTIAL_UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        if let Err(error) = write_stream(&partial_path, contents).await {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+
+            return Err(error.into());
+        }
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::rename(partial_path, path).await?;
+
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>, Box<dyn std::error::Error>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(contents) => Ok(Some(Bytes::from(contents))),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    // Read as it's sent on, so big artifacts aren't held in memory
+    pub async fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<Option<ContentStream>, Box<dyn std::error::Error>> {
+        let file = match tokio::fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let length = file.metadata().await?.len();
+
+        Ok(Some(ContentStream {
+            length,
+            chunks: Box::pin(
+                FramedRead::new(file, BytesCodec::new()).map_ok(|chunk| chunk.freeze()),
+            ),
+        }))
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub async fn list(&self, prefix: &str) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        let prefix_directory = self.path(prefix)?;
+
+        let mut artifacts = Vec::new();
+        let mut directories = vec![prefix_directory.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = match tokio::fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(error) if error.kind() == ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                } else {
+                    artifacts.push(Artifact {
+                        path: relative_path(&prefix_directory, &entry.path()),
+                        size: metadata.len(),
+                    });
+                }
+            }
+        }
+
+        Ok(artifacts)
+    }
+
+    // Keys are made from URLs, so anything that could leave the root is refused. They're checked
+    // component by component, as files about to be written can't be canonicalised.
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+        let is_within_root = Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if is_within_root {
+            Ok(self.root.join(key))
+        } else {
+            Err(format!("{} is outside of the artifact store", key))
+        }
+    }
+}
+
+async fn write_stream(path: &Path, mut contents: ContentStream) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+
+    while let Some(chunk) = contents.chunks.try_next().await? {
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+
+    Ok(())
+}
+
+fn relative_path(prefix_directory: &Path, path: &Path) -> String {
+    path.strip_prefix(prefix_directory)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_list_artifacts_under_a_prefix() {
+        let root = std::env::temp_dir().join(format!("kubesci-artifacts-{}", std::process::id()));
+
+        let store = FilesystemArtifactStore { root: root.clone() };
+
+        store.put("repo/abc/dist/app", b"binary").await.unwrap();
+        store.put("repo/abc/report.txt", b"report").await.unwrap();
+        store.put("repo/def/other.txt", b"other").await.unwrap();
+        store
+            .put_stream("repo/abc/streamed/log.txt", Bytes::from("streamed").into())
+            .await
+            .unwrap();
+
+        let mut artifacts = store.list("repo/abc/").await.unwrap();
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(
+            artifacts,
+            vec![
+                Artifact {
+                    path: "dist/app".to_string(),
+                    size: 6
+                },
+                Artifact {
+                    path: "report.txt".to_string(),
+                    size: 6
+                },
+                Artifact {
+                    path: "streamed/log.txt".to_string(),
+                    size: 8
+                },
+            ]
+        );
+        assert_eq!(
+            store.get("repo/abc/dist/app").await.unwrap(),
+            Some(Bytes::from("binary"))
+        );
+        assert_eq!(store.get("repo/abc/missing").await.unwrap(), None);
+
+        let streamed = store
+            .get_stream("repo/abc/report.txt")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(streamed.length, 6);
+        assert_eq!(
+            streamed
+                .chunks
+                .try_collect::<Vec<Bytes>>()
+                .await
+                .unwrap()
+                .concat(),
+            b"report"
+        );
+        assert!(store
+            .get_stream("repo/abc/missing")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store.list("repo/missing/").await.unwrap().is_empty());
+        assert!(store.get("repo/../../etc/passwd").await.is_err());
+        assert!(store.list("/etc").await.is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
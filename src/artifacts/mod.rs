@@ -0,0 +1,1146 @@
+pub mod filesystem;
+pub mod s3;
+
+use chrono::{DateTime, Utc};
+use filesystem::FilesystemArtifactStore;
+use futures::lock::Mutex;
+use futures::Stream;
+use hmac::{Hmac, Mac, NewMac};
+use regex::Regex;
+use s3::S3ArtifactStore;
+use serde_derive::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::UNIX_EPOCH;
+use warp::hyper::body::Bytes;
+
+const CACHES_PREFIX: &str = "caches";
+// When each cache was last saved or restored, for evicting the least recently used. It's kept in
+// one object so saving a cache doesn't read one for every other cache.
+const CACHE_USAGE_KEY: &str = "cache-usage-index";
+const PIPELINE_UPLOADS_PREFIX: &str = "pipeline-uploads";
+// The last step section of a build the pipeline has moved past, kept with its uploads
+const FINISHED_STEP_SECTION_KEY: &str = "finished-step-section";
+// The files each build changed, worked out once when it starts so every step section agrees
+const CHANGED_FILES_PREFIX: &str = "changed-files";
+// Each build's pipeline with its includes resolved when it starts, so every step section agrees
+const RESOLVED_PIPELINES_PREFIX: &str = "resolved-pipelines";
+
+// The cache usage is read and written back whole, so saves and restores take turns with it. The
+// lock only covers this process, which is enough as the controller runs as a single replica. More
+// replicas could lose each other's usage, making caches look older than they are.
+static CACHE_USAGE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Artifact {
+    pub path: String,
+    pub size: u64,
+}
+
+// Steps uploaded by a step while the pipeline runs, to run after the step section it's in
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineUpload {
+    pub step_section: usize,
+    pub steps: String,
+}
+
+// An upload's or download's body, passed on as it arrives rather than held in memory
+pub struct ContentStream {
+    pub length: u64,
+    pub chunks: Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync>>,
+}
+
+impl From<Bytes> for ContentStream {
+    fn from(contents: Bytes) -> Self {
+        ContentStream {
+            length: contents.len() as u64,
+            chunks: Box::pin(futures::stream::once(async { Ok(contents) })),
+        }
+    }
+}
+
+// Artifacts are kept per commit, under the same repo name the pod labels use
+#[derive(Clone)]
+pub enum ArtifactStore {
+    Filesystem(FilesystemArtifactStore),
+    S3(S3ArtifactStore),
+}
+
+impl ArtifactStore {
+    pub async fn put(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        path: &str,
+        contents: ContentStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        validate_commit(repo_name, commit_sha)?;
+        validate_artifact_path(path)?;
+
+        self.put_object_stream(&artifact_key(repo_name, commit_sha, path), contents)
+            .await
+    }
+
+    pub async fn get(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        path: &str,
+    ) -> Result<Option<ContentStream>, Box<dyn std::error::Error>> {
+        validate_commit(repo_name, commit_sha)?;
+        validate_artifact_path(path)?;
+
+        self.get_object_stream(&artifact_key(repo_name, commit_sha, path))
+            .await
+    }
+
+    pub async fn list(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+    ) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        validate_commit(repo_name, commit_sha)?;
+
+        let mut artifacts = self
+            .list_objects(&artifact_key(repo_name, commit_sha, ""))
+            .await?;
+
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(artifacts)
+    }
+
+    // Caches are kept per branch rather than per commit, so later commits can restore them.
+    // Builds can only save caches for their own branch, so a branch can't poison another's.
+    pub async fn put_cache(
+        &self,
+        repo_name: &str,
+        branch_name: &str,
+        cache_key: &str,
+        contents: ContentStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        validate_repo_name(repo_name)?;
+        validate_cache_key(cache_key)?;
+
+        let path = cache_path(branch_name, cache_key);
+
+        self.put_object_stream(&repo_key(CACHES_PREFIX, repo_name, &path), contents)
+            .await?;
+
+        self.mark_cache_used(repo_name, &path).await
+    }
+
+    // New branches start from the caches of the fallback branch, usually the default branch
+    pub async fn get_cache(
+        &self,
+        repo_name: &str,
+        branch_name: &str,
+        fallback_branch_name: Option<&str>,
+        cache_key: &str,
+    ) -> Result<Option<ContentStream>, Box<dyn std::error::Error>> {
+        validate_repo_name(repo_name)?;
+        validate_cache_key(cache_key)?;
+
+        for branch_name in std::iter::once(branch_name).chain(fallback_branch_name) {
+            let path = cache_path(branch_name, cache_key);
+
+            let contents = self
+                .get_object_stream(&repo_key(CACHES_PREFIX, repo_name, &path))
+                .await?;
+
+            if contents.is_some() {
+                self.mark_cache_used(repo_name, &path).await?;
+
+                return Ok(contents);
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Removes the least recently used caches until the rest fit in the max size, returning the
+    // evicted ones
+    pub async fn evict_caches(
+        &self,
+        max_size: u64,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let _cache_usage_lock = CACHE_USAGE_LOCK.lock().await;
+
+        let caches = self.list_objects(&format!("{}/", CACHES_PREFIX)).await?;
+
+        let mut cache_usage = self.get_cache_usage().await?;
+
+        // Usage of caches that are gone, such as ones evicted before their last use was saved
+        cache_usage.retain(|path, _| caches.iter().any(|cache| cache.path == *path));
+
+        let mut caches_with_last_used: Vec<(Artifact, DateTime<Utc>)> = caches
+            .into_iter()
+            .map(|cache| {
+                let last_used = cache_usage
+                    .get(&cache.path)
+                    .copied()
+                    .unwrap_or_else(|| DateTime::<Utc>::from(UNIX_EPOCH));
+
+                (cache, last_used)
+            })
+            .collect();
+
+        caches_with_last_used.sort_by_key(|(_, last_used)| *last_used);
+
+        let mut total_size: u64 = caches_with_last_used
+            .iter()
+            .map(|(cache, _)| cache.size)
+            .sum();
+
+        let mut evicted = Vec::new();
+
+        for (cache, _) in caches_with_last_used {
+            if total_size <= max_size {
+                break;
+            }
+
+            self.delete_object(&format!("{}/{}", CACHES_PREFIX, cache.path))
+                .await?;
+
+            cache_usage.remove(&cache.path);
+
+            total_size -= cache.size;
+            evicted.push(cache.path);
+        }
+
+        self.put_cache_usage(&cache_usage).await?;
+
+        Ok(evicted)
+    }
+
+    // Each step keeps only its latest upload, so rerunning it replaces rather than adds steps
+    pub async fn put_pipeline_upload(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+        step_section: usize,
+        step_id: &str,
+        steps: Bytes,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Step ids are hex encoded step names, so are always a single path segment
+        if hex::decode(step_id).is_err() {
+            return Err(format!("{} is not a valid step id", step_id).into());
+        }
+
+        self.put_object(
+            &pipeline_upload_key(
+                repo_name,
+                commit_sha,
+                build,
+                &format!("{}/{}", step_section, step_id),
+            ),
+            steps,
+        )
+        .await
+    }
+
+    // In the order they're inserted into the pipeline: by step section, then by step
+    pub async fn get_pipeline_uploads(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+    ) -> Result<Vec<PipelineUpload>, Box<dyn std::error::Error>> {
+        let prefix = pipeline_upload_key(repo_name, commit_sha, build, "");
+
+        let mut objects: Vec<(usize, String)> = self
+            .list_objects(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|object| {
+                let step_section = object.path.split('/').next()?.parse().ok()?;
+
+                Some((step_section, object.path))
+            })
+            .collect();
+
+        objects.sort();
+
+        let mut uploads = Vec::with_capacity(objects.len());
+
+        for (step_section, path) in objects {
+            if let Some(steps) = self.get_object(&format!("{}{}", prefix, path)).await? {
+                uploads.push(PipelineUpload {
+                    step_section,
+                    steps: String::from_utf8_lossy(&steps).into_owned(),
+                });
+            }
+        }
+
+        Ok(uploads)
+    }
+
+    // Steps uploaded into a section the pipeline has moved past would shift the sections after it,
+    // so they're turned away
+    pub async fn finish_pipeline_upload_section(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+        step_section: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_object(
+            &pipeline_upload_key(repo_name, commit_sha, build, FINISHED_STEP_SECTION_KEY),
+            Bytes::from(step_section.to_string()),
+        )
+        .await
+    }
+
+    pub async fn is_pipeline_upload_section_finished(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+        step_section: usize,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let finished_step_section = self
+            .get_object(&pipeline_upload_key(
+                repo_name,
+                commit_sha,
+                build,
+                FINISHED_STEP_SECTION_KEY,
+            ))
+            .await?
+            .and_then(|finished_step_section| {
+                String::from_utf8_lossy(&finished_step_section)
+                    .parse::<usize>()
+                    .ok()
+            });
+
+        Ok(finished_step_section
+            .map(|finished_step_section| step_section <= finished_step_section)
+            .unwrap_or(false))
+    }
+
+    pub async fn delete_pipeline_uploads(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let prefix = pipeline_upload_key(repo_name, commit_sha, build, "");
+
+        let objects = self.list_objects(&prefix).await?;
+
+        for object in objects {
+            self.delete_object(&format!("{}{}", prefix, object.path))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn put_changed_files(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+        changed_files: &[String],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_object(
+            &changed_files_key(repo_name, commit_sha, build),
+            Bytes::from(changed_files.join("\n")),
+        )
+        .await
+    }
+
+    // None when the changed files aren't known, such as for the first build of a branch
+    pub async fn get_changed_files(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+    ) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+        let maybe_changed_files = self
+            .get_object(&changed_files_key(repo_name, commit_sha, build))
+            .await?;
+
+        Ok(maybe_changed_files.map(|changed_files| {
+            String::from_utf8_lossy(&changed_files)
+                .lines()
+                .map(|line| line.to_string())
+                .collect()
+        }))
+    }
+
+    pub async fn delete_changed_files(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.delete_object(&changed_files_key(repo_name, commit_sha, build))
+            .await
+    }
+
+    pub async fn put_resolved_pipeline(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+        pipeline: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_object(
+            &resolved_pipeline_key(repo_name, commit_sha, build),
+            Bytes::from(pipeline.to_string()),
+        )
+        .await
+    }
+
+    // None for builds started before their pipeline was kept
+    pub async fn get_resolved_pipeline(
+        &self,
+        repo_name: &str,
+        commit_sha: &str,
+        build: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let maybe_pipeline = self
+            .get_object(&resolved_pipeline_key(repo_name, commit_sha, build))
+            .await?;
+
+        Ok(maybe_pipeline.map(|pipeline| String::from_utf8_lossy(&pipeline).into_owned()))
+    }
+
+    async fn mark_cache_used(
+        &self,
+        repo_name: &str,
+        cache_path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _cache_usage_lock = CACHE_USAGE_LOCK.lock().await;
+
+        let mut cache_usage = self.get_cache_usage().await?;
+
+        cache_usage.insert(
+            format!("{}/{}", repo_name.replace("/", "."), cache_path),
+            Utc::now(),
+        );
+
+        self.put_cache_usage(&cache_usage).await
+    }
+
+    // Keyed by the cache's path under the caches prefix
+    async fn get_cache_usage(
+        &self,
+    ) -> Result<HashMap<String, DateTime<Utc>>, Box<dyn std::error::Error>> {
+        Ok(self
+            .get_object(CACHE_USAGE_KEY)
+            .await?
+            .and_then(|cache_usage| serde_json::from_slice(&cache_usage).ok())
+            .unwrap_or_default())
+    }
+
+    async fn put_cache_usage(
+        &self,
+        cache_usage: &HashMap<String, DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_object(
+            CACHE_USAGE_KEY,
+            Bytes::from(serde_json::to_vec(cache_usage)?),
+        )
+        .await
+    }
+
+    async fn put_object(
+        &self,
+        key: &str,
+        contents: Bytes,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ArtifactStore::Filesystem(store) => store.put(key, &contents).await,
+            ArtifactStore::S3(store) => store.put(key, contents).await,
+        }
+    }
+
+    async fn put_object_stream(
+        &self,
+        key: &str,
+        contents: ContentStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ArtifactStore::Filesystem(store) => store.put_stream(key, contents).await,
+            ArtifactStore::S3(store) => store.put_stream(key, contents).await,
+        }
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Option<Bytes>, Box<dyn std::error::Error>> {
+        match self {
+            ArtifactStore::Filesystem(store) => store.get(key).await,
+            ArtifactStore::S3(store) => store.get(key).await,
+        }
+    }
+
+    async fn get_object_stream(
+        &self,
+        key: &str,
+    ) -> Result<Option<ContentStream>, Box<dyn std::error::Error>> {
+        match self {
+            ArtifactStore::Filesystem(store) => store.get_stream(key).await,
+            ArtifactStore::S3(store) => store.get_stream(key).await,
+        }
+    }
+
+    async fn list_objects(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        match self {
+            ArtifactStore::Filesystem(store) => store.list(prefix).await,
+            ArtifactStore::S3(store) => store.list(prefix).await,
+        }
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ArtifactStore::Filesystem(store) => store.delete(key).await,
+            ArtifactStore::S3(store) => store.delete(key).await,
+        }
+    }
+}
+
+fn artifact_key(repo_name: &str, commit_sha: &str, path: &str) -> String {
+    format!("{}/{}/{}", repo_name.replace("/", "."), commit_sha, path)
+}
+
+// Repo names always have a dot once dotted, so can't clash with these prefixes
+fn repo_key(prefix: &str, repo_name: &str, key: &str) -> String {
+    format!("{}/{}/{}", prefix, repo_name.replace("/", "."), key)
+}
+
+// Branch names can have slashes, so they're encoded to stay a single path segment
+fn cache_path(branch_name: &str, cache_key: &str) -> String {
+    format!("{}/{}", uri_encode(branch_name, true), cache_key)
+}
+
+fn pipeline_upload_key(repo_name: &str, commit_sha: &str, build: &str, key: &str) -> String {
+    repo_key(
+        PIPELINE_UPLOADS_PREFIX,
+        repo_name,
+        &format!("{}/{}/{}", commit_sha, build, key),
+    )
+}
+
+fn changed_files_key(repo_name: &str, commit_sha: &str, build: &str) -> String {
+    repo_key(
+        CHANGED_FILES_PREFIX,
+        repo_name,
+        &format!("{}/{}", commit_sha, build),
+    )
+}
+
+fn resolved_pipeline_key(repo_name: &str, commit_sha: &str, build: &str) -> String {
+    repo_key(
+        RESOLVED_PIPELINES_PREFIX,
+        repo_name,
+        &format!("{}/{}", commit_sha, build),
+    )
+}
+
+// Paths come from pods, so can't be allowed to escape the commit's artifacts
+pub fn validate_artifact_path(path: &str) -> Result<(), String> {
+    let is_valid = !path.is_empty()
+        && !path.starts_with('/')
+        && path
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(format!("{} is not a valid artifact path", path))
+    }
+}
+
+// Repo names and commit shas come from URLs too, as `owner/repo` or the dotted `owner.repo`. Owners
+// can't have dots, so a dotted repo name is never `.` or `..`.
+pub fn validate_repo_name(repo_name: &str) -> Result<(), String> {
+    let regex = Regex::new(r"^[A-Za-z0-9-]+[./][A-Za-z0-9_.-]+$").unwrap();
+
+    if regex.is_match(repo_name) {
+        Ok(())
+    } else {
+        Err(format!("{} is not a valid repo name", repo_name))
+    }
+}
+
+fn validate_commit(repo_name: &str, commit_sha: &str) -> Result<(), String> {
+    validate_repo_name(repo_name)?;
+
+    let regex = Regex::new(r"^[0-9a-f]{40}$").unwrap();
+
+    if regex.is_match(commit_sha) {
+        Ok(())
+    } else {
+        Err(format!("{} is not a valid commit sha", commit_sha))
+    }
+}
+
+// Cache keys are rendered in pods, so are kept to characters that are safe in a path and a URL
+pub fn validate_cache_key(cache_key: &str) -> Result<(), String> {
+    let regex = Regex::new(r"^[A-Za-z0-9._-]+$").unwrap();
+
+    if regex.is_match(cache_key) && cache_key != "." && cache_key != ".." {
+        Ok(())
+    } else {
+        Err(format!("{} is not a valid cache key", cache_key))
+    }
+}
+
+// `*` and `?` stay within a directory, `**` matches across them
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    let mut pattern = "^".to_string();
+    let mut characters = glob.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        match character {
+            '*' if characters.peek() == Some(&'*') => {
+                characters.next();
+                pattern += ".*";
+            }
+            '*' => pattern += "[^/]*",
+            '?' => pattern += "[^/]",
+            other => pattern += &regex::escape(&other.to_string()),
+        }
+    }
+
+    pattern += "$";
+
+    Regex::new(&pattern)
+        .map(|regex| regex.is_match(path))
+        .unwrap_or(false)
+}
+
+// Step pods are given a token for their commit, so they can only upload that commit's artifacts
+pub fn artifact_token(secret: &str, repo_name: &str, commit_sha: &str) -> String {
+    scoped_token(
+        &token_key(secret, "artifacts"),
+        &artifact_key(repo_name, commit_sha, ""),
+    )
+}
+
+pub fn verify_artifact_token(secret: &str, repo_name: &str, commit_sha: &str, token: &str) -> bool {
+    verify_scoped_token(
+        &token_key(secret, "artifacts"),
+        &artifact_key(repo_name, commit_sha, ""),
+        token,
+    )
+}
+
+// Scoped to the build's branch, which is the only one it can save caches for
+pub fn cache_token(secret: &str, repo_name: &str, branch_name: &str) -> String {
+    scoped_token(
+        &token_key(secret, CACHES_PREFIX),
+        &repo_key(CACHES_PREFIX, repo_name, &cache_path(branch_name, "")),
+    )
+}
+
+pub fn verify_cache_token(secret: &str, repo_name: &str, branch_name: &str, token: &str) -> bool {
+    verify_scoped_token(
+        &token_key(secret, CACHES_PREFIX),
+        &repo_key(CACHES_PREFIX, repo_name, &cache_path(branch_name, "")),
+        token,
+    )
+}
+
+// Scoped to the step uploading, in its build and step section, so it can't add steps for others
+pub fn pipeline_upload_token(
+    secret: &str,
+    repo_name: &str,
+    commit_sha: &str,
+    build: &str,
+    step_section: usize,
+    step_id: &str,
+) -> String {
+    scoped_token(
+        &token_key(secret, PIPELINE_UPLOADS_PREFIX),
+        &pipeline_upload_key(
+            repo_name,
+            commit_sha,
+            build,
+            &format!("{}/{}", step_section, step_id),
+        ),
+    )
+}
+
+pub fn verify_pipeline_upload_token(
+    secret: &str,
+    repo_name: &str,
+    commit_sha: &str,
+    build: &str,
+    step_section: usize,
+    step_id: &str,
+    token: &str,
+) -> bool {
+    verify_scoped_token(
+        &token_key(secret, PIPELINE_UPLOADS_PREFIX),
+        &pipeline_upload_key(
+            repo_name,
+            commit_sha,
+            build,
+            &format!("{}/{}", step_section, step_id),
+        ),
+        token,
+    )
+}
+
+// Each kind of token is signed with its own key, derived from the webhook secret, so none of them
+// can stand in for another or for a webhook signature
+fn token_key(secret: &str, purpose: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+
+    mac.update(format!("kubesci-{}-token", purpose).as_bytes());
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn scoped_token(key: &[u8], scope: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+
+    mac.update(scope.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn verify_scoped_token(key: &[u8], scope: &str, token: &str) -> bool {
+    match (hex::decode(token), Hmac::<Sha256>::new_varkey(key)) {
+        (Ok(expected_token), Ok(mut mac)) => {
+            mac.update(scope.as_bytes());
+
+            mac.verify(&expected_token).is_ok()
+        }
+        _ => false,
+    }
+}
+
+// Percent-encodes everything but the characters RFC 3986 leaves unreserved
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            other => format!("%{:02X}", other),
+        })
+        .collect()
+}
+
+pub fn uri_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let maybe_byte = match bytes.get(index..index + 3) {
+            Some([b'%', high, low]) => hex::decode([*high, *low])
+                .ok()
+                .map(|decoded_bytes| decoded_bytes[0]),
+            _ => None,
+        };
+
+        match maybe_byte {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn should_match_globs_within_and_across_directories() {
+        assert!(glob_matches("dist/*.tar.gz", "dist/app.tar.gz"));
+        assert!(!glob_matches("dist/*.tar.gz", "dist/linux/app.tar.gz"));
+        assert!(glob_matches("dist/**.tar.gz", "dist/linux/app.tar.gz"));
+        assert!(glob_matches("target/release/app?", "target/release/app1"));
+        assert!(!glob_matches("target/release/app", "target/release/app.d"));
+    }
+
+    #[test]
+    fn should_reject_paths_outside_the_commit() {
+        assert!(validate_artifact_path("dist/app.tar.gz").is_ok());
+        assert!(validate_artifact_path("../other-commit/app").is_err());
+        assert!(validate_artifact_path("dist/../../app").is_err());
+        assert!(validate_artifact_path("/etc/passwd").is_err());
+        assert!(validate_artifact_path("").is_err());
+    }
+
+    #[test]
+    fn should_reject_repo_names_and_commit_shas_outside_the_store() {
+        let commit_sha = "0123456789abcdef0123456789abcdef01234567";
+
+        assert!(validate_commit("some/repo", commit_sha).is_ok());
+        assert!(validate_commit("some.repo.rs", commit_sha).is_ok());
+        assert!(validate_commit("..", commit_sha).is_err());
+        assert!(validate_commit("some/../..", commit_sha).is_err());
+        assert!(validate_commit("some.repo", "..").is_err());
+        assert!(validate_commit("some.repo", "abcdef").is_err());
+    }
+
+    #[test]
+    fn should_only_accept_tokens_for_the_same_commit() {
+        let token = artifact_token("some-secret", "some/repo", "abcdef");
+
+        assert!(verify_artifact_token(
+            "some-secret",
+            "some/repo",
+            "abcdef",
+            &token
+        ));
+        assert!(!verify_artifact_token(
+            "some-secret",
+            "some/repo",
+            "123456",
+            &token
+        ));
+        assert!(!verify_artifact_token(
+            "some-secret",
+            "some/repo",
+            "abcdef",
+            "not-hex"
+        ));
+    }
+
+    #[test]
+    fn should_not_sign_artifact_tokens_with_the_webhook_secret() {
+        let scope = artifact_key("some/repo", "abcdef", "");
+
+        assert_ne!(
+            artifact_token("some-secret", "some/repo", "abcdef"),
+            scoped_token(b"some-secret", &scope)
+        );
+    }
+
+    #[test]
+    fn should_only_accept_cache_tokens_for_the_same_repo_and_branch() {
+        let token = cache_token("some-secret", "some/repo", "feature/x");
+
+        assert!(verify_cache_token(
+            "some-secret",
+            "some/repo",
+            "feature/x",
+            &token
+        ));
+        assert!(!verify_cache_token(
+            "some-secret",
+            "other/repo",
+            "feature/x",
+            &token
+        ));
+        assert!(!verify_cache_token(
+            "some-secret",
+            "some/repo",
+            "main",
+            &token
+        ));
+        assert!(!verify_artifact_token(
+            "some-secret",
+            "some/repo",
+            "",
+            &token
+        ));
+    }
+
+    #[test]
+    fn should_reject_cache_keys_that_are_not_a_single_path_segment() {
+        assert!(validate_cache_key("cargo-0123abcd").is_ok());
+        assert!(validate_cache_key("cargo/0123abcd").is_err());
+        assert!(validate_cache_key("..").is_err());
+        assert!(validate_cache_key("").is_err());
+    }
+
+    #[tokio::test]
+    async fn should_evict_the_least_recently_used_caches() {
+        let root = std::env::temp_dir().join(format!("kubesci-caches-{}", std::process::id()));
+
+        let store = ArtifactStore::Filesystem(FilesystemArtifactStore { root: root.clone() });
+
+        store
+            .put_cache("some/repo", "main", "new", Bytes::from("12345").into())
+            .await
+            .unwrap();
+        store
+            .put_cache("some/repo", "main", "old", Bytes::from("12345").into())
+            .await
+            .unwrap();
+
+        let mut cache_usage = store.get_cache_usage().await.unwrap();
+        cache_usage.insert(
+            "some.repo/main/old".to_string(),
+            DateTime::<Utc>::from(UNIX_EPOCH),
+        );
+        store.put_cache_usage(&cache_usage).await.unwrap();
+
+        assert_eq!(
+            store.evict_caches(8).await.unwrap(),
+            vec!["some.repo/main/old".to_string()]
+        );
+        assert!(store
+            .get_cache("some/repo", "main", None, "old")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            store
+                .get_cache("some/repo", "main", None, "new")
+                .await
+                .unwrap()
+                .unwrap()
+                .chunks
+                .try_collect::<Vec<Bytes>>()
+                .await
+                .unwrap()
+                .concat(),
+            b"12345"
+        );
+        assert!(store.evict_caches(8).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_restore_caches_from_the_fallback_branch_but_only_save_them_to_its_own() {
+        let root = std::env::temp_dir().join(format!("kubesci-branches-{}", std::process::id()));
+
+        let store = ArtifactStore::Filesystem(FilesystemArtifactStore { root: root.clone() });
+
+        store
+            .put_cache("some/repo", "main", "cargo", Bytes::from("main").into())
+            .await
+            .unwrap();
+
+        let restore = |branch_name| {
+            let store = store.clone();
+
+            async move {
+                store
+                    .get_cache("some/repo", branch_name, Some("main"), "cargo")
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .chunks
+                    .try_collect::<Vec<Bytes>>()
+                    .await
+                    .unwrap()
+                    .concat()
+            }
+        };
+
+        assert_eq!(restore("feature/x").await, b"main");
+
+        store
+            .put_cache(
+                "some/repo",
+                "feature/x",
+                "cargo",
+                Bytes::from("feature").into(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(restore("feature/x").await, b"feature");
+        assert_eq!(restore("main").await, b"main");
+        assert!(store
+            .get_cache("some/repo", "feature/y", None, "cargo")
+            .await
+            .unwrap()
+            .is_none());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_latest_pipeline_upload_of_each_step_in_section_order() {
+        let root = std::env::temp_dir().join(format!("kubesci-uploads-{}", std::process::id()));
+
+        let store = ArtifactStore::Filesystem(FilesystemArtifactStore { root: root.clone() });
+
+        for (step_section, step_id, steps) in &[
+            (10, "61", "steps: [c]"),
+            (2, "62", "steps: [b]"),
+            (2, "61", "steps: [old]"),
+            (2, "61", "steps: [a]"),
+        ] {
+            store
+                .put_pipeline_upload(
+                    "some/repo",
+                    "abcdef",
+                    "push",
+                    *step_section,
+                    step_id,
+                    Bytes::from(*steps),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert!(store
+            .put_pipeline_upload("some/repo", "abcdef", "push", 0, "../x", Bytes::new())
+            .await
+            .is_err());
+        assert_eq!(
+            store
+                .get_pipeline_uploads("some/repo", "abcdef", "push")
+                .await
+                .unwrap()
+                .iter()
+                .map(|upload| (upload.step_section, upload.steps.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "steps: [a]"), (2, "steps: [b]"), (10, "steps: [c]")]
+        );
+        assert!(store
+            .get_pipeline_uploads("some/repo", "abcdef", "pull-request-1")
+            .await
+            .unwrap()
+            .is_empty());
+
+        store
+            .finish_pipeline_upload_section("some/repo", "abcdef", "push", 2)
+            .await
+            .unwrap();
+
+        assert!(store
+            .is_pipeline_upload_section_finished("some/repo", "abcdef", "push", 2)
+            .await
+            .unwrap());
+        assert!(!store
+            .is_pipeline_upload_section_finished("some/repo", "abcdef", "push", 3)
+            .await
+            .unwrap());
+        assert_eq!(
+            store
+                .get_pipeline_uploads("some/repo", "abcdef", "push")
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        store
+            .delete_pipeline_uploads("some/repo", "abcdef", "push")
+            .await
+            .unwrap();
+
+        assert!(!store
+            .is_pipeline_upload_section_finished("some/repo", "abcdef", "push", 2)
+            .await
+            .unwrap());
+
+        assert!(store
+            .get_pipeline_uploads("some/repo", "abcdef", "push")
+            .await
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_resolved_pipeline_of_each_build() {
+        let root =
+            std::env::temp_dir().join(format!("kubesci-resolved-pipelines-{}", std::process::id()));
+
+        let store = ArtifactStore::Filesystem(FilesystemArtifactStore { root: root.clone() });
+
+        store
+            .put_resolved_pipeline("some/repo", "abcdef", "push", "steps: []")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store
+                .get_resolved_pipeline("some/repo", "abcdef", "push")
+                .await
+                .unwrap(),
+            Some("steps: []".to_string())
+        );
+        assert_eq!(
+            store
+                .get_resolved_pipeline("some/repo", "abcdef", "pull-request-1")
+                .await
+                .unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_only_know_the_changed_files_of_builds_that_recorded_them() {
+        let root =
+            std::env::temp_dir().join(format!("kubesci-changed-files-{}", std::process::id()));
+
+        let store = ArtifactStore::Filesystem(FilesystemArtifactStore { root: root.clone() });
+
+        let changed_files = vec!["src/main.rs".to_string(), "README.md".to_string()];
+
+        store
+            .put_changed_files("some/repo", "abcdef", "push", &changed_files)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store
+                .get_changed_files("some/repo", "abcdef", "push")
+                .await
+                .unwrap(),
+            Some(changed_files)
+        );
+        assert_eq!(
+            store
+                .get_changed_files("some/repo", "abcdef", "pull-request-1")
+                .await
+                .unwrap(),
+            None
+        );
+
+        store
+            .delete_changed_files("some/repo", "abcdef", "push")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store
+                .get_changed_files("some/repo", "abcdef", "push")
+                .await
+                .unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn should_uri_encode_reserved_characters() {
+        assert_eq!(
+            uri_encode("dist/my app+1.txt", false),
+            "dist/my%20app%2B1.txt"
+        );
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+    }
+
+    #[test]
+    fn should_uri_decode_what_was_encoded() {
+        assert_eq!(uri_decode("dist/my%20app%2B1.txt"), "dist/my app+1.txt");
+        assert_eq!(uri_decode("100%"), "100%");
+        assert_eq!(uri_decode("%zz"), "%zz");
+    }
+}
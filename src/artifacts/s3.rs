@@ -0,0 +1,417 @@
+use crate::artifacts::{uri_encode, Artifact, ContentStream};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use log::info;
+use regex::Regex;
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use warp::hyper::body::Bytes;
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+// Streamed uploads can't be hashed before they're sent, so their payload isn't signed
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+// Any S3 compatible storage, such as AWS S3 or MinIO. Buckets are addressed by path rather than
+// by subdomain, which every S3 compatible storage supports.
+#[derive(Clone)]
+pub struct S3ArtifactStore {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl S3ArtifactStore {
+    pub async fn put(&self, key: &str, contents: Bytes) -> Result<(), Box<dyn std::error::Error>> {
+        let request = self.request(reqwest::Method::PUT, key, &[], &contents)?;
+
+        let response = request.body(contents).send().await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            other => Err(format!("Unable to upload {}: {}", key, other).into()),
+        }
+    }
+
+    // S3 needs the length of uploads upfront, as it doesn't take chunked bodies
+    pub async fn put_stream(
+        &self,
+        key: &str,
+        contents: ContentStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request =
+            self.request_with_payload_hash(reqwest::Method::PUT, key, &[], UNSIGNED_PAYLOAD)?;
+
+        let response = request
+            .header(reqwest::header::CONTENT_LENGTH, contents.length)
+            .body(reqwest::Body::wrap_stream(contents.chunks))
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            other => Err(format!("Unable to upload {}: {}", key, other).into()),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>, Box<dyn std::error::Error>> {
+        let request = self.request(reqwest::Method::GET, key, &[], b"")?;
+
+        let response = request.send().await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.bytes().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            other => Err(format!("Unable to download {}: {}", key, other).into()),
+        }
+    }
+
+    pub async fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<Option<ContentStream>, Box<dyn std::error::Error>> {
+        let request = self.request(reqwest::Method::GET, key, &[], b"")?;
+
+        let response = request.send().await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(ContentStream {
+                length: response.content_length().unwrap_or_default(),
+                chunks: Box::pin(response.bytes_stream().map_err(std::io::Error::other)),
+            })),
+            StatusCode::NOT_FOUND => Ok(None),
+            other => Err(format!("Unable to download {}: {}", key, other).into()),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let request = self.request(reqwest::Method::DELETE, key, &[], b"")?;
+
+        let response = request.send().await?;
+
+        match response.status() {
+            StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+            other => Err(format!("Unable to delete {}: {}", key, other).into()),
+        }
+    }
+
+    pub async fn list(&self, prefix: &str) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        let mut artifacts = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut query = vec![
+                ("list-type".to_string(), "2".to_string()),
+                ("prefix".to_string(), prefix.to_string()),
+            ];
+
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token".to_string(), token.clone()));
+            }
+
+            let request = self.request(reqwest::Method::GET, "", &query, b"")?;
+
+            let response = request.send().await?;
+
+            if response.status() != StatusCode::OK {
+                return Err(format!("Unable to list {}: {}", prefix, response.status()).into());
+            }
+
+            let body = response.text().await?;
+
+            let (page, next_continuation_token) = parse_list_objects_response(&body, prefix);
+
+            artifacts.extend(page);
+
+            match next_continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(artifacts),
+            }
+        }
+    }
+
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(String, String)],
+        payload: &[u8],
+    ) -> Result<reqwest::RequestBuilder, Box<dyn std::error::Error>> {
+        self.request_with_payload_hash(method, key, query, &hex::encode(Sha256::digest(payload)))
+    }
+
+    fn request_with_payload_hash(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(String, String)],
+        payload_hash: &str,
+    ) -> Result<reqwest::RequestBuilder, Box<dyn std::error::Error>> {
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket, true),
+            uri_encode(key, false)
+        );
+
+        let mut encoded_query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        encoded_query.sort();
+        let canonical_query = encoded_query.join("&");
+
+        let mut url = Url::parse(&self.endpoint)?.join(&path)?;
+        if !canonical_query.is_empty() {
+            url.set_query(Some(&canonical_query));
+        }
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(format!("{} has no host", self.endpoint).into()),
+        };
+
+        let now = Utc::now();
+
+        let authorization = self.authorization(
+            method.as_str(),
+            url.path(),
+            &canonical_query,
+            &host,
+            payload_hash,
+            now,
+        );
+
+        info!("Sending {} {} to artifact storage", method, url);
+
+        Ok(reqwest::Client::new()
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string())
+            .header("authorization", authorization))
+    }
+
+    // AWS Signature Version 4, signing the host, payload hash and date headers
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        canonical_query: &str,
+        host: &str,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            canonical_query,
+            host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = signing_key(&self.secret_access_key, &date, &self.region, "s3");
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id,
+            scope,
+            SIGNED_HEADERS,
+            hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()))
+        )
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+
+    mac.update(data);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let date_key = hmac_sha256(
+        format!("AWS4{}", secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let region_key = hmac_sha256(&date_key, region.as_bytes());
+    let service_key = hmac_sha256(&region_key, service.as_bytes());
+
+    hmac_sha256(&service_key, b"aws4_request")
+}
+
+// Only the keys, sizes and continuation token are needed, so the XML is picked apart with regexes
+fn parse_list_objects_response(body: &str, prefix: &str) -> (Vec<Artifact>, Option<String>) {
+    let contents_regex =
+        Regex::new(r"(?s)<Contents>.*?<Key>(.*?)</Key>.*?<Size>(\d+)</Size>.*?</Contents>")
+            .unwrap();
+    let continuation_token_regex =
+        Regex::new(r"<NextContinuationToken>(.*?)</NextContinuationToken>").unwrap();
+
+    let artifacts = contents_regex
+        .captures_iter(body)
+        .map(|captures| {
+            let key = unescape_xml(&captures[1]);
+
+            Artifact {
+                path: key.strip_prefix(prefix).unwrap_or(&key).to_string(),
+                size: captures[2].parse().unwrap_or(0),
+            }
+        })
+        .collect();
+
+    let continuation_token = continuation_token_regex
+        .captures(body)
+        .map(|captures| unescape_xml(&captures[1]));
+
+    (artifacts, continuation_token)
+}
+
+fn unescape_xml(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example taken from the AWS Signature Version 4 documentation
+    #[test]
+    fn should_derive_the_signing_key() {
+        let signing_key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        assert_eq!(
+            hex::encode(signing_key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn should_parse_list_objects_responses() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>kubesci</Name>
+  <Prefix>some.repo/abc/</Prefix>
+  <IsTruncated>true</IsTruncated>
+  <NextContinuationToken>next&amp;page</NextContinuationToken>
+  <Contents>
+    <Key>some.repo/abc/dist/app</Key>
+    <LastModified>2020-01-01T00:00:00.000Z</LastModified>
+    <Size>1024</Size>
+  </Contents>
+  <Contents>
+    <Key>some.repo/abc/R&amp;D.txt</Key>
+    <LastModified>2020-01-01T00:00:00.000Z</LastModified>
+    <Size>12</Size>
+  </Contents>
+</ListBucketResult>"#;
+
+        let (artifacts, continuation_token) = parse_list_objects_response(body, "some.repo/abc/");
+
+        assert_eq!(
+            artifacts,
+            vec![
+                Artifact {
+                    path: "dist/app".to_string(),
+                    size: 1024
+                },
+                Artifact {
+                    path: "R&D.txt".to_string(),
+                    size: 12
+                },
+            ]
+        );
+        assert_eq!(continuation_token, Some("next&page".to_string()));
+    }
+
+    // Runs against a local MinIO, e.g. `docker run -p 9000:9000 minio/minio server /data` with a
+    // kubesci bucket created
+    #[tokio::test]
+    #[ignore]
+    async fn should_store_artifacts_in_minio() {
+        let store = S3ArtifactStore {
+            endpoint: "http://localhost:9000".to_string(),
+            bucket: "kubesci".to_string(),
+            region: "us-east-1".to_string(),
+            access_key_id: "minioadmin".to_string(),
+            secret_access_key: "minioadmin".to_string(),
+        };
+
+        store
+            .put("some.repo/abc/dist/my app", Bytes::from("binary"))
+            .await
+            .unwrap();
+
+        store
+            .put_stream("some.repo/abc/streamed.txt", Bytes::from("streamed").into())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get("some.repo/abc/dist/my app").await.unwrap(),
+            Some(Bytes::from("binary"))
+        );
+        assert_eq!(
+            store.get("some.repo/abc/streamed.txt").await.unwrap(),
+            Some(Bytes::from("streamed"))
+        );
+        assert_eq!(
+            store.list("some.repo/abc/").await.unwrap(),
+            vec![
+                Artifact {
+                    path: "dist/my app".to_string(),
+                    size: 6
+                },
+                Artifact {
+                    path: "streamed.txt".to_string(),
+                    size: 8
+                }
+            ]
+        );
+        assert_eq!(store.get("some.repo/abc/missing").await.unwrap(), None);
+
+        let streamed = store
+            .get_stream("some.repo/abc/streamed.txt")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(streamed.length, 8);
+        assert_eq!(
+            streamed
+                .chunks
+                .try_collect::<Vec<Bytes>>()
+                .await
+                .unwrap()
+                .concat(),
+            b"streamed"
+        );
+    }
+}
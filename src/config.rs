@@ -1,4 +1,8 @@
-use std::{env, env::VarError};
+use crate::artifacts::filesystem::FilesystemArtifactStore;
+use crate::artifacts::s3::S3ArtifactStore;
+use crate::artifacts::ArtifactStore;
+use crate::kubernetes::resources::{parse_quantity, ResourceValues};
+use std::env;
 
 #[derive(Clone)]
 pub struct Config {
@@ -6,20 +10,125 @@ pub struct Config {
     pub application_id: String,
     pub namespace: String,
     pub github_base_url: String,
+    pub github_url: String,
+    pub webhook_secret: String,
+    pub max_step_resources: ResourceValues,
+    pub artifact_store: ArtifactStore,
+    pub max_artifact_size: u64,
+    pub max_cache_size: u64,
+    pub max_pipeline_upload_size: u64,
+    pub kubesci_url: String,
+    pub templates_repo: Option<String>,
 }
 
 impl Config {
-    pub fn new() -> Result<Config, VarError> {
+    pub fn new() -> Result<Config, Box<dyn std::error::Error>> {
         let github_private_key = env::var("GITHUB_APPLICATION_PRIVATE_KEY")?;
         let application_id = env::var("APPLICATION_ID")?;
         let namespace = std::env::var("NAMESPACE").unwrap_or_else(|_| "kubesci".into());
-        let github_base_url = "https://api.github.com".to_string();
+        let github_base_url =
+            env::var("GITHUB_API_URL").unwrap_or_else(|_| "https://api.github.com".into());
+        let github_url = env::var("GITHUB_URL").unwrap_or_else(|_| "https://github.com".into());
+        let webhook_secret = env::var("WEBHOOK_SECRET")?;
+        let max_step_resources = ResourceValues {
+            cpu: quantity_var("MAX_STEP_CPU")?,
+            memory: quantity_var("MAX_STEP_MEMORY")?,
+            ephemeral_storage: quantity_var("MAX_STEP_EPHEMERAL_STORAGE")?,
+        };
+        let artifact_store = match env::var("ARTIFACTS_STORAGE").as_deref() {
+            Ok("s3") => ArtifactStore::S3(S3ArtifactStore {
+                endpoint: env::var("S3_ENDPOINT")?,
+                bucket: env::var("S3_BUCKET")?,
+                region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into()),
+                access_key_id: env::var("S3_ACCESS_KEY_ID")?,
+                secret_access_key: env::var("S3_SECRET_ACCESS_KEY")?,
+            }),
+            _ => ArtifactStore::Filesystem(FilesystemArtifactStore {
+                root: env::var("ARTIFACTS_PATH")
+                    .unwrap_or_else(|_| "/var/lib/kubesci/artifacts".into())
+                    .into(),
+            }),
+        };
+        // Bigger artifacts are turned away rather than uploaded
+        let max_artifact_size = size_var("MAX_ARTIFACT_SIZE", "5Gi")?;
+        // Least recently used caches are evicted once they add up to more than this, and a single
+        // cache bigger than it is turned away
+        let max_cache_size = size_var("MAX_CACHE_SIZE", "10Gi")?;
+        // Uploaded steps are read whole to be checked before they're saved
+        let max_pipeline_upload_size = size_var("MAX_PIPELINE_UPLOAD_SIZE", "1Mi")?;
+        let kubesci_url =
+            env::var("KUBESCI_URL").unwrap_or_else(|_| "http://kubesci-controller.kubesci".into());
+        // Pipelines can include shared files from this repo, such as "org/kubesci-templates"
+        let templates_repo = env::var("TEMPLATES_REPO").ok();
 
         Ok(Config {
             github_private_key,
             application_id,
             namespace,
             github_base_url,
+            github_url,
+            webhook_secret,
+            max_step_resources,
+            artifact_store,
+            max_artifact_size,
+            max_cache_size,
+            max_pipeline_upload_size,
+            kubesci_url,
+            templates_repo,
         })
     }
 }
+
+// Checked up front, so a typo stops the controller starting rather than failing every pipeline
+fn quantity_var(name: &str) -> Result<Option<String>, String> {
+    env::var(name)
+        .ok()
+        .map(|value| validate_quantity(name, value))
+        .transpose()
+}
+
+fn validate_quantity(name: &str, value: String) -> Result<String, String> {
+    parse_quantity(&value)
+        .map(|_| value)
+        .map_err(|error| format!("{}: {}", name, error))
+}
+
+fn size_var(name: &str, default: &str) -> Result<u64, String> {
+    let value = env::var(name).unwrap_or_else(|_| default.into());
+
+    parse_size(name, &value)
+}
+
+fn parse_size(name: &str, value: &str) -> Result<u64, String> {
+    parse_quantity(value)
+        .map(|size| size as u64)
+        .map_err(|error| format!("{}: {}", name, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_say_which_setting_is_not_a_valid_quantity() {
+        assert_eq!(
+            validate_quantity("MAX_STEP_MEMORY", "4Gi".to_string()),
+            Ok("4Gi".to_string())
+        );
+        assert_eq!(
+            validate_quantity("MAX_STEP_MEMORY", "4GB".to_string()),
+            Err("MAX_STEP_MEMORY: 4GB is not a valid quantity".to_string())
+        );
+    }
+    #[test]
+    fn should_read_sizes_in_bytes() {
+        assert_eq!(
+            parse_size("MAX_ARTIFACT_SIZE", "5Gi"),
+            Ok(5 * 1024 * 1024 * 1024)
+        );
+        assert_eq!(
+            parse_size("MAX_ARTIFACT_SIZE", "lots"),
+            Err("MAX_ARTIFACT_SIZE: lots is not a valid quantity".to_string())
+        );
+    }
+}
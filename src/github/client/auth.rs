@@ -18,6 +18,7 @@ impl GithubAuthorisationClient {
     pub fn new(
         github_private_key: &str,
         application_id: &str,
+        base_url: &str,
     ) -> Result<GithubAuthorisationClient, jsonwebtoken::errors::Error> {
         let now = Utc::now().timestamp();
 
@@ -25,7 +26,7 @@ impl GithubAuthorisationClient {
 
         Ok(GithubAuthorisationClient {
             github_jwt_token,
-            base_url: "https://api.github.com".to_string(),
+            base_url: base_url.to_string(),
         })
     }
 
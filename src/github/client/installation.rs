@@ -1,23 +1,29 @@
+use crate::artifacts::uri_encode;
 use crate::routes::CompleteCheckRunRequest;
 use chrono::prelude::*;
 use log::info;
-use reqwest::header::{ACCEPT, USER_AGENT};
+use reqwest::header::{ACCEPT, LINK, USER_AGENT};
 use serde_derive::{Deserialize, Serialize};
 use warp::http::StatusCode;
 
+// Blocks are completed as soon as they're created, so this marks the ones that have been unblocked
+pub const UNBLOCKED_BLOCK_SUMMARY: &str = "Unblocked";
+pub const UNBLOCK_ACTION_IDENTIFIER: &str = "unblock";
+
 #[derive(Serialize)]
 struct CreateCheckRunRequest {
     accept: String,
     name: String,
     head_sha: String,
+    external_id: String,
 }
 
-#[derive(Serialize)]
-struct UpdateCheckRunRequest {
-    accept: String,
-    name: String,
-    status: String,
-    started_at: String, // ISO 8601
+#[derive(Serialize, Debug)]
+struct UpdateCheckRunRequest<'a> {
+    accept: &'a str,
+    name: &'a str,
+    status: &'a str,
+    output: CheckRunOutput<'a>,
 }
 
 #[derive(Serialize, Debug)]
@@ -46,10 +52,83 @@ struct CompletedCheckRunRequest<'a> {
     actions: &'a Vec<Action<'a>>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct GetCheckRunOutput {
+    pub summary: Option<String>,
+    pub text: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct GetCheckRunResponse {
+    pub id: i64,
     pub name: String,
     pub started_at: String,
+    pub status: String,
+    pub conclusion: Option<String>,
+    pub external_id: Option<String>,
+    pub output: GetCheckRunOutput,
+    pub check_suite: CheckRunCheckSuite,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListCheckRunsResponse {
+    check_runs: Vec<GetCheckRunResponse>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommitAuthor {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommitDetails {
+    pub message: String,
+    pub author: CommitAuthor,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetRepositoryResponse {
+    default_branch: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetCommitResponse {
+    pub sha: String,
+    pub commit: CommitDetails,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetLabelResponse {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListCommitsItem {
+    sha: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompareFile {
+    filename: String,
+    previous_filename: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetTreeResponse {
+    tree: Vec<TreeEntry>,
+    truncated: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompareCommitsResponse {
+    files: Option<Vec<CompareFile>>,
 }
 
 pub struct GithubInstallationClient<'a> {
@@ -58,9 +137,15 @@ pub struct GithubInstallationClient<'a> {
     pub base_url: &'a str,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct CheckRunCheckSuite {
+    pub id: u64,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CreateCheckRunResponse {
     pub id: u32,
+    pub check_suite: CheckRunCheckSuite,
 }
 
 impl<'a> GithubInstallationClient<'a> {
@@ -68,6 +153,7 @@ impl<'a> GithubInstallationClient<'a> {
         &self,
         name: &str,
         head_sha: &str,
+        external_id: &str,
     ) -> Result<CreateCheckRunResponse, Box<dyn std::error::Error>> {
         let request_url = format!(
             "{}/repos/{}/check-runs",
@@ -78,6 +164,7 @@ impl<'a> GithubInstallationClient<'a> {
             accept: "application/vnd.github.antiope-preview+json".to_string(),
             name: name.to_string(),
             head_sha: head_sha.to_string(),
+            external_id: external_id.to_string(),
         };
 
         info!("Creating the check run...");
@@ -100,9 +187,11 @@ impl<'a> GithubInstallationClient<'a> {
         &self,
         name: &str,
         head_sha: &str,
-        step_section_to_unblock: usize,
+        step_section_identifier: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let create_check_run_response = self.create_check_run(name, head_sha).await?;
+        let create_check_run_response = self
+            .create_check_run(name, head_sha, step_section_identifier)
+            .await?;
 
         let request_url = format!(
             "{}/repos/{}/check-runs/{}",
@@ -112,10 +201,12 @@ impl<'a> GithubInstallationClient<'a> {
         let started_at = Utc::now().to_rfc3339();
         let fnished_at = Utc::now().to_rfc3339();
 
+        // Action identifiers are limited to 20 characters, too few for the pipeline file, so the
+        // block is found by its check run's external id instead
         let actions = vec![Action {
             label: "Unblock",
             description: "Unblocks the remaining steps",
-            identifier: step_section_to_unblock.to_string(),
+            identifier: UNBLOCK_ACTION_IDENTIFIER.to_string(),
         }];
 
         let update_check_run_request = CompletedCheckRunRequest {
@@ -148,6 +239,170 @@ impl<'a> GithubInstallationClient<'a> {
         Ok(())
     }
 
+    pub async fn unblock_block_step(
+        &self,
+        check_run_id: i64,
+        name: &str,
+        started_at: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/check-runs/{}",
+            self.base_url, self.repository_name, check_run_id
+        );
+
+        let finished_at = Utc::now().to_rfc3339();
+
+        let check_run_output = CheckRunOutput {
+            title: name,
+            summary: UNBLOCKED_BLOCK_SUMMARY,
+            text: "",
+        };
+
+        // No actions removes the unblock button now that it has been used
+        let update_check_run_request = CompletedCheckRunRequest {
+            accept: "application/vnd.github.antiope-preview+json",
+            name,
+            status: "completed",
+            started_at,
+            completed_at: &Some(finished_at),
+            conclusion: &Some("success".to_string()),
+            output: Some(&check_run_output),
+            actions: &Vec::new(),
+        };
+
+        info!(
+            "Unblocking the block check run with request: {:?}",
+            update_check_run_request
+        );
+
+        let response = reqwest::Client::new()
+            .patch(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.antiope-preview+json")
+            .header(USER_AGENT, "my-test-app")
+            .json(&update_check_run_request)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            other => Err(other.to_string().into()),
+        }
+    }
+
+    pub async fn create_skipped_check_run(
+        &self,
+        name: &str,
+        head_sha: &str,
+        step_section_identifier: &str,
+        reason: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_completed_check_run(
+            name,
+            head_sha,
+            step_section_identifier,
+            "skipped",
+            reason,
+            "",
+        )
+        .await
+    }
+
+    pub async fn create_failed_check_run(
+        &self,
+        name: &str,
+        head_sha: &str,
+        step_section_identifier: &str,
+        reason: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_completed_check_run(
+            name,
+            head_sha,
+            step_section_identifier,
+            "failure",
+            reason,
+            "",
+        )
+        .await
+    }
+
+    pub async fn create_successful_check_run(
+        &self,
+        name: &str,
+        head_sha: &str,
+        step_section_identifier: &str,
+        summary: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_completed_check_run(
+            name,
+            head_sha,
+            step_section_identifier,
+            "success",
+            summary,
+            text,
+        )
+        .await
+    }
+
+    async fn create_completed_check_run(
+        &self,
+        name: &str,
+        head_sha: &str,
+        step_section_identifier: &str,
+        conclusion: &str,
+        reason: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let create_check_run_response = self
+            .create_check_run(name, head_sha, step_section_identifier)
+            .await?;
+
+        let request_url = format!(
+            "{}/repos/{}/check-runs/{}",
+            self.base_url, self.repository_name, create_check_run_response.id
+        );
+
+        let started_at = Utc::now().to_rfc3339();
+        let finished_at = Utc::now().to_rfc3339();
+
+        let check_run_output = CheckRunOutput {
+            title: name,
+            summary: reason,
+            text,
+        };
+
+        let update_check_run_request = CompletedCheckRunRequest {
+            accept: "application/vnd.github.antiope-preview+json",
+            name,
+            status: "completed",
+            started_at: &started_at,
+            completed_at: &Some(finished_at),
+            conclusion: &Some(conclusion.to_string()),
+            output: Some(&check_run_output),
+            actions: &Vec::new(),
+        };
+
+        info!(
+            "Creating the completed check run: {:?}",
+            update_check_run_request
+        );
+
+        let response = reqwest::Client::new()
+            .patch(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.antiope-preview+json")
+            .header(USER_AGENT, "my-test-app")
+            .json(&update_check_run_request)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            other => Err(other.to_string().into()),
+        }
+    }
+
     pub async fn get_check_run(
         &self,
         check_run_id: i32,
@@ -172,6 +427,86 @@ impl<'a> GithubInstallationClient<'a> {
         Ok(check_run_response)
     }
 
+    pub async fn update_check_run_output(
+        &self,
+        check_run_id: i32,
+        name: &str,
+        summary: &str,
+        text: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/check-runs/{}",
+            self.base_url, self.repository_name, check_run_id
+        );
+
+        let update_check_run_request = UpdateCheckRunRequest {
+            accept: "application/vnd.github.antiope-preview+json",
+            name,
+            status: "in_progress",
+            output: CheckRunOutput {
+                title: name,
+                summary,
+                text,
+            },
+        };
+
+        info!(
+            "Updating the check run output with request: {:?}",
+            update_check_run_request
+        );
+
+        let response = reqwest::Client::new()
+            .patch(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.antiope-preview+json")
+            .header(USER_AGENT, "my-test-app")
+            .json(&update_check_run_request)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            other => Err(other.to_string().into()),
+        }
+    }
+
+    // Every build of the commit is listed, as the push and pull request builds have check runs with
+    // the same names. Reruns add another check run with the same name and external id, so only the
+    // latest of those is kept.
+    pub async fn list_check_runs(
+        &self,
+        head_sha: &str,
+    ) -> Result<Vec<GetCheckRunResponse>, Box<dyn std::error::Error>> {
+        let mut maybe_request_url = Some(format!(
+            "{}/repos/{}/commits/{}/check-runs?filter=all&per_page=100",
+            self.base_url, self.repository_name, head_sha
+        ));
+
+        info!("Listing the check runs for {}...", head_sha);
+
+        let mut check_runs = Vec::new();
+
+        while let Some(request_url) = maybe_request_url {
+            let response = reqwest::Client::new()
+                .get(&request_url)
+                .bearer_auth(self.github_installation_token.to_string())
+                .header(ACCEPT, "application/vnd.github.antiope-preview+json")
+                .header(USER_AGENT, "my-test-app")
+                .send()
+                .await?;
+
+            maybe_request_url = response
+                .headers()
+                .get(LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_page_url);
+
+            check_runs.extend(response.json::<ListCheckRunsResponse>().await?.check_runs);
+        }
+
+        Ok(latest_check_runs(check_runs))
+    }
+
     pub async fn set_check_run_complete(
         &self,
         check_run_id: i32,
@@ -186,7 +521,7 @@ impl<'a> GithubInstallationClient<'a> {
 
         let check_run_output = CheckRunOutput {
             title: name,
-            summary: "Complete!",
+            summary: &update_check_run_request.summary,
             text: &update_check_run_request.logs,
         };
 
@@ -223,16 +558,226 @@ impl<'a> GithubInstallationClient<'a> {
         }
     }
 
-    pub async fn get_pipeline_file(
+    // Annotated tags are followed through to their commit
+    pub async fn get_tag_commit_sha(
         &self,
-        github_commit_sha: &str,
+        tag: &str,
     ) -> Result<Option<String>, Box<dyn std::error::Error>> {
         let request_url = format!(
-            "{}/repos/{}/contents/.kubesci/pipeline.yml?ref={}",
-            self.base_url, self.repository_name, github_commit_sha
+            "{}/repos/{}/commits/{}",
+            self.base_url,
+            self.repository_name,
+            uri_encode(&format!("refs/tags/{}", tag), false)
         );
 
-        info!("Downloading the steps to run...");
+        info!("Getting the commit for the tag {}...", tag);
+
+        let response = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.json::<GetCommitResponse>().await?.sha)),
+            StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => Ok(None),
+            other => Err(other.to_string().into()),
+        }
+    }
+
+    pub async fn get_commit(
+        &self,
+        commit_sha: &str,
+    ) -> Result<GetCommitResponse, Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/commits/{}",
+            self.base_url, self.repository_name, commit_sha
+        );
+
+        info!("Getting the commit {}...", commit_sha);
+
+        let commit_response = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?
+            .json::<GetCommitResponse>()
+            .await?;
+
+        Ok(commit_response)
+    }
+
+    pub async fn list_pull_request_labels(
+        &self,
+        pull_request_number: u64,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/issues/{}/labels?per_page=100",
+            self.base_url, self.repository_name, pull_request_number
+        );
+
+        info!(
+            "Listing the labels of the pull request {}...",
+            pull_request_number
+        );
+
+        let labels = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?
+            .json::<Vec<GetLabelResponse>>()
+            .await?;
+
+        Ok(labels.into_iter().map(|label| label.name).collect())
+    }
+
+    // Newest first, starting with the commit itself
+    pub async fn list_commits(
+        &self,
+        commit_sha: &str,
+        limit: u32,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/commits?sha={}&per_page={}",
+            self.base_url, self.repository_name, commit_sha, limit
+        );
+
+        info!("Listing the commits up to {}...", commit_sha);
+
+        let commits = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?
+            .json::<Vec<ListCommitsItem>>()
+            .await?;
+
+        Ok(commits.into_iter().map(|commit| commit.sha).collect())
+    }
+
+    // Files changed since the two commits' merge base, with renamed files under both names. GitHub
+    // lists at most 300 files, so larger changes are unknown.
+    pub async fn get_changed_files(
+        &self,
+        base: &str,
+        head_sha: &str,
+    ) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+        const MAX_COMPARE_FILES: usize = 300;
+
+        let request_url = format!(
+            "{}/repos/{}/compare/{}...{}",
+            self.base_url,
+            self.repository_name,
+            uri_encode(base, false),
+            head_sha
+        );
+
+        info!("Comparing {} with {}...", base, head_sha);
+
+        let response = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?;
+
+        let compare_response = match response.status() {
+            StatusCode::OK => response.json::<CompareCommitsResponse>().await?,
+            StatusCode::NOT_FOUND => return Ok(None),
+            other => return Err(other.to_string().into()),
+        };
+
+        let files = match compare_response.files {
+            Some(files) if files.len() < MAX_COMPARE_FILES => files,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(
+            files
+                .into_iter()
+                .flat_map(|file| std::iter::once(file.filename).chain(file.previous_filename))
+                .collect(),
+        ))
+    }
+
+    pub async fn get_default_branch(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let request_url = format!("{}/repos/{}", self.base_url, self.repository_name);
+
+        info!("Getting the default branch of {}...", self.repository_name);
+
+        let repository_response = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?
+            .json::<GetRepositoryResponse>()
+            .await?;
+
+        Ok(repository_response.default_branch)
+    }
+
+    // Every file in the commit. Very large repos only have part of their tree listed.
+    pub async fn list_files(
+        &self,
+        commit_sha: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/git/trees/{}?recursive=1",
+            self.base_url, self.repository_name, commit_sha
+        );
+
+        info!("Listing the files in {}...", commit_sha);
+
+        let tree_response = reqwest::Client::new()
+            .get(&request_url)
+            .bearer_auth(self.github_installation_token.to_string())
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            .header(USER_AGENT, "my-test-app")
+            .send()
+            .await?
+            .json::<GetTreeResponse>()
+            .await?;
+
+        if tree_response.truncated {
+            info!("Only some of the files in {} were listed", commit_sha);
+        }
+
+        Ok(tree_response
+            .tree
+            .into_iter()
+            .filter(|entry| entry.entry_type == "blob")
+            .map(|entry| entry.path)
+            .collect())
+    }
+
+    // Files can come from other repos the app is installed on, such as the templates repo
+    pub async fn get_repository_file(
+        &self,
+        repository_name: &str,
+        path: &str,
+        git_ref: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let request_url = format!(
+            "{}/repos/{}/contents/{}?ref={}",
+            self.base_url,
+            repository_name,
+            uri_encode(path, false),
+            uri_encode(git_ref, true)
+        );
+
+        info!("Downloading {} from {}...", path, repository_name);
 
         let response = reqwest::Client::new()
             .get(&request_url)
@@ -249,3 +794,90 @@ impl<'a> GithubInstallationClient<'a> {
         }
     }
 }
+
+// The `Link` header GitHub paginates with, such as `<https://...&page=2>; rel="next", <...>; rel="last"`
+fn next_page_url(link: &str) -> Option<String> {
+    link.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let url = parts.next()?.trim();
+
+        if parts.any(|part| part.trim() == "rel=\"next\"") {
+            Some(
+                url.trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string(),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+// Check run ids only go up, so the highest of each name and external id is the latest attempt
+fn latest_check_runs(mut check_runs: Vec<GetCheckRunResponse>) -> Vec<GetCheckRunResponse> {
+    check_runs.sort_by_key(|check_run| std::cmp::Reverse(check_run.id));
+
+    let mut latest_check_runs: Vec<GetCheckRunResponse> = Vec::new();
+
+    for check_run in check_runs {
+        let is_superseded = latest_check_runs.iter().any(|latest_check_run| {
+            latest_check_run.name == check_run.name
+                && latest_check_run.external_id == check_run.external_id
+        });
+
+        if !is_superseded {
+            latest_check_runs.push(check_run);
+        }
+    }
+
+    latest_check_runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_run(id: i64, name: &str, external_id: &str) -> GetCheckRunResponse {
+        GetCheckRunResponse {
+            id,
+            name: name.to_string(),
+            started_at: "2020-01-01T00:00:00Z".to_string(),
+            status: "completed".to_string(),
+            conclusion: Some("success".to_string()),
+            external_id: Some(external_id.to_string()),
+            output: GetCheckRunOutput {
+                summary: None,
+                text: None,
+            },
+            check_suite: CheckRunCheckSuite { id: 1 },
+        }
+    }
+
+    #[test]
+    fn should_follow_the_next_page_link() {
+        let link = "<https://api.github.com/repositories/1/check-runs?page=2>; rel=\"next\", \
+                    <https://api.github.com/repositories/1/check-runs?page=5>; rel=\"last\"";
+
+        assert_eq!(
+            next_page_url(link),
+            Some("https://api.github.com/repositories/1/check-runs?page=2".to_string())
+        );
+        assert_eq!(
+            next_page_url("<https://api.github.com/x?page=1>; rel=\"prev\""),
+            None
+        );
+    }
+
+    #[test]
+    fn should_keep_the_latest_check_run_of_each_build() {
+        let check_runs = latest_check_runs(vec![
+            check_run(1, "test", "0"),
+            check_run(3, "test", "0"),
+            check_run(2, "test", "0:12"),
+        ]);
+
+        let ids: Vec<i64> = check_runs.iter().map(|check_run| check_run.id).collect();
+
+        assert_eq!(ids, vec![3, 2]);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod auth;
 pub mod client;
+pub mod pull_request;
+pub mod signature;
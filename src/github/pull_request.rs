@@ -0,0 +1,22 @@
+pub const PUSH_EVENT: &str = "push";
+pub const PULL_REQUEST_EVENT: &str = "pull_request";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PullRequest {
+    pub number: u64,
+    pub base_branch: String,
+    pub head_branch: String,
+}
+
+impl PullRequest {
+    pub fn merge_ref(&self) -> String {
+        format!("refs/pull/{}/merge", self.number)
+    }
+}
+
+pub fn github_event(pull_request: Option<&PullRequest>) -> &'static str {
+    match pull_request {
+        Some(_) => PULL_REQUEST_EVENT,
+        None => PUSH_EVENT,
+    }
+}
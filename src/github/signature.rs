@@ -0,0 +1,61 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+pub fn verify_signature(webhook_secret: &str, signature: &str, body: &[u8]) -> bool {
+    let maybe_expected_signature = signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|hex_signature| hex::decode(hex_signature).ok());
+
+    match (
+        maybe_expected_signature,
+        Hmac::<Sha256>::new_varkey(webhook_secret.as_bytes()),
+    ) {
+        (Some(expected_signature), Ok(mut mac)) => {
+            mac.update(body);
+
+            // Mac::verify compares the signatures in constant time
+            mac.verify(&expected_signature).is_ok()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example taken from the GitHub webhook documentation
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn should_accept_valid_signature() {
+        assert!(verify_signature(SECRET, SIGNATURE, BODY));
+    }
+
+    #[test]
+    fn should_reject_signature_signed_with_different_secret() {
+        assert!(!verify_signature("some-other-secret", SIGNATURE, BODY));
+    }
+
+    #[test]
+    fn should_reject_signature_for_different_body() {
+        assert!(!verify_signature(SECRET, SIGNATURE, b"Goodbye, World!"));
+    }
+
+    #[test]
+    fn should_reject_signature_without_sha256_prefix() {
+        let signature = SIGNATURE.trim_start_matches(SIGNATURE_PREFIX);
+
+        assert!(!verify_signature(SECRET, signature, BODY));
+    }
+
+    #[test]
+    fn should_reject_signature_that_is_not_hex() {
+        assert!(!verify_signature(SECRET, "sha256=not-hex", BODY));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::artifacts::{glob_matches, uri_decode, ArtifactStore, ContentStream};
+use crate::handlers::ErrorMessage;
+use crate::routes::ListArtifactsQuery;
+use log::{error, info};
+use std::convert::Infallible;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::path::Tail;
+use warp::Reply;
+
+pub async fn handle_list_artifacts(
+    repo_name: String,
+    commit_sha: String,
+    query: ListArtifactsQuery,
+    artifact_store: ArtifactStore,
+) -> Result<warp::reply::Response, Infallible> {
+    match artifact_store.list(&repo_name, &commit_sha).await {
+        Ok(artifacts) => {
+            let artifacts: Vec<_> = artifacts
+                .into_iter()
+                .filter(|artifact| {
+                    query
+                        .glob
+                        .as_ref()
+                        .map(|glob| glob_matches(glob, &artifact.path))
+                        .unwrap_or(true)
+                })
+                .collect();
+
+            if query.format.as_deref() == Some("text") {
+                let paths: Vec<String> = artifacts
+                    .into_iter()
+                    .map(|artifact| artifact.path)
+                    .collect();
+
+                Ok(paths.join("\n").into_response())
+            } else {
+                Ok(warp::reply::json(&artifacts).into_response())
+            }
+        }
+        Err(error) => {
+            error!("Unable to list artifacts: {}", error);
+
+            Ok(internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_get_artifact(
+    repo_name: String,
+    commit_sha: String,
+    path: Tail,
+    artifact_store: ArtifactStore,
+) -> Result<warp::reply::Response, Infallible> {
+    match artifact_store
+        .get(&repo_name, &commit_sha, &decoded_path(&path))
+        .await
+    {
+        Ok(Some(contents)) => Ok(Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", contents.length)
+            .body(Body::wrap_stream(contents.chunks))
+            .unwrap()),
+        Ok(None) => Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage { code: 404 }),
+            StatusCode::NOT_FOUND,
+        )
+        .into_response()),
+        Err(error) => {
+            error!("Unable to get artifact: {}", error);
+
+            Ok(internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_upload_artifact(
+    repo_name: String,
+    commit_sha: String,
+    path: Tail,
+    contents: ContentStream,
+    artifact_store: ArtifactStore,
+) -> Result<warp::reply::Response, Infallible> {
+    let path = decoded_path(&path);
+
+    info!("Uploading artifact {} for {}", path, commit_sha);
+
+    match artifact_store
+        .put(&repo_name, &commit_sha, &path, contents)
+        .await
+    {
+        Ok(()) => Ok(StatusCode::CREATED.into_response()),
+        Err(error) => {
+            error!("Unable to upload artifact: {}", error);
+
+            Ok(internal_server_error())
+        }
+    }
+}
+
+fn internal_server_error() -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorMessage { code: 500 }),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response()
+}
+
+fn decoded_path(path: &Tail) -> String {
+    uri_decode(path.as_str())
+}
@@ -0,0 +1,82 @@
+use crate::artifacts::{ArtifactStore, ContentStream};
+use crate::handlers::ErrorMessage;
+use log::{error, info};
+use std::convert::Infallible;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::Reply;
+
+pub async fn handle_get_cache(
+    repo_name: String,
+    branch_name: String,
+    fallback_branch_name: Option<String>,
+    cache_key: String,
+    artifact_store: ArtifactStore,
+) -> Result<warp::reply::Response, Infallible> {
+    match artifact_store
+        .get_cache(
+            &repo_name,
+            &branch_name,
+            fallback_branch_name.as_deref(),
+            &cache_key,
+        )
+        .await
+    {
+        Ok(Some(contents)) => Ok(Response::builder()
+            .header("Content-Type", "application/gzip")
+            .header("Content-Length", contents.length)
+            .body(Body::wrap_stream(contents.chunks))
+            .unwrap()),
+        Ok(None) => Ok(with_status(StatusCode::NOT_FOUND)),
+        Err(error) => {
+            error!("Unable to get cache {}: {}", cache_key, error);
+
+            Ok(with_status(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+// Caches over the max size are evicted once the new one is saved, which is never bigger than the
+// max size itself
+pub async fn handle_upload_cache(
+    repo_name: String,
+    branch_name: String,
+    cache_key: String,
+    contents: ContentStream,
+    artifact_store: ArtifactStore,
+    max_cache_size: u64,
+) -> Result<warp::reply::Response, Infallible> {
+    info!(
+        "Saving cache {} for {} on {}",
+        cache_key, repo_name, branch_name
+    );
+
+    let saved = artifact_store
+        .put_cache(&repo_name, &branch_name, &cache_key, contents)
+        .await
+        .map_err(|error| error.to_string());
+
+    if let Err(error) = saved {
+        error!("Unable to save cache {}: {}", cache_key, error);
+
+        return Ok(with_status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    match artifact_store.evict_caches(max_cache_size).await {
+        Ok(evicted) if !evicted.is_empty() => info!("Evicted caches {:?}", evicted),
+        Ok(_) => {}
+        Err(error) => error!("Unable to evict caches: {}", error),
+    }
+
+    Ok(StatusCode::CREATED.into_response())
+}
+
+fn with_status(status: StatusCode) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorMessage {
+            code: status.as_u16(),
+        }),
+        status,
+    )
+    .into_response()
+}
@@ -1,5 +1,8 @@
-use crate::pipeline::PipelineService;
-use crate::routes::GithubCheckRunRequest;
+use crate::github::client::installation::UNBLOCK_ACTION_IDENTIFIER;
+use crate::github::pull_request::PullRequest;
+use crate::pipeline::{parse_step_identifier, PipelineService, StepLocation};
+use crate::routes::{CheckRun, GithubCheckRunRequest};
+use log::info;
 use std::convert::Infallible;
 use warp::http::StatusCode;
 
@@ -7,27 +10,99 @@ pub async fn handle_check_run_request(
     github_webhook_request: GithubCheckRunRequest,
     pipeline_service: PipelineService,
 ) -> Result<impl warp::Reply, Infallible> {
-    if github_webhook_request.action != "requested_action" {
+    let is_unblock = github_webhook_request.action == "requested_action"
+        && github_webhook_request
+            .requested_action
+            .as_ref()
+            .map(|requested_action| requested_action.identifier == UNBLOCK_ACTION_IDENTIFIER)
+            .unwrap_or(false);
+
+    // Both reruns and unblocks find the step by the identifier its check run was created with
+    if !is_unblock && github_webhook_request.action != "rerequested" {
         return Ok(warp::reply::with_status("".to_string(), StatusCode::OK));
     }
 
-    let step_section: usize = github_webhook_request
-        .requested_action
-        .unwrap()
-        .identifier
-        .parse()
-        .unwrap();
-
-    match pipeline_service
-        .start_step_section(
-            github_webhook_request.installation.id,
-            &github_webhook_request.repository.full_name,
-            &github_webhook_request.check_run.check_suite.head_sha,
-            &github_webhook_request.check_run.check_suite.head_branch,
-            Some(step_section),
-        )
-        .await
-    {
+    let check_run = &github_webhook_request.check_run;
+
+    // Check runs that weren't created for a step, or by an older version, can't be found again
+    let maybe_parsed_identifier = check_run
+        .external_id
+        .as_deref()
+        .and_then(|identifier| parse_step_identifier(identifier).ok());
+
+    let (step_location, maybe_pull_request_number, pipeline_path) = match maybe_parsed_identifier {
+        Some(parsed_identifier) => parsed_identifier,
+        None => {
+            info!(
+                "Check run {} has no step identifier: {:?}",
+                check_run.name, check_run.external_id
+            );
+
+            return Ok(warp::reply::with_status(
+                format!("Check run {} is not for a step", check_run.name),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    // Steps their filters skipped aren't part of the build, so there's nothing to run again
+    if let StepLocation::Filtered(_) = step_location {
+        return Ok(warp::reply::with_status(
+            format!(
+                "Check run {} is for a step its filters skipped, so it can't be rerun",
+                check_run.name
+            ),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+    }
+
+    let maybe_pull_request = match maybe_pull_request_number {
+        Some(pull_request_number) => match find_pull_request(check_run, pull_request_number) {
+            Some(pull_request) => Some(pull_request),
+            None => {
+                return Ok(warp::reply::with_status(
+                    format!(
+                        "Pull request {} is not associated with the check run",
+                        pull_request_number
+                    ),
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let result = if github_webhook_request.action == "rerequested" {
+        pipeline_service
+            .rerun_step(
+                github_webhook_request.installation.id,
+                &github_webhook_request.repository.full_name,
+                &check_run.check_suite.head_sha,
+                &check_run.check_suite.head_branch,
+                step_location,
+                maybe_pull_request.as_ref(),
+                &pipeline_path,
+                &check_run.name,
+            )
+            .await
+    } else {
+        pipeline_service
+            .unblock_step(
+                github_webhook_request.installation.id,
+                &github_webhook_request.repository.full_name,
+                &check_run.check_suite.head_sha,
+                &check_run.check_suite.head_branch,
+                step_location,
+                maybe_pull_request.as_ref(),
+                &pipeline_path,
+                check_run.id,
+                &check_run.name,
+                &check_run.started_at,
+            )
+            .await
+    };
+
+    match result {
         Ok(()) => Ok(warp::reply::with_status("".to_string(), StatusCode::OK)),
         Err(error) => Ok(warp::reply::with_status(
             error.to_string(),
@@ -35,3 +110,11 @@ pub async fn handle_check_run_request(
         )),
     }
 }
+
+fn find_pull_request(check_run: &CheckRun, pull_request_number: u64) -> Option<PullRequest> {
+    check_run
+        .pull_requests
+        .iter()
+        .find(|pull_request| pull_request.number == pull_request_number)
+        .map(|pull_request| pull_request.to_pull_request())
+}
@@ -1,3 +1,4 @@
+use crate::github::pull_request::PullRequest;
 use crate::pipeline::PipelineService;
 use crate::routes::GithubCheckSuiteRequest;
 use std::convert::Infallible;
@@ -7,24 +8,45 @@ pub async fn handle_check_suite_request(
     github_webhook_request: GithubCheckSuiteRequest,
     pipeline_service: PipelineService,
 ) -> Result<impl warp::Reply, Infallible> {
-    if github_webhook_request.action != "requested" {
+    // Re-running the whole check suite just starts the pipeline again
+    if github_webhook_request.action != "requested"
+        && github_webhook_request.action != "rerequested"
+    {
         return Ok(warp::reply::with_status("".to_string(), StatusCode::OK));
     }
 
-    match pipeline_service
-        .start_step_section(
-            github_webhook_request.installation.id,
-            &github_webhook_request.repository.full_name,
-            &github_webhook_request.check_suite.head_sha,
-            &github_webhook_request.check_suite.head_branch,
-            None,
-        )
-        .await
-    {
-        Ok(()) => Ok(warp::reply::with_status("".to_string(), StatusCode::OK)),
-        Err(error) => Ok(warp::reply::with_status(
-            error.to_string(),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        )),
+    // Pull requests start their own builds when opened, so only a re-run builds them here too
+    let pull_requests: Vec<PullRequest> = if github_webhook_request.action == "rerequested" {
+        github_webhook_request
+            .check_suite
+            .pull_requests
+            .iter()
+            .map(|pull_request| pull_request.to_pull_request())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let builds = std::iter::once(None).chain(pull_requests.iter().map(Some));
+
+    for maybe_pull_request in builds {
+        let result = pipeline_service
+            .start_pipelines(
+                github_webhook_request.installation.id,
+                &github_webhook_request.repository.full_name,
+                &github_webhook_request.check_suite.head_sha,
+                &github_webhook_request.check_suite.head_branch,
+                maybe_pull_request,
+            )
+            .await;
+
+        if let Err(error) = result {
+            return Ok(warp::reply::with_status(
+                error.to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
     }
+
+    Ok(warp::reply::with_status("".to_string(), StatusCode::OK))
 }
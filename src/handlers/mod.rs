@@ -2,10 +2,14 @@ use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::Pod;
 use serde_derive::Serialize;
 
+pub mod artifacts;
+pub mod caches;
 pub mod check_run;
 pub mod check_suite;
 pub mod pipeline;
+pub mod pipeline_uploads;
 pub mod pipelines;
+pub mod pull_request;
 pub mod steps;
 
 #[derive(Serialize, Clone)]
@@ -0,0 +1,87 @@
+use crate::artifacts::ArtifactStore;
+use crate::handlers::ErrorMessage;
+use crate::kubernetes::resources::ResourceValues;
+use crate::pipeline::uploads::parse_uploaded_steps;
+use log::{error, info};
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::Reply;
+
+// Invalid steps are turned away here, failing the step that uploaded them, rather than breaking
+// the rest of the build once its next section starts
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_upload_pipeline(
+    repo_name: String,
+    commit_sha: String,
+    build: String,
+    step_section: usize,
+    step_id: String,
+    steps: Bytes,
+    artifact_store: ArtifactStore,
+    max_step_resources: ResourceValues,
+) -> Result<warp::reply::Response, Infallible> {
+    info!(
+        "Uploading steps from step section {} of {} for {}",
+        step_section, build, commit_sha
+    );
+
+    let validated = String::from_utf8(steps.to_vec())
+        .map_err(|error| error.to_string())
+        .and_then(|steps| parse_uploaded_steps(&steps, None, &max_step_resources));
+
+    if let Err(message) = validated {
+        return Ok(warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response());
+    }
+
+    let is_finished = artifact_store
+        .is_pipeline_upload_section_finished(&repo_name, &commit_sha, &build, step_section)
+        .await
+        .map_err(|error| error.to_string());
+
+    match is_finished {
+        Ok(false) => {}
+        Ok(true) => {
+            return Ok(warp::reply::with_status(
+                format!(
+                    "The pipeline has already moved past step section {}",
+                    step_section
+                ),
+                StatusCode::CONFLICT,
+            )
+            .into_response())
+        }
+        Err(error) => {
+            error!("Unable to check the step section of the upload: {}", error);
+
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&ErrorMessage { code: 500 }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response());
+        }
+    }
+
+    match artifact_store
+        .put_pipeline_upload(
+            &repo_name,
+            &commit_sha,
+            &build,
+            step_section,
+            &step_id,
+            steps,
+        )
+        .await
+    {
+        Ok(()) => Ok(StatusCode::CREATED.into_response()),
+        Err(error) => {
+            error!("Unable to upload steps: {}", error);
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&ErrorMessage { code: 500 }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response())
+        }
+    }
+}
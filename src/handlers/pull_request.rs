@@ -0,0 +1,39 @@
+use crate::github::pull_request::PullRequest;
+use crate::pipeline::PipelineService;
+use crate::routes::GithubPullRequestRequest;
+use std::convert::Infallible;
+use warp::http::StatusCode;
+
+const BUILDABLE_ACTIONS: [&str; 3] = ["opened", "synchronize", "reopened"];
+
+pub async fn handle_pull_request_request(
+    github_webhook_request: GithubPullRequestRequest,
+    pipeline_service: PipelineService,
+) -> Result<impl warp::Reply, Infallible> {
+    if !BUILDABLE_ACTIONS.contains(&github_webhook_request.action.as_str()) {
+        return Ok(warp::reply::with_status("".to_string(), StatusCode::OK));
+    }
+
+    let pull_request = PullRequest {
+        number: github_webhook_request.pull_request.number,
+        base_branch: github_webhook_request.pull_request.base.branch,
+        head_branch: github_webhook_request.pull_request.head.branch.clone(),
+    };
+
+    match pipeline_service
+        .start_pipelines(
+            github_webhook_request.installation.id,
+            &github_webhook_request.repository.full_name,
+            &github_webhook_request.pull_request.head.sha,
+            &github_webhook_request.pull_request.head.branch,
+            Some(&pull_request),
+        )
+        .await
+    {
+        Ok(()) => Ok(warp::reply::with_status("".to_string(), StatusCode::OK)),
+        Err(error) => Ok(warp::reply::with_status(
+            error.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
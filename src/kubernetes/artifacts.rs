@@ -0,0 +1,316 @@
+use crate::artifacts::uri_encode;
+use crate::kubernetes::{KubernetesContainer, StepWithCheckRunId};
+use k8s_openapi::api::core::v1::{Container, EnvVar, VolumeMount};
+use serde_derive::Deserialize;
+use vec1::Vec1;
+
+pub const ARTIFACTS_UPLOAD_CONTAINER_NAME: &str = "kubesci-artifacts-upload";
+// Written by a step's container with its exit code once its commands finish, so its artifacts
+// and cache can be saved
+pub const STEP_FINISHED_MARKER: &str = ".kubesci-step-finished";
+// Written by a step's container with the pid of its shell, so whatever waits on the step can tell
+// it was stopped before writing the finished marker
+pub const STEP_PID_FILE: &str = ".kubesci-step-pid";
+
+// Busybox can't URL encode, so every byte of a path is percent-encoded and decoded by the routes
+const URI_ENCODE_FUNCTION: &str = "uri_encode() { printf '%s' \"$1\" | od -An -tx1 -v | \
+                                   awk '{ for (i = 1; i <= NF; i++) printf \"%%%s\", toupper($i) }'; }\n";
+
+const ARTIFACTS_IMAGE: &str = "busybox:1.32";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ArtifactOptions {
+    pub download: Vec1<String>,
+}
+
+// Where a pod's artifacts go. The token only gives access to the artifacts of the pod's commit.
+pub struct ArtifactsEndpoint {
+    pub url: String,
+    pub token: String,
+}
+
+// Uploads each step's artifact paths once its container has finished. Containers can't run after
+// the steps in a pod, so this runs alongside them and waits.
+pub struct ArtifactsUploadContainer<'a> {
+    pub steps_with_check_run_id: &'a [StepWithCheckRunId<'a>],
+    pub endpoint: &'a ArtifactsEndpoint,
+}
+
+// Downloads artifacts from earlier steps of the same commit into each step's checkout
+pub struct ArtifactsDownloadInitContainer<'a> {
+    pub steps_with_check_run_id: &'a [StepWithCheckRunId<'a>],
+    pub endpoint: &'a ArtifactsEndpoint,
+}
+
+// Waits for the finished marker in a step's volume, giving up once the step's shell is gone without
+// writing it, such as when its container was killed. Needs the pod to share its processes.
+pub fn wait_for_step_function() -> String {
+    format!(
+        "step_done() {{ [ -f \"/$1/{marker}\" ] || \
+         {{ [ -f \"/$1/{pid}\" ] && [ ! -d \"/proc/$(cat \"/$1/{pid}\")\" ]; }}; }}\n\
+         wait_for_step() {{ until step_done \"$1\"; do sleep 1; done; \
+         [ -f \"/$1/{marker}\" ] || {{ echo \"The step of $1 stopped without finishing\"; return 1; }}; }}\n",
+        marker = STEP_FINISHED_MARKER,
+        pid = STEP_PID_FILE
+    )
+}
+
+impl<'a> ArtifactsUploadContainer<'a> {
+    pub fn is_needed(&self) -> bool {
+        self.steps_with_check_run_id
+            .iter()
+            .any(|step_with_check_run_id| step_with_check_run_id.step.artifact_paths.is_some())
+    }
+}
+
+impl<'a> KubernetesContainer for ArtifactsUploadContainer<'a> {
+    fn to_container(&self) -> Container {
+        let mut script = format!(
+            "set -e\n{}{}",
+            wait_for_step_function(),
+            URI_ENCODE_FUNCTION
+        );
+        let mut volume_names = Vec::new();
+
+        for step_with_check_run_id in self.steps_with_check_run_id {
+            if let Some(artifact_paths) = &step_with_check_run_id.step.artifact_paths {
+                let volume_name = step_with_check_run_id.check_run_id.to_string();
+
+                // Whatever the step left behind is uploaded, even if it was stopped
+                script += &format!(
+                    "wait_for_step {volume} || true\ncd /{volume}\n",
+                    volume = volume_name
+                );
+
+                // Left unquoted so the shell expands the globs, with directories uploaded whole
+                for artifact_path in artifact_paths {
+                    script += &format!(
+                        "for match in {}; do [ -e \"$match\" ] && find \"$match\" -type f; done | \
+                         while read -r file; do \
+                         file=\"${{file#./}}\"; echo \"Uploading $file\"; \
+                         wget -q -O /dev/null --post-file=\"$file\" \"$ARTIFACTS_URL/$(uri_encode \"$file\")?token=$ARTIFACTS_TOKEN\"; \
+                         done\n",
+                        artifact_path
+                    );
+                }
+
+                volume_names.push(volume_name);
+            }
+        }
+
+        artifacts_container(
+            ARTIFACTS_UPLOAD_CONTAINER_NAME,
+            script,
+            &volume_names,
+            self.endpoint,
+        )
+    }
+}
+
+impl<'a> ArtifactsDownloadInitContainer<'a> {
+    pub fn is_needed(&self) -> bool {
+        self.steps_with_check_run_id
+            .iter()
+            .any(|step_with_check_run_id| step_with_check_run_id.step.artifacts.is_some())
+    }
+}
+
+impl<'a> KubernetesContainer for ArtifactsDownloadInitContainer<'a> {
+    fn to_container(&self) -> Container {
+        let mut script = format!("set -eo pipefail\n{}", URI_ENCODE_FUNCTION);
+        let mut volume_names = Vec::new();
+
+        for step_with_check_run_id in self.steps_with_check_run_id {
+            if let Some(artifacts) = &step_with_check_run_id.step.artifacts {
+                let volume_name = step_with_check_run_id.check_run_id.to_string();
+
+                for glob in &artifacts.download {
+                    script += &format!(
+                        "paths=$(wget -q -O - \"$ARTIFACTS_URL?format=text&glob={encoded_glob}&token=$ARTIFACTS_TOKEN\")\n\
+                         [ -n \"$paths\" ] || {{ echo 'No artifacts match {glob}'; exit 1; }}\n\
+                         echo \"$paths\" | while read -r path; do \
+                         echo \"Downloading $path\"; \
+                         mkdir -p \"$(dirname \"/{volume}/$path\")\"; \
+                         wget -q -O \"/{volume}/$path\" \"$ARTIFACTS_URL/$(uri_encode \"$path\")?token=$ARTIFACTS_TOKEN\"; \
+                         done\n",
+                        encoded_glob = uri_encode(glob, true),
+                        glob = glob.replace("'", ""),
+                        volume = volume_name
+                    );
+                }
+
+                volume_names.push(volume_name);
+            }
+        }
+
+        artifacts_container(
+            "kubesci-artifacts-download",
+            script,
+            &volume_names,
+            self.endpoint,
+        )
+    }
+}
+
+fn artifacts_container(
+    name: &str,
+    script: String,
+    volume_names: &[String],
+    endpoint: &ArtifactsEndpoint,
+) -> Container {
+    let volume_mounts = volume_names
+        .iter()
+        .map(|volume_name| VolumeMount {
+            mount_path: format!("/{}", volume_name),
+            mount_propagation: None,
+            name: volume_name.to_string(),
+            read_only: None,
+            sub_path: None,
+            sub_path_expr: None,
+        })
+        .collect();
+
+    let env = vec![
+        EnvVar {
+            name: "ARTIFACTS_URL".to_string(),
+            value: Some(endpoint.url.clone()),
+            value_from: None,
+        },
+        EnvVar {
+            name: "ARTIFACTS_TOKEN".to_string(),
+            value: Some(endpoint.token.clone()),
+            value_from: None,
+        },
+    ];
+
+    Container {
+        args: None,
+        command: Some(vec!["/bin/sh".to_string(), "-c".to_string(), script]),
+        env: Some(env),
+        env_from: None,
+        image: Some(ARTIFACTS_IMAGE.to_string()),
+        image_pull_policy: None,
+        lifecycle: None,
+        liveness_probe: None,
+        name: name.to_string(),
+        ports: None,
+        readiness_probe: None,
+        resources: None,
+        security_context: None,
+        startup_probe: None,
+        stdin: None,
+        stdin_once: None,
+        termination_message_path: None,
+        termination_message_policy: None,
+        tty: None,
+        volume_devices: None,
+        volume_mounts: Some(volume_mounts),
+        working_dir: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::Step;
+
+    fn endpoint() -> ArtifactsEndpoint {
+        ArtifactsEndpoint {
+            url: "http://kubesci/artifacts/some.repo/abc".to_string(),
+            token: "some-token".to_string(),
+        }
+    }
+
+    #[test]
+    fn should_only_upload_artifacts_of_steps_with_artifact_paths() {
+        let build = Step {
+            name: "build".to_string(),
+            artifact_paths: Some(vec1!["dist/*".to_string()]),
+            ..Default::default()
+        };
+        let lint = Step {
+            name: "lint".to_string(),
+            ..Default::default()
+        };
+
+        let steps_with_check_run_id = vec![
+            StepWithCheckRunId {
+                step: &build,
+                check_run_id: 1,
+                attempt: 1,
+            },
+            StepWithCheckRunId {
+                step: &lint,
+                check_run_id: 2,
+                attempt: 1,
+            },
+        ];
+
+        let endpoint = endpoint();
+
+        let upload_container = ArtifactsUploadContainer {
+            steps_with_check_run_id: &steps_with_check_run_id,
+            endpoint: &endpoint,
+        };
+
+        assert!(upload_container.is_needed());
+
+        let container = upload_container.to_container();
+        let script = &container.command.unwrap()[2];
+
+        assert!(script.contains("wait_for_step 1 || true\ncd /1\n"));
+        assert!(
+            script.contains("\"$ARTIFACTS_URL/$(uri_encode \"$file\")?token=$ARTIFACTS_TOKEN\"")
+        );
+        assert!(script.contains("for match in dist/*;"));
+        assert!(!script.contains("/2/"));
+        assert_eq!(
+            container
+                .volume_mounts
+                .unwrap()
+                .iter()
+                .map(|volume_mount| volume_mount.mount_path.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["/1"]
+        );
+
+        let download_container = ArtifactsDownloadInitContainer {
+            steps_with_check_run_id: &steps_with_check_run_id,
+            endpoint: &endpoint,
+        };
+
+        assert!(!download_container.is_needed());
+    }
+
+    #[test]
+    fn should_download_matching_artifacts_into_the_step_checkout() {
+        let release = Step {
+            name: "release".to_string(),
+            artifacts: Some(ArtifactOptions {
+                download: vec1!["dist/*.tar.gz".to_string()],
+            }),
+            ..Default::default()
+        };
+
+        let steps_with_check_run_id = vec![StepWithCheckRunId {
+            step: &release,
+            check_run_id: 3,
+            attempt: 1,
+        }];
+
+        let endpoint = endpoint();
+
+        let container = ArtifactsDownloadInitContainer {
+            steps_with_check_run_id: &steps_with_check_run_id,
+            endpoint: &endpoint,
+        }
+        .to_container();
+
+        let script = &container.command.unwrap()[2];
+
+        assert!(script.contains("glob=dist%2F%2A.tar.gz"));
+        assert!(
+            script.contains("\"$ARTIFACTS_URL/$(uri_encode \"$path\")?token=$ARTIFACTS_TOKEN\"")
+        );
+    }
+}
@@ -0,0 +1,575 @@
+use crate::artifacts::uri_encode;
+use crate::kubernetes::artifacts::{wait_for_step_function, STEP_FINISHED_MARKER};
+use crate::kubernetes::{KubernetesContainer, StepWithCheckRunId};
+use k8s_openapi::api::core::v1::{Container, EnvVar, VolumeMount};
+use regex::Regex;
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
+use vec1::Vec1;
+
+const CACHE_IMAGE: &str = "busybox:1.32";
+// Where the cache volume is mounted in step containers, so they can print whether it was restored
+const CACHE_STATUS_MOUNT_PATH: &str = "/kubesci-cache";
+const CACHE_STATUS_FILE: &str = ".kubesci-cache-status";
+const CACHE_KEY_FILE: &str = ".kubesci-cache-key";
+const CACHE_HIT_PREFIX: &str = "Cache hit for ";
+const CACHE_MISS_PREFIX: &str = "Cache miss for ";
+
+// Keys are rendered in the pod once the repo is checked out, as they can depend on its files
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "RawCacheOptions")]
+pub struct CacheOptions {
+    pub key: Vec<CacheKeyPart>,
+    pub paths: Vec1<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheKeyPart {
+    Literal(String),
+    Checksum(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCacheOptions {
+    key: String,
+    paths: Vec1<String>,
+}
+
+impl TryFrom<RawCacheOptions> for CacheOptions {
+    type Error = String;
+
+    fn try_from(raw_cache_options: RawCacheOptions) -> Result<Self, Self::Error> {
+        Ok(CacheOptions {
+            key: parse_cache_key(&raw_cache_options.key)?,
+            paths: raw_cache_options.paths,
+        })
+    }
+}
+
+impl CacheOptions {
+    // A shell expression for the key, with a checksum of a missing file rendered as `missing`
+    fn key_script(&self) -> String {
+        self.key
+            .iter()
+            .map(|part| match part {
+                CacheKeyPart::Literal(literal) => literal.to_string(),
+                CacheKeyPart::Checksum(file) => format!(
+                    "$( (sha256sum '{}' 2>/dev/null || echo missing) | cut -d ' ' -f 1)",
+                    file
+                ),
+            })
+            .collect()
+    }
+}
+
+// Only `{{ checksum 'file' }}` is supported, with the rest of the key used as is
+fn parse_cache_key(key: &str) -> Result<Vec<CacheKeyPart>, String> {
+    let checksum_regex = Regex::new(r"\{\{\s*checksum\s+'([^']+)'\s*\}\}").unwrap();
+    let literal_regex = Regex::new(r"^[A-Za-z0-9._-]*$").unwrap();
+
+    let mut parts = Vec::new();
+    let mut last_end = 0;
+
+    for captures in checksum_regex.captures_iter(key) {
+        let checksum = captures.get(0).unwrap();
+
+        parts.push(CacheKeyPart::Literal(
+            key[last_end..checksum.start()].to_string(),
+        ));
+        parts.push(CacheKeyPart::Checksum(captures[1].to_string()));
+
+        last_end = checksum.end();
+    }
+
+    parts.push(CacheKeyPart::Literal(key[last_end..].to_string()));
+
+    let parts: Vec<CacheKeyPart> = parts
+        .into_iter()
+        .filter(|part| part != &CacheKeyPart::Literal("".to_string()))
+        .collect();
+
+    for part in &parts {
+        if let CacheKeyPart::Literal(literal) = part {
+            if !literal_regex.is_match(literal) {
+                return Err(format!(
+                    "The cache key {} can only have letters, numbers, `.`, `_`, `-` and checksums",
+                    key
+                ));
+            }
+        }
+    }
+
+    if parts.is_empty() {
+        return Err("The cache key can't be empty".to_string());
+    }
+
+    Ok(parts)
+}
+
+// Each step with a cache gets its own volume, with a directory per cached path
+pub fn cache_volume_name(check_run_id: u32) -> String {
+    format!("cache-{}", check_run_id)
+}
+
+// Relative paths are in the checkout, and `~` is the home of the root user most images run as
+fn cache_mount_path(path: &str) -> String {
+    let path = path.trim_end_matches('/');
+
+    if path == "~" {
+        "/root".to_string()
+    } else if let Some(home_path) = path.strip_prefix("~/") {
+        format!("/root/{}", home_path)
+    } else if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/app/{}", path.trim_start_matches("./"))
+    }
+}
+
+pub fn cache_volume_mounts(step_with_check_run_id: &StepWithCheckRunId) -> Vec<VolumeMount> {
+    let cache = match &step_with_check_run_id.step.cache {
+        Some(cache) => cache,
+        None => return vec![],
+    };
+
+    let volume_name = cache_volume_name(step_with_check_run_id.check_run_id);
+
+    let status_mount = VolumeMount {
+        mount_path: CACHE_STATUS_MOUNT_PATH.to_string(),
+        mount_propagation: None,
+        name: volume_name.clone(),
+        read_only: Some(true),
+        sub_path: None,
+        sub_path_expr: None,
+    };
+
+    let path_mounts = cache
+        .paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| VolumeMount {
+            mount_path: cache_mount_path(path),
+            mount_propagation: None,
+            name: volume_name.clone(),
+            read_only: None,
+            sub_path: Some(index.to_string()),
+            sub_path_expr: None,
+        });
+
+    std::iter::once(status_mount).chain(path_mounts).collect()
+}
+
+// Printed at the start of the step's logs, so the pod informer can add it to the summary
+pub fn print_cache_status_command() -> String {
+    format!(
+        "cat {}/{} 2>/dev/null",
+        CACHE_STATUS_MOUNT_PATH, CACHE_STATUS_FILE
+    )
+}
+
+pub fn cache_summary(logs: &str) -> Option<&str> {
+    logs.lines()
+        .find(|line| line.starts_with(CACHE_HIT_PREFIX) || line.starts_with(CACHE_MISS_PREFIX))
+}
+
+// Where a pod's caches go. The token only allows saving caches for the pod's repo and branch,
+// while restoring can fall back to another branch, such as the default branch.
+pub struct CacheEndpoint {
+    pub url: String,
+    pub token: String,
+    pub fallback_branch: Option<String>,
+}
+
+// Restores each step's cache into its volume once the repo is checked out. Failing to restore is
+// reported as a miss rather than failing the step.
+pub struct CacheRestoreInitContainer<'a> {
+    pub steps_with_check_run_id: &'a [StepWithCheckRunId<'a>],
+    pub endpoint: &'a CacheEndpoint,
+}
+
+// Saves each step's cache once its commands succeed, unless it was restored with the same key
+pub struct CacheSaveContainer<'a> {
+    pub steps_with_check_run_id: &'a [StepWithCheckRunId<'a>],
+    pub endpoint: &'a CacheEndpoint,
+}
+
+fn steps_with_cache<'a>(
+    steps_with_check_run_id: &'a [StepWithCheckRunId<'a>],
+) -> impl Iterator<Item = (&'a StepWithCheckRunId<'a>, &'a CacheOptions)> {
+    steps_with_check_run_id
+        .iter()
+        .filter_map(|step_with_check_run_id| {
+            step_with_check_run_id
+                .step
+                .cache
+                .as_ref()
+                .map(|cache| (step_with_check_run_id, cache))
+        })
+}
+
+impl<'a> CacheRestoreInitContainer<'a> {
+    pub fn is_needed(&self) -> bool {
+        steps_with_cache(self.steps_with_check_run_id)
+            .next()
+            .is_some()
+    }
+}
+
+impl<'a> KubernetesContainer for CacheRestoreInitContainer<'a> {
+    fn to_container(&self) -> Container {
+        let mut script = String::new();
+
+        for (step_with_check_run_id, cache) in steps_with_cache(self.steps_with_check_run_id) {
+            let check_run_id = step_with_check_run_id.check_run_id;
+            let cache_directory = format!("/{}", cache_volume_name(check_run_id));
+
+            let path_directories: Vec<String> = (0..cache.paths.len())
+                .map(|index| format!("{}/{}", cache_directory, index))
+                .collect();
+
+            script += &format!(
+                "cd /{check_run_id}\n\
+                 key=\"{key}\"\n\
+                 echo \"$key\" > {cache}/{key_file}\n\
+                 mkdir -p {paths}\n\
+                 if wget -q -O /tmp/cache.tar.gz \"$CACHE_URL/$key?token=$CACHE_TOKEN&fallback_branch=$CACHE_FALLBACK_BRANCH\" && tar -xzf /tmp/cache.tar.gz -C {cache}; then \
+                 echo \"{hit}$key\" > {cache}/{status_file}; \
+                 else echo \"{miss}$key\" > {cache}/{status_file}; fi\n\
+                 rm -f /tmp/cache.tar.gz\n\
+                 cat {cache}/{status_file}\n",
+                check_run_id = check_run_id,
+                key = cache.key_script(),
+                cache = cache_directory,
+                key_file = CACHE_KEY_FILE,
+                status_file = CACHE_STATUS_FILE,
+                paths = path_directories.join(" "),
+                hit = CACHE_HIT_PREFIX,
+                miss = CACHE_MISS_PREFIX
+            );
+        }
+
+        cache_container(
+            "kubesci-cache-restore",
+            script,
+            self.steps_with_check_run_id,
+            self.endpoint,
+        )
+    }
+}
+
+impl<'a> CacheSaveContainer<'a> {
+    pub fn is_needed(&self) -> bool {
+        steps_with_cache(self.steps_with_check_run_id)
+            .next()
+            .is_some()
+    }
+}
+
+impl<'a> KubernetesContainer for CacheSaveContainer<'a> {
+    fn to_container(&self) -> Container {
+        let mut script = wait_for_step_function();
+
+        for (step_with_check_run_id, cache) in steps_with_cache(self.steps_with_check_run_id) {
+            let check_run_id = step_with_check_run_id.check_run_id;
+            let cache_directory = format!("/{}", cache_volume_name(check_run_id));
+
+            let path_directories: Vec<String> = (0..cache.paths.len())
+                .map(|index| index.to_string())
+                .collect();
+
+            // The marker holds the exit code of the step's commands
+            script += &format!(
+                "key=$(cat {cache}/{key_file})\n\
+                 if wait_for_step {check_run_id} && [ \"$(cat /{check_run_id}/{marker})\" = 0 ] && ! grep -q '^{hit}' {cache}/{status_file}; then \
+                 echo \"Saving cache $key\"; \
+                 tar -czf /tmp/cache.tar.gz -C {cache} {paths} && \
+                 wget -q -O /dev/null --post-file=/tmp/cache.tar.gz \"$CACHE_URL/$key?token=$CACHE_TOKEN\" || \
+                 echo \"Unable to save cache $key\"; \
+                 rm -f /tmp/cache.tar.gz; \
+                 fi\n",
+                check_run_id = check_run_id,
+                marker = STEP_FINISHED_MARKER,
+                cache = cache_directory,
+                key_file = CACHE_KEY_FILE,
+                status_file = CACHE_STATUS_FILE,
+                paths = path_directories.join(" "),
+                hit = CACHE_HIT_PREFIX
+            );
+        }
+
+        cache_container(
+            "kubesci-cache-save",
+            script,
+            self.steps_with_check_run_id,
+            self.endpoint,
+        )
+    }
+}
+
+fn cache_container(
+    name: &str,
+    script: String,
+    steps_with_check_run_id: &[StepWithCheckRunId],
+    endpoint: &CacheEndpoint,
+) -> Container {
+    let volume_mounts = steps_with_cache(steps_with_check_run_id)
+        .flat_map(|(step_with_check_run_id, _)| {
+            let check_run_id = step_with_check_run_id.check_run_id;
+
+            vec![
+                VolumeMount {
+                    mount_path: format!("/{}", check_run_id),
+                    mount_propagation: None,
+                    name: check_run_id.to_string(),
+                    read_only: None,
+                    sub_path: None,
+                    sub_path_expr: None,
+                },
+                VolumeMount {
+                    mount_path: format!("/{}", cache_volume_name(check_run_id)),
+                    mount_propagation: None,
+                    name: cache_volume_name(check_run_id),
+                    read_only: None,
+                    sub_path: None,
+                    sub_path_expr: None,
+                },
+            ]
+        })
+        .collect();
+
+    let env = vec![
+        EnvVar {
+            name: "CACHE_URL".to_string(),
+            value: Some(endpoint.url.clone()),
+            value_from: None,
+        },
+        EnvVar {
+            name: "CACHE_TOKEN".to_string(),
+            value: Some(endpoint.token.clone()),
+            value_from: None,
+        },
+        EnvVar {
+            name: "CACHE_FALLBACK_BRANCH".to_string(),
+            value: Some(
+                endpoint
+                    .fallback_branch
+                    .as_deref()
+                    .map(|fallback_branch| uri_encode(fallback_branch, true))
+                    .unwrap_or_default(),
+            ),
+            value_from: None,
+        },
+    ];
+
+    Container {
+        args: None,
+        command: Some(vec!["/bin/sh".to_string(), "-c".to_string(), script]),
+        env: Some(env),
+        env_from: None,
+        image: Some(CACHE_IMAGE.to_string()),
+        image_pull_policy: None,
+        lifecycle: None,
+        liveness_probe: None,
+        name: name.to_string(),
+        ports: None,
+        readiness_probe: None,
+        resources: None,
+        security_context: None,
+        startup_probe: None,
+        stdin: None,
+        stdin_once: None,
+        termination_message_path: None,
+        termination_message_policy: None,
+        tty: None,
+        volume_devices: None,
+        volume_mounts: Some(volume_mounts),
+        working_dir: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::Step;
+
+    fn endpoint() -> CacheEndpoint {
+        CacheEndpoint {
+            url: "http://kubesci/caches/some.repo/feature%2Fx".to_string(),
+            token: "some-token".to_string(),
+            fallback_branch: Some("main".to_string()),
+        }
+    }
+
+    #[test]
+    fn should_parse_checksums_in_cache_keys() {
+        let cache_options: CacheOptions = serde_yaml::from_str(
+            r#"
+key: "cargo-{{ checksum 'Cargo.lock' }}-v1"
+paths:
+  - target
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            cache_options.key,
+            vec![
+                CacheKeyPart::Literal("cargo-".to_string()),
+                CacheKeyPart::Checksum("Cargo.lock".to_string()),
+                CacheKeyPart::Literal("-v1".to_string()),
+            ]
+        );
+        assert_eq!(
+            cache_options.key_script(),
+            "cargo-$( (sha256sum 'Cargo.lock' 2>/dev/null || echo missing) | cut -d ' ' -f 1)-v1"
+        );
+    }
+
+    #[test]
+    fn should_reject_cache_keys_that_are_unsafe_in_a_path() {
+        let error = serde_yaml::from_str::<CacheOptions>(
+            r#"
+key: "cargo/$(whoami)"
+paths:
+  - target
+"#,
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains(
+            "The cache key cargo/$(whoami) can only have letters, numbers, `.`, `_`, `-` and checksums"
+        ));
+    }
+
+    #[test]
+    fn should_mount_each_cached_path_from_the_cache_volume() {
+        let step = Step {
+            name: "test".to_string(),
+            cache: Some(CacheOptions {
+                key: vec![CacheKeyPart::Literal("cargo".to_string())],
+                paths: vec1![
+                    "target".to_string(),
+                    "~/.cargo/registry".to_string(),
+                    "/usr/local/cache/".to_string()
+                ],
+            }),
+            ..Default::default()
+        };
+
+        let step_with_check_run_id = StepWithCheckRunId {
+            step: &step,
+            check_run_id: 4,
+            attempt: 1,
+        };
+
+        let volume_mounts: Vec<(String, Option<String>)> =
+            cache_volume_mounts(&step_with_check_run_id)
+                .into_iter()
+                .map(|volume_mount| (volume_mount.mount_path, volume_mount.sub_path))
+                .collect();
+
+        assert_eq!(
+            volume_mounts,
+            vec![
+                ("/kubesci-cache".to_string(), None),
+                ("/app/target".to_string(), Some("0".to_string())),
+                ("/root/.cargo/registry".to_string(), Some("1".to_string())),
+                ("/usr/local/cache".to_string(), Some("2".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_only_save_caches_of_successful_steps_that_missed() {
+        let step = Step {
+            name: "test".to_string(),
+            cache: Some(CacheOptions {
+                key: vec![CacheKeyPart::Literal("cargo".to_string())],
+                paths: vec1!["target".to_string()],
+            }),
+            ..Default::default()
+        };
+        let lint = Step {
+            name: "lint".to_string(),
+            ..Default::default()
+        };
+
+        let steps_with_check_run_id = vec![
+            StepWithCheckRunId {
+                step: &step,
+                check_run_id: 4,
+                attempt: 1,
+            },
+            StepWithCheckRunId {
+                step: &lint,
+                check_run_id: 5,
+                attempt: 1,
+            },
+        ];
+
+        let endpoint = endpoint();
+
+        let container = CacheSaveContainer {
+            steps_with_check_run_id: &steps_with_check_run_id,
+            endpoint: &endpoint,
+        }
+        .to_container();
+
+        let script = &container.command.unwrap()[2];
+
+        assert!(script.contains(&format!(
+            "if wait_for_step 4 && [ \"$(cat /4/{})\" = 0 ] && ! grep -q '^Cache hit for ' /cache-4/.kubesci-cache-status;",
+            STEP_FINISHED_MARKER
+        )));
+        assert!(script.contains("tar -czf /tmp/cache.tar.gz -C /cache-4 0 &&"));
+        assert!(!script.contains("/5/"));
+    }
+
+    #[test]
+    fn should_restore_caches_from_the_fallback_branch_when_the_branch_has_none() {
+        let step = Step {
+            name: "test".to_string(),
+            cache: Some(CacheOptions {
+                key: vec![CacheKeyPart::Literal("cargo".to_string())],
+                paths: vec1!["target".to_string()],
+            }),
+            ..Default::default()
+        };
+
+        let steps_with_check_run_id = vec![StepWithCheckRunId {
+            step: &step,
+            check_run_id: 4,
+            attempt: 1,
+        }];
+
+        let endpoint = CacheEndpoint {
+            fallback_branch: Some("release/1.x".to_string()),
+            ..endpoint()
+        };
+
+        let container = CacheRestoreInitContainer {
+            steps_with_check_run_id: &steps_with_check_run_id,
+            endpoint: &endpoint,
+        }
+        .to_container();
+
+        assert!(container.command.unwrap()[2].contains(
+            "\"$CACHE_URL/$key?token=$CACHE_TOKEN&fallback_branch=$CACHE_FALLBACK_BRANCH\""
+        ));
+        assert!(container.env.unwrap().contains(&EnvVar {
+            name: "CACHE_FALLBACK_BRANCH".to_string(),
+            value: Some("release%2F1.x".to_string()),
+            value_from: None,
+        }));
+    }
+
+    #[test]
+    fn should_find_the_cache_status_in_the_logs() {
+        assert_eq!(
+            cache_summary("Cache hit for cargo-abc\nRunning tests"),
+            Some("Cache hit for cargo-abc")
+        );
+        assert_eq!(cache_summary("Running tests"), None);
+    }
+}
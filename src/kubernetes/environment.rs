@@ -0,0 +1,306 @@
+use k8s_openapi::api::core::v1::{
+    ConfigMapEnvSource, ConfigMapKeySelector, EnvFromSource, EnvVar, EnvVarSource,
+    ObjectFieldSelector, SecretEnvSource, SecretKeySelector,
+};
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
+
+// Checked by hand rather than untagged, so a bad env says what's wrong with it
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "RawEnvironment")]
+pub enum Environment {
+    BasicEnv { name: String, value: String },
+    ValueFromEnv { name: String, value_from: ValueFrom },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEnvironment {
+    name: String,
+    value: Option<String>,
+    #[serde(rename = "valueFrom")]
+    value_from: Option<ValueFrom>,
+}
+
+impl TryFrom<RawEnvironment> for Environment {
+    type Error = String;
+
+    fn try_from(raw_environment: RawEnvironment) -> Result<Self, Self::Error> {
+        let name = raw_environment.name;
+
+        match (raw_environment.value, raw_environment.value_from) {
+            (Some(value), None) => Ok(Environment::BasicEnv { name, value }),
+            (None, Some(value_from)) => Ok(Environment::ValueFromEnv { name, value_from }),
+            (Some(_), Some(_)) => Err(format!(
+                "The env {} can't have both a value and a valueFrom",
+                name
+            )),
+            (None, None) => Err(format!("The env {} needs a value or a valueFrom", name)),
+        }
+    }
+}
+
+impl Environment {
+    pub fn name(&self) -> &str {
+        match self {
+            Environment::BasicEnv { name, .. } => name,
+            Environment::ValueFromEnv { name, .. } => name,
+        }
+    }
+
+    pub fn to_env_var(&self) -> EnvVar {
+        match self {
+            Environment::BasicEnv { name, value } => EnvVar {
+                name: name.clone(),
+                value: Some(value.clone()),
+                value_from: None,
+            },
+            Environment::ValueFromEnv { name, value_from } => EnvVar {
+                name: name.clone(),
+                value: None,
+                value_from: Some(value_from.to_env_var_source()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub enum ValueFrom {
+    #[serde(rename = "secretKeyRef")]
+    SecretKey(KeyRef),
+    #[serde(rename = "configMapKeyRef")]
+    ConfigMapKey(KeyRef),
+    #[serde(rename = "fieldRef")]
+    Field(FieldRef),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRef {
+    pub name: String,
+    pub key: String,
+    pub optional: Option<bool>,
+}
+
+// The downward API, such as metadata.name or status.podIP
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FieldRef {
+    #[serde(rename = "fieldPath")]
+    pub field_path: String,
+    #[serde(rename = "apiVersion")]
+    pub api_version: Option<String>,
+}
+
+impl ValueFrom {
+    fn to_env_var_source(&self) -> EnvVarSource {
+        let mut env_var_source = EnvVarSource {
+            config_map_key_ref: None,
+            field_ref: None,
+            resource_field_ref: None,
+            secret_key_ref: None,
+        };
+
+        match self {
+            ValueFrom::SecretKey(key_ref) => {
+                env_var_source.secret_key_ref = Some(SecretKeySelector {
+                    key: key_ref.key.clone(),
+                    name: Some(key_ref.name.clone()),
+                    optional: key_ref.optional,
+                })
+            }
+            ValueFrom::ConfigMapKey(key_ref) => {
+                env_var_source.config_map_key_ref = Some(ConfigMapKeySelector {
+                    key: key_ref.key.clone(),
+                    name: Some(key_ref.name.clone()),
+                    optional: key_ref.optional,
+                })
+            }
+            ValueFrom::Field(field_ref) => {
+                env_var_source.field_ref = Some(ObjectFieldSelector {
+                    api_version: field_ref.api_version.clone(),
+                    field_path: field_ref.field_path.clone(),
+                })
+            }
+        }
+
+        env_var_source
+    }
+}
+
+// Every key of a secret or config map as an env, optionally prefixed
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "RawEnvFrom")]
+pub struct EnvFrom {
+    pub source: EnvFromSourceRef,
+    pub prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvFromSourceRef {
+    Secret(NameRef),
+    ConfigMap(NameRef),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NameRef {
+    pub name: String,
+    pub optional: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEnvFrom {
+    #[serde(rename = "secretRef")]
+    secret_ref: Option<NameRef>,
+    #[serde(rename = "configMapRef")]
+    config_map_ref: Option<NameRef>,
+    prefix: Option<String>,
+}
+
+impl TryFrom<RawEnvFrom> for EnvFrom {
+    type Error = String;
+
+    fn try_from(raw_env_from: RawEnvFrom) -> Result<Self, Self::Error> {
+        let source = match (raw_env_from.secret_ref, raw_env_from.config_map_ref) {
+            (Some(secret_ref), None) => EnvFromSourceRef::Secret(secret_ref),
+            (None, Some(config_map_ref)) => EnvFromSourceRef::ConfigMap(config_map_ref),
+            (Some(_), Some(_)) => {
+                return Err("An envFrom can't have both a secretRef and a configMapRef".into())
+            }
+            (None, None) => return Err("An envFrom needs a secretRef or a configMapRef".into()),
+        };
+
+        Ok(EnvFrom {
+            source,
+            prefix: raw_env_from.prefix,
+        })
+    }
+}
+
+impl EnvFrom {
+    pub fn to_env_from_source(&self) -> EnvFromSource {
+        let (config_map_ref, secret_ref) = match &self.source {
+            EnvFromSourceRef::Secret(name_ref) => (
+                None,
+                Some(SecretEnvSource {
+                    name: Some(name_ref.name.clone()),
+                    optional: name_ref.optional,
+                }),
+            ),
+            EnvFromSourceRef::ConfigMap(name_ref) => (
+                Some(ConfigMapEnvSource {
+                    name: Some(name_ref.name.clone()),
+                    optional: name_ref.optional,
+                }),
+                None,
+            ),
+        };
+
+        EnvFromSource {
+            config_map_ref,
+            prefix: self.prefix.clone(),
+            secret_ref,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_env(raw_env: &str) -> Result<Environment, serde_yaml::Error> {
+        serde_yaml::from_str(raw_env)
+    }
+
+    #[test]
+    fn should_map_value_from_references_onto_env_var_sources() {
+        let config_map_env = decode_env(
+            r#"
+name: LOG_LEVEL
+valueFrom:
+  configMapKeyRef:
+    name: settings
+    key: log-level
+"#,
+        )
+        .unwrap()
+        .to_env_var();
+
+        let config_map_key_ref = config_map_env.value_from.unwrap().config_map_key_ref;
+
+        assert_eq!(
+            config_map_key_ref.map(|key_ref| (key_ref.name, key_ref.key)),
+            Some((Some("settings".to_string()), "log-level".to_string()))
+        );
+
+        let field_env = decode_env(
+            r#"
+name: POD_NAME
+valueFrom:
+  fieldRef:
+    fieldPath: metadata.name
+"#,
+        )
+        .unwrap()
+        .to_env_var();
+
+        assert_eq!(
+            field_env
+                .value_from
+                .unwrap()
+                .field_ref
+                .map(|field_ref| field_ref.field_path),
+            Some("metadata.name".to_string())
+        );
+    }
+
+    #[test]
+    fn should_say_what_is_wrong_with_an_env() {
+        let error = decode_env(
+            "{ name: FOO, value: bar, valueFrom: { fieldRef: { fieldPath: metadata.name } } }",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "The env FOO can't have both a value and a valueFrom"
+        );
+
+        let error =
+            decode_env("{ name: FOO, valueFrom: { secretRef: { name: bar } } }").unwrap_err();
+
+        assert!(error.to_string().contains("unknown variant `secretRef`"));
+
+        let error =
+            decode_env("{ name: FOO, valueFrom: { secretKeyRef: { name: bar } } }").unwrap_err();
+
+        assert!(error.to_string().contains("missing field `key`"));
+    }
+
+    #[test]
+    fn should_map_env_from_onto_env_from_sources() {
+        let env_from: EnvFrom =
+            serde_yaml::from_str("{ configMapRef: { name: settings }, prefix: APP_ }").unwrap();
+
+        let env_from_source = env_from.to_env_from_source();
+
+        assert_eq!(env_from_source.prefix, Some("APP_".to_string()));
+        assert_eq!(
+            env_from_source
+                .config_map_ref
+                .and_then(|config_map_ref| config_map_ref.name),
+            Some("settings".to_string())
+        );
+        assert!(env_from_source.secret_ref.is_none());
+
+        let error = serde_yaml::from_str::<EnvFrom>("{ prefix: APP_ }").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "An envFrom needs a secretRef or a configMapRef"
+        );
+    }
+}
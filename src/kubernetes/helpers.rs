@@ -1,8 +1,30 @@
-use k8s_openapi::api::core::v1::{ContainerState, ContainerStateTerminated, Pod};
+use k8s_openapi::api::core::v1::{Container, ContainerState, ContainerStateTerminated, Pod};
+
+// Only steps are given a check run, unlike services and the artifacts and cache sidecars
+pub fn is_step_container(container: &Container) -> bool {
+    container
+        .env
+        .iter()
+        .flatten()
+        .any(|env| env.name == "CHECK_RUN_ID")
+}
+
+pub fn step_containers(pod: &Pod) -> Vec<&Container> {
+    pod.spec
+        .iter()
+        .flat_map(|pod_spec| pod_spec.containers.iter())
+        .filter(|container| is_step_container(container))
+        .collect()
+}
 
 pub fn extract_newly_finished_container_states(
     pod: &Pod,
 ) -> Option<Vec<(String, ContainerStateTerminated)>> {
+    let step_container_names: Vec<&str> = step_containers(pod)
+        .into_iter()
+        .map(|container| container.name.as_str())
+        .collect();
+
     pod.status
         .as_ref()
         .map(|status| status.container_statuses.as_ref())
@@ -10,6 +32,9 @@ pub fn extract_newly_finished_container_states(
         .map(|container_status| {
             container_status
                 .iter()
+                .filter(|container_status| {
+                    step_container_names.contains(&container_status.name.as_str())
+                })
                 .filter_map(|container_status| {
                     let maybe_last_state = container_status.last_state.as_ref();
                     let maybe_state = container_status.state.as_ref();
@@ -48,3 +73,68 @@ fn extract_terminated_state(
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::{ContainerStatus, EnvVar, PodSpec, PodStatus};
+
+    fn container(name: &str, envs: Vec<&str>) -> Container {
+        Container {
+            name: name.to_string(),
+            env: Some(
+                envs.into_iter()
+                    .map(|env| EnvVar {
+                        name: env.to_string(),
+                        value: Some("1".to_string()),
+                        value_from: None,
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn terminated_status(name: &str) -> ContainerStatus {
+        ContainerStatus {
+            name: name.to_string(),
+            state: Some(ContainerState {
+                terminated: Some(ContainerStateTerminated {
+                    exit_code: 143,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_only_extract_finished_step_containers() {
+        let pod = Pod {
+            spec: Some(PodSpec {
+                containers: vec![
+                    container("step-test-1", vec!["CHECK_RUN_ID"]),
+                    container("service-postgres-1", vec!["KUBESCI_SERVICE_OF"]),
+                ],
+                ..Default::default()
+            }),
+            status: Some(PodStatus {
+                container_statuses: Some(vec![
+                    terminated_status("step-test-1"),
+                    terminated_status("service-postgres-1"),
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let finished_container_names: Vec<String> = extract_newly_finished_container_states(&pod)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        assert_eq!(finished_container_names, vec!["step-test-1".to_string()]);
+    }
+}
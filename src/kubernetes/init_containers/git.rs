@@ -1,9 +1,13 @@
 use crate::kubernetes::KubernetesContainer;
 use k8s_openapi::api::core::v1::{Container, EnvVar, VolumeMount};
 
+pub const GIT_CREDENTIALS_VOLUME_NAME: &str = "kubesci-git-credentials";
+pub const GIT_CREDENTIALS_FILE_NAME: &str = ".git-credentials";
+
 pub struct GitInitContainer<'a> {
     pub clone_url: &'a str,
     pub commit_sha: &'a str,
+    pub git_ref: Option<&'a str>,
     pub volume_mount_names: &'a Vec<String>,
 }
 
@@ -16,6 +20,17 @@ impl<'a> KubernetesContainer for GitInitContainer<'a> {
             .collect::<Vec<String>>()
             .join(";");
 
+        let git_credentials_mount_path = format!("/{}", GIT_CREDENTIALS_VOLUME_NAME);
+
+        let git_credentials_volume_mount = VolumeMount {
+            mount_path: git_credentials_mount_path.clone(),
+            mount_propagation: None,
+            name: GIT_CREDENTIALS_VOLUME_NAME.to_string(),
+            read_only: Some(true),
+            sub_path: None,
+            sub_path_expr: None,
+        };
+
         let volume_mounts = self
             .volume_mount_names
             .iter()
@@ -27,9 +42,10 @@ impl<'a> KubernetesContainer for GitInitContainer<'a> {
                 sub_path: None,
                 sub_path_expr: None,
             })
+            .chain(std::iter::once(git_credentials_volume_mount))
             .collect::<Vec<VolumeMount>>();
 
-        let env = vec![
+        let mut env = vec![
             EnvVar {
                 name: "REPO_URL".to_string(),
                 value: Some(self.clone_url.to_string()),
@@ -45,14 +61,31 @@ impl<'a> KubernetesContainer for GitInitContainer<'a> {
                 value: Some(container_volumes),
                 value_from: None,
             },
+            EnvVar {
+                name: "GIT_CREDENTIALS_FILE".to_string(),
+                value: Some(format!(
+                    "{}/{}",
+                    git_credentials_mount_path, GIT_CREDENTIALS_FILE_NAME
+                )),
+                value_from: None,
+            },
         ];
 
+        // Checks out the ref (e.g. a pull request merge ref) instead of the commit when set
+        if let Some(git_ref) = self.git_ref {
+            env.push(EnvVar {
+                name: "GIT_REF".to_string(),
+                value: Some(git_ref.to_string()),
+                value_from: None,
+            });
+        }
+
         Container {
             args: None,
             command: None,
             env: Some(env),
             env_from: None,
-            image: Some("jordanph/kubesci-git-checkout:1.0.0".to_string()),
+            image: Some("jordanph/kubesci-git-checkout:1.2.0".to_string()),
             image_pull_policy: None,
             lifecycle: None,
             liveness_probe: None,
@@ -84,6 +117,7 @@ mod tests {
         let git_init_container = GitInitContainer {
             clone_url: &repo_name.to_string(),
             commit_sha: &"whatever".to_string(),
+            git_ref: None,
             volume_mount_names: &vec!["".to_string()],
         };
 
@@ -109,6 +143,7 @@ mod tests {
         let git_init_container = GitInitContainer {
             clone_url: &"whatever".to_string(),
             commit_sha: &commit_sha.to_string(),
+            git_ref: None,
             volume_mount_names: &vec!["".to_string()],
         };
 
@@ -138,6 +173,7 @@ mod tests {
         let git_init_container = GitInitContainer {
             clone_url: &"whatever".to_string(),
             commit_sha: &"commit_sha".to_string(),
+            git_ref: None,
             volume_mount_names: &container_volume_names,
         };
 
@@ -167,6 +203,7 @@ mod tests {
         let git_init_container = GitInitContainer {
             clone_url: &"whatever".to_string(),
             commit_sha: &"commit_sha".to_string(),
+            git_ref: None,
             volume_mount_names: &container_volume_names,
         };
 
@@ -199,8 +236,40 @@ mod tests {
                 sub_path: None,
                 sub_path_expr: None,
             },
+            VolumeMount {
+                mount_path: "/kubesci-git-credentials".to_string(),
+                mount_propagation: None,
+                name: "kubesci-git-credentials".to_string(),
+                read_only: Some(true),
+                sub_path: None,
+                sub_path_expr: None,
+            },
         ];
 
         assert_eq!(container_volumes_env, expected_value)
     }
+
+    #[test]
+    fn should_point_git_credentials_file_at_mounted_secret() {
+        let git_init_container = GitInitContainer {
+            clone_url: "whatever",
+            commit_sha: "commit_sha",
+            git_ref: None,
+            volume_mount_names: &vec!["".to_string()],
+        };
+
+        let container = git_init_container.to_container();
+
+        let envs = container.env.unwrap();
+
+        let git_credentials_file_env = envs.get(3).unwrap();
+
+        let expected_value = EnvVar {
+            name: "GIT_CREDENTIALS_FILE".to_string(),
+            value: Some("/kubesci-git-credentials/.git-credentials".to_string()),
+            value_from: None,
+        };
+
+        assert_eq!(git_credentials_file_env, &expected_value)
+    }
 }
@@ -1,41 +1,68 @@
+pub mod artifacts;
+pub mod cache;
+pub mod environment;
 pub mod generate;
 pub mod helpers;
 pub mod init_containers;
+pub mod resources;
+pub mod scheduling;
+pub mod services;
 
-use regex::Regex;
+use crate::pipeline::expressions::Expression;
+use crate::pipeline::steps_filter::{validate_filter, PathsFilter};
+use regex::{Captures, Regex};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
 use vec1::Vec1;
 
-use k8s_openapi::api::core::v1::{Container, EnvVar, EnvVarSource, SecretKeySelector, VolumeMount};
+use artifacts::{ArtifactOptions, STEP_FINISHED_MARKER, STEP_PID_FILE};
+use cache::{cache_volume_mounts, print_cache_status_command, CacheOptions};
+use environment::{EnvFrom, Environment};
+use resources::{ResourceValues, Resources};
+use scheduling::Placement;
+use services::{Service, SERVICES_READY_MARKER};
 
-pub trait KubernetesContainer {
-    fn to_container(&self) -> Container;
-}
+use k8s_openapi::api::core::v1::{Container, EnvVar, VolumeMount};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct SecretKeyRef {
-    name: String,
-    key: String,
-}
+// Matches `timeout`, so steps that time themselves out are reported the same way
+pub const STEP_TIMED_OUT_EXIT_CODE: i32 = 124;
+// Left as the termination message of a step the watchdog stopped, as the exit code alone could come
+// from the step's own commands
+pub const STEP_TIMED_OUT_MESSAGE: &str = "kubesci-step-timed-out";
+// Kept out of the checkout in /app, where the step's own files could clash with it or be uploaded
+// with it
+const STEP_TIMED_OUT_MARKER: &str = "/tmp/.kubesci-step-timed-out";
+// Kubernetes container names are DNS labels
+const MAX_CONTAINER_NAME_LENGTH: usize = 63;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct ValueFrom {
-    #[serde(rename = "secretKeyRef")]
-    secret_key_ref: SecretKeyRef,
+// Container names can be at most 63 characters, such as for the long names of matrix steps, and
+// the check run id keeps them unique once the name is cut short
+pub fn container_name(prefix: &str, name: &str, check_run_id: u32) -> String {
+    let regex = Regex::new(r"[^a-z0-9-]").unwrap();
+
+    let name_with_dashes = name.replace(" ", "-").to_lowercase();
+
+    let name = regex.replace_all(&name_with_dashes, "");
+
+    let check_run_id = check_run_id.to_string();
+
+    let max_name_length =
+        MAX_CONTAINER_NAME_LENGTH - prefix.len() - "-".len() * 2 - check_run_id.len();
+
+    format!(
+        "{}-{}-{}",
+        prefix,
+        &name[..name.len().min(max_name_length)],
+        check_run_id
+    )
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-#[serde(untagged)]
-pub enum Environment {
-    BasicEnv {
-        name: String,
-        value: String,
-    },
-    KubernetesSecretEnv {
-        name: String,
-        #[serde(rename = "valueFrom")]
-        value_from: ValueFrom,
-    },
+pub trait KubernetesContainer {
+    fn to_container(&self) -> Container;
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -45,21 +72,202 @@ pub struct MountSecret {
     pub mount_path: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Step {
     pub name: String,
     pub image: String,
     pub commands: Option<std::vec::Vec<String>>,
     pub args: Option<std::vec::Vec<String>>,
     pub branch: Option<String>,
+    pub event: Option<String>,
+    pub tag: Option<String>,
+    #[serde(rename = "if")]
+    pub condition: Option<Expression>,
+    #[serde(flatten)]
+    pub paths_filter: PathsFilter,
+    // Creates a skipped check run when the step is filtered out, so required checks aren't left
+    // waiting
+    pub report_skipped: Option<bool>,
+    pub key: Option<String>,
+    pub depends_on: Option<Vec1<String>>,
+    pub matrix: Option<BTreeMap<String, Vec1<String>>>,
+    // Enforced around the step's commands, so steps without any only have the pipeline deadline
+    pub timeout_in_minutes: Option<u32>,
+    pub retry: Option<Retry>,
+    pub soft_fail: Option<SoftFail>,
+    pub resources: Option<Resources>,
+    #[serde(flatten)]
+    pub placement: Placement,
     pub env: Option<Vec1<Environment>>,
+    #[serde(rename = "envFrom")]
+    pub env_from: Option<Vec1<EnvFrom>>,
     #[serde(rename = "mountSecret")]
     pub mount_secret: Option<Vec1<MountSecret>>,
+    // Uploaded once the step's commands finish, whether or not they succeeded
+    pub artifact_paths: Option<Vec1<String>>,
+    pub artifacts: Option<ArtifactOptions>,
+    pub cache: Option<CacheOptions>,
+    pub services: Option<Vec1<Service>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Retry {
+    pub limit: u32,
+    // Any failure is retried when no exit statuses are given
+    pub exit_status: Option<Vec<i32>>,
+}
+
+impl Retry {
+    // Containers that never ran have no exit status
+    pub fn should_retry(&self, attempt: u32, exit_code: Option<i32>) -> bool {
+        let retries_exit_status = match (&self.exit_status, exit_code) {
+            (Some(exit_statuses), Some(exit_code)) => exit_statuses.contains(&exit_code),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        attempt <= self.limit && retries_exit_status
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SoftFail {
+    Enabled(bool),
+    ExitStatuses(Vec<i32>),
+}
+
+impl SoftFail {
+    // Containers that never ran have no exit status
+    pub fn allows(&self, exit_code: Option<i32>) -> bool {
+        match (self, exit_code) {
+            (SoftFail::Enabled(enabled), _) => *enabled,
+            (SoftFail::ExitStatuses(exit_statuses), Some(exit_code)) => {
+                exit_statuses.contains(&exit_code)
+            }
+            (SoftFail::ExitStatuses(_), None) => false,
+        }
+    }
+}
+
+// Passed to the step's container, so the pod informer can report the outcome without the pipeline
+impl fmt::Display for SoftFail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoftFail::Enabled(enabled) => write!(f, "{}", enabled),
+            SoftFail::ExitStatuses(exit_statuses) => {
+                let exit_statuses: Vec<String> = exit_statuses
+                    .iter()
+                    .map(|exit_status| exit_status.to_string())
+                    .collect();
+
+                write!(f, "{}", exit_statuses.join(","))
+            }
+        }
+    }
+}
+
+impl FromStr for SoftFail {
+    type Err = ParseIntError;
+
+    fn from_str(soft_fail: &str) -> Result<Self, Self::Err> {
+        match soft_fail {
+            "true" => Ok(SoftFail::Enabled(true)),
+            "false" => Ok(SoftFail::Enabled(false)),
+            exit_statuses => exit_statuses
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<i32>, _>>()
+                .map(SoftFail::ExitStatuses),
+        }
+    }
+}
+
+impl Step {
+    // Each combination of matrix values gets its own step, and so its own container and check run
+    pub fn expand_matrix(&self) -> Vec<Step> {
+        match &self.matrix {
+            Some(matrix) => matrix_combinations(matrix)
+                .iter()
+                .map(|combination| self.with_matrix_values(combination))
+                .collect(),
+            None => vec![self.clone()],
+        }
+    }
+
+    fn with_matrix_values(&self, combination: &[(&str, &str)]) -> Step {
+        let regex = Regex::new(r"\{\{\s*matrix\.([A-Za-z0-9_-]+)\s*\}\}").unwrap();
+
+        let interpolate = |value: &str| {
+            regex
+                .replace_all(value, |captures: &Captures| {
+                    combination
+                        .iter()
+                        .find(|(name, _)| *name == &captures[1])
+                        .map(|(_, value)| value.to_string())
+                        .unwrap_or_else(|| captures[0].to_string())
+                })
+                .into_owned()
+        };
+
+        let interpolate_all = |values: &Vec<String>| -> Vec<String> {
+            values.iter().map(|value| interpolate(value)).collect()
+        };
+
+        let name = if combination.is_empty() {
+            self.name.clone()
+        } else {
+            let values: Vec<String> = combination
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect();
+
+            format!("{} ({})", self.name, values.join(", "))
+        };
+
+        let env = self.env.as_ref().map(|envs| {
+            envs.mapped_ref(|env| match env {
+                Environment::BasicEnv { name, value } => Environment::BasicEnv {
+                    name: name.clone(),
+                    value: interpolate(value),
+                },
+                other => other.clone(),
+            })
+        });
+
+        Step {
+            name,
+            image: interpolate(&self.image),
+            commands: self.commands.as_ref().map(interpolate_all),
+            args: self.args.as_ref().map(interpolate_all),
+            env,
+            matrix: None,
+            ..self.clone()
+        }
+    }
+}
+
+fn matrix_combinations(matrix: &BTreeMap<String, Vec1<String>>) -> Vec<Vec<(&str, &str)>> {
+    matrix
+        .iter()
+        .fold(vec![Vec::new()], |combinations, (name, values)| {
+            combinations
+                .iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((name.as_str(), value.as_str()));
+                        combination
+                    })
+                })
+                .collect()
+        })
 }
 
 pub struct StepWithCheckRunId<'a> {
     pub step: &'a Step,
     pub check_run_id: u32,
+    pub attempt: u32,
 }
 
 impl<'a> KubernetesContainer for StepWithCheckRunId<'a> {
@@ -94,42 +302,47 @@ impl<'a> KubernetesContainer for StepWithCheckRunId<'a> {
             None => repo_mount,
         };
 
+        let volume_mounts = [volume_mounts, cache_volume_mounts(self)].concat();
+
         let check_run_id_env = EnvVar {
             name: "CHECK_RUN_ID".to_string(),
             value: Some(self.check_run_id.to_string()),
             value_from: None,
         };
 
-        let maybe_envs = self.step.env.clone().map(|envs| {
+        let maybe_envs = self.step.env.as_ref().map(|envs| {
             envs.iter()
-                .map(|env| match env {
-                    Environment::BasicEnv { name, value } => EnvVar {
-                        name: name.clone(),
-                        value: Some(value.clone()),
-                        value_from: None,
-                    },
-                    Environment::KubernetesSecretEnv { name, value_from } => EnvVar {
-                        name: name.clone(),
-                        value: None,
-                        value_from: Some(EnvVarSource {
-                            config_map_key_ref: None,
-                            field_ref: None,
-                            resource_field_ref: None,
-                            secret_key_ref: Some(SecretKeySelector {
-                                name: Some(value_from.secret_key_ref.name.clone()),
-                                key: value_from.secret_key_ref.key.clone(),
-                                optional: None,
-                            }),
-                        }),
-                    },
-                })
+                .map(Environment::to_env_var)
                 .collect::<Vec<EnvVar>>()
         });
 
+        let step_attempt_env = EnvVar {
+            name: "STEP_ATTEMPT".to_string(),
+            value: Some(self.attempt.to_string()),
+            value_from: None,
+        };
+
+        let step_name_env = EnvVar {
+            name: "KUBESCI_STEP_NAME".to_string(),
+            value: Some(self.step.name.clone()),
+            value_from: None,
+        };
+
+        let soft_fail_env = self.step.soft_fail.as_ref().map(|soft_fail| EnvVar {
+            name: "STEP_SOFT_FAIL".to_string(),
+            value: Some(soft_fail.to_string()),
+            value_from: None,
+        });
+
+        let step_envs: Vec<EnvVar> = vec![check_run_id_env, step_attempt_env, step_name_env]
+            .into_iter()
+            .chain(soft_fail_env)
+            .collect();
+
         let envs: Vec<EnvVar> = if let Some(envs) = maybe_envs {
-            [envs, vec![check_run_id_env]].concat()
+            [envs, step_envs].concat()
         } else {
-            vec![check_run_id_env]
+            step_envs
         };
 
         let command = self.step.commands.as_ref().map(|commands| {
@@ -137,6 +350,14 @@ impl<'a> KubernetesContainer for StepWithCheckRunId<'a> {
 
             let mut script = start_script_file;
 
+            // Part of the script, so waiting for services counts towards the step's timeout
+            if self.step.services.is_some() {
+                script += &format!(
+                    "echo 'Waiting for the services to be ready'\\nuntil [ -f ./{} ]; do sleep 1; done\\n",
+                    SERVICES_READY_MARKER
+                );
+            }
+
             for command in commands {
                 script += &format!("echo '{}'\\n", command);
                 script += &format!("{}\\n", command);
@@ -144,31 +365,49 @@ impl<'a> KubernetesContainer for StepWithCheckRunId<'a> {
 
             let escaped_script = script.replace("'", "'\\\''");
 
-            vec![
-                "/bin/sh".to_string(),
-                "-c".to_string(),
-                format!(
-                    "echo -e '{}' > ./script.sh && chmod +x ./script.sh && ./script.sh",
-                    escaped_script
-                ),
-            ]
-        });
+            let run_script = match self.step.timeout_in_minutes {
+                Some(timeout_in_minutes) => run_script_with_timeout(timeout_in_minutes),
+                None => "./script.sh".to_string(),
+            };
 
-        let regex = Regex::new(r"[^a-z0-9/-]").unwrap();
+            let run_command = format!(
+                "echo -e '{}' > ./script.sh && chmod +x ./script.sh && {}",
+                escaped_script, run_script
+            );
 
-        let step_name_with_spaces = self.step.name.replace(" ", "-").to_lowercase();
+            let run_command = match self.step.cache {
+                Some(_) => format!("{}; {}", print_cache_status_command(), run_command),
+                None => run_command,
+            };
 
-        let container_name = format!(
-            "step-{}-{}",
-            regex.replace_all(&step_name_with_spaces, ""),
-            self.check_run_id
-        );
+            // Tells the artifacts upload, cache save and services stop containers the step is done,
+            // with its exit code, or that it was stopped if its shell is gone without it
+            let run_command = if self.step.artifact_paths.is_some()
+                || self.step.cache.is_some()
+                || self.step.services.is_some()
+            {
+                format!(
+                    "echo $$ > ./{}; ({}); exit_code=$?; echo $exit_code > ./{}; exit $exit_code",
+                    STEP_PID_FILE, run_command, STEP_FINISHED_MARKER
+                )
+            } else {
+                run_command
+            };
+
+            vec!["/bin/sh".to_string(), "-c".to_string(), run_command]
+        });
+
+        let container_name = container_name("step", &self.step.name, self.check_run_id);
 
         Container {
             args: self.step.args.clone(),
             command,
             env: Some(envs),
-            env_from: None,
+            env_from: self
+                .step
+                .env_from
+                .as_ref()
+                .map(|envs_from| envs_from.iter().map(EnvFrom::to_env_from_source).collect()),
             image: Some(self.step.image.to_string()),
             image_pull_policy: None,
             lifecycle: None,
@@ -176,7 +415,11 @@ impl<'a> KubernetesContainer for StepWithCheckRunId<'a> {
             name: container_name,
             ports: None,
             readiness_probe: None,
-            resources: None,
+            resources: self
+                .step
+                .resources
+                .as_ref()
+                .map(Resources::to_resource_requirements),
             security_context: None,
             startup_probe: None,
             stdin: None,
@@ -191,25 +434,353 @@ impl<'a> KubernetesContainer for StepWithCheckRunId<'a> {
     }
 }
 
+// A watchdog kills the script once the timeout is up, leaving a marker so the container can exit
+// with the timed out exit code and message rather than whatever the killed script returned. The
+// script runs in its own session where setsid is available, so everything it started is killed
+// with it rather than keeping the container running.
+fn run_script_with_timeout(timeout_in_minutes: u32) -> String {
+    format!(
+        "(if command -v setsid >/dev/null 2>&1; then setsid ./script.sh & else ./script.sh & fi; \
+         step_pid=$!; \
+         (sleep {seconds} && touch {marker} && \
+         (kill -9 -$step_pid 2>/dev/null || kill -9 $step_pid)) & watchdog_pid=$!; \
+         wait $step_pid; exit_code=$?; \
+         kill $watchdog_pid 2>/dev/null; \
+         if [ -f {marker} ]; then echo 'Step timed out after {minutes} minutes'; \
+         printf {message} > /dev/termination-log 2>/dev/null; exit {timed_out}; fi; \
+         exit $exit_code)",
+        seconds = u64::from(timeout_in_minutes) * 60,
+        marker = STEP_TIMED_OUT_MARKER,
+        minutes = timeout_in_minutes,
+        message = STEP_TIMED_OUT_MESSAGE,
+        timed_out = STEP_TIMED_OUT_EXIT_CODE,
+    )
+}
+
+// Picked by shape rather than untagged, so a mistake in a step is reported instead of it not
+// matching any step type
 #[derive(Debug, Deserialize)]
-#[serde(untagged)]
+#[serde(try_from = "serde_yaml::Value")]
+#[allow(clippy::large_enum_variant)]
 pub enum StepType {
     Block(Block),
     Step(Step),
-    #[serde(rename = "wait")]
-    Wait(String),
+    Wait(Wait),
+}
+
+impl TryFrom<serde_yaml::Value> for StepType {
+    type Error = String;
+
+    fn try_from(value: serde_yaml::Value) -> Result<Self, Self::Error> {
+        if value.get("block").is_some() {
+            serde_yaml::from_value(value)
+                .map(StepType::Block)
+                .map_err(|error| format!("Invalid block: {}", error))
+        } else if value.is_string() || value.get("wait").is_some() {
+            serde_yaml::from_value(value)
+                .map(StepType::Wait)
+                .map_err(|error| format!("Invalid wait: {}", error))
+        } else {
+            let name = value
+                .get("name")
+                .and_then(serde_yaml::Value::as_str)
+                .unwrap_or("without a name")
+                .to_string();
+
+            serde_yaml::from_value(value)
+                .map(StepType::Step)
+                .map_err(|error| format!("Invalid step {}: {}", name, error))
+        }
+    }
+}
+
+impl StepType {
+    pub fn key(&self) -> Option<&String> {
+        match self {
+            StepType::Block(block) => block.key.as_ref(),
+            StepType::Step(step) => step.key.as_ref(),
+            StepType::Wait(_) => None,
+        }
+    }
+
+    pub fn depends_on(&self) -> Option<&Vec1<String>> {
+        match self {
+            StepType::Block(block) => block.depends_on.as_ref(),
+            StepType::Step(step) => step.depends_on.as_ref(),
+            StepType::Wait(_) => None,
+        }
+    }
+
+    pub fn report_skipped(&self) -> bool {
+        match self {
+            StepType::Block(block) => block.report_skipped.unwrap_or(false),
+            StepType::Step(step) => step.report_skipped.unwrap_or(false),
+            StepType::Wait(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Wait {
+    #[serde(deserialize_with = "deserialize_basic_wait")]
+    Basic,
+    WithOptions {
+        wait: WaitOptions,
+    },
+}
+
+fn deserialize_basic_wait<'de, D>(deserializer: D) -> Result<(), D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: String = serde::Deserialize::deserialize(deserializer)?;
+
+    if value == "wait" {
+        Ok(())
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "Expected \"wait\", found \"{}\"",
+            value
+        )))
+    }
+}
+
+impl Wait {
+    pub fn continue_on_failure(&self) -> bool {
+        match self {
+            Wait::Basic => false,
+            Wait::WithOptions { wait } => wait.continue_on_failure,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
+pub struct WaitOptions {
+    #[serde(default)]
+    pub continue_on_failure: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
 pub struct Block {
     #[serde(rename = "block")]
     pub name: String,
     pub branch: Option<String>,
+    pub event: Option<String>,
+    pub tag: Option<String>,
+    #[serde(rename = "if")]
+    pub condition: Option<Expression>,
+    pub report_skipped: Option<bool>,
+    pub key: Option<String>,
+    pub depends_on: Option<Vec1<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RawPipeline {
     pub steps: Vec1<StepType>,
+    // A deadline for the whole pipeline, restarted whenever a block is unblocked
+    pub timeout_in_minutes: Option<u32>,
+    // Defaults for every step, which steps can override value by value
+    pub resources: Option<Resources>,
+    #[serde(flatten)]
+    pub placement: Placement,
+    pub env: Option<Vec1<Environment>>,
+    #[serde(flatten)]
+    pub paths_filter: PathsFilter,
+    // Whether steps that don't set it are reported as skipped when filtered out
+    pub report_skipped: Option<bool>,
+}
+
+impl RawPipeline {
+    pub fn expand_matrices(self) -> RawPipeline {
+        let steps: Vec<StepType> = self
+            .steps
+            .into_iter()
+            .flat_map(|step| match step {
+                StepType::Step(step) => step
+                    .expand_matrix()
+                    .into_iter()
+                    .map(StepType::Step)
+                    .collect(),
+                other => vec![other],
+            })
+            .collect();
+
+        // Matrix values can't be empty, so every step expands into at least one
+        RawPipeline {
+            steps: Vec1::try_from_vec(steps).unwrap(),
+            timeout_in_minutes: self.timeout_in_minutes,
+            resources: self.resources,
+            placement: self.placement,
+            paths_filter: self.paths_filter,
+            report_skipped: self.report_skipped,
+            env: self.env,
+        }
+    }
+
+    // Pipeline envs come first, leaving out any the step sets itself
+    pub fn apply_env(mut self) -> RawPipeline {
+        if let Some(pipeline_envs) = &self.env {
+            for step in self.steps.iter_mut() {
+                if let StepType::Step(step) = step {
+                    let step_envs: Vec<Environment> = step
+                        .env
+                        .as_ref()
+                        .map(|envs| envs.to_vec())
+                        .unwrap_or_default();
+
+                    let envs: Vec<Environment> = pipeline_envs
+                        .iter()
+                        .filter(|pipeline_env| {
+                            !step_envs
+                                .iter()
+                                .any(|step_env| step_env.name() == pipeline_env.name())
+                        })
+                        .cloned()
+                        .chain(step_envs.iter().cloned())
+                        .collect();
+
+                    step.env = Vec1::try_from_vec(envs).ok();
+                }
+            }
+        }
+
+        self
+    }
+
+    // Checks what serde can't, e.g. artifacts and caches are saved once the step's commands have
+    // run, so there have to be some
+    pub fn validate_steps(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for step in self.steps.iter() {
+            let (name, filters) = match step {
+                StepType::Block(block) => (&block.name, [&block.branch, &block.event, &block.tag]),
+                StepType::Step(step) => (&step.name, [&step.branch, &step.event, &step.tag]),
+                StepType::Wait(_) => continue,
+            };
+
+            for filter in filters.iter().copied().flatten() {
+                validate_filter(filter)
+                    .map_err(|error| format!("Invalid filter for the step {}: {}", name, error))?;
+            }
+
+            if let StepType::Step(step) = step {
+                if step.artifact_paths.is_some() && step.commands.is_none() {
+                    return Err(format!(
+                        "The step {} has artifact_paths but no commands to upload them after",
+                        step.name
+                    )
+                    .into());
+                }
+
+                let service_names: Vec<&str> = step
+                    .services
+                    .iter()
+                    .flatten()
+                    .map(|service| service.name.as_str())
+                    .collect();
+
+                if let Some(duplicate_name) = service_names
+                    .iter()
+                    .enumerate()
+                    .find(|(index, name)| service_names[..*index].contains(name))
+                    .map(|(_, name)| name)
+                {
+                    return Err(format!(
+                        "The step {} has more than one service called {}",
+                        step.name, duplicate_name
+                    )
+                    .into());
+                }
+
+                if step.services.is_some() && step.commands.is_none() {
+                    return Err(format!(
+                        "The step {} has services but no commands to stop them after",
+                        step.name
+                    )
+                    .into());
+                }
+
+                for service in step.services.iter().flatten() {
+                    service.validate_readiness_probe()?;
+                }
+
+                if step.timeout_in_minutes.is_some() && step.commands.is_none() {
+                    return Err(format!(
+                        "The step {} has timeout_in_minutes but no commands to time out",
+                        step.name
+                    )
+                    .into());
+                }
+
+                if step.cache.is_some() && step.commands.is_none() {
+                    return Err(format!(
+                        "The step {} has a cache but no commands to save it after",
+                        step.name
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_paths_filter(mut self) -> RawPipeline {
+        for step in self.steps.iter_mut() {
+            if let StepType::Step(step) = step {
+                step.paths_filter = step.paths_filter.or(&self.paths_filter);
+            }
+        }
+
+        self
+    }
+
+    pub fn apply_report_skipped(mut self) -> RawPipeline {
+        for step in self.steps.iter_mut() {
+            match step {
+                StepType::Block(block) => {
+                    block.report_skipped = block.report_skipped.or(self.report_skipped)
+                }
+                StepType::Step(step) => {
+                    step.report_skipped = step.report_skipped.or(self.report_skipped)
+                }
+                StepType::Wait(_) => {}
+            }
+        }
+
+        self
+    }
+
+    pub fn apply_placement(mut self) -> RawPipeline {
+        for step in self.steps.iter_mut() {
+            if let StepType::Step(step) = step {
+                step.placement = step.placement.or(&self.placement);
+            }
+        }
+
+        self
+    }
+
+    pub fn resolve_resources(
+        mut self,
+        max_resources: &ResourceValues,
+    ) -> Result<RawPipeline, Box<dyn std::error::Error>> {
+        let defaults = self.resources.clone().unwrap_or_default();
+
+        for step in self.steps.iter_mut() {
+            if let StepType::Step(step) = step {
+                let resources = step.resources.clone().unwrap_or_default().or(&defaults);
+
+                resources.validate(max_resources).map_err(|error| {
+                    format!("Invalid resources for the step {}: {}", step.name, error)
+                })?;
+
+                step.resources = Some(resources);
+            }
+        }
+
+        Ok(self)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -230,11 +801,13 @@ mod tests {
             branch: None,
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let step_with_check_run_id = StepWithCheckRunId {
             step: &step,
             check_run_id: 1,
+            attempt: 1,
         };
 
         let container = step_with_check_run_id.to_container();
@@ -242,6 +815,118 @@ mod tests {
         assert_eq!(container.command, Some(vec!("/bin/sh".to_string(), "-c".to_string(), "echo -e '#!/bin/sh\\nset -euf\\necho '\\\''cargo test'\\''\\ncargo test\\necho '\\''cargo run'\\''\\ncargo run\\n' > ./script.sh && chmod +x ./script.sh && ./script.sh".to_string())))
     }
 
+    #[test]
+    fn should_kill_script_once_step_timeout_is_up() {
+        let step = Step {
+            name: "test-string".to_string(),
+            image: "some-image".to_string(),
+            commands: Some(vec!["cargo test".to_string()]),
+            timeout_in_minutes: Some(5),
+            ..Default::default()
+        };
+
+        let step_with_check_run_id = StepWithCheckRunId {
+            step: &step,
+            check_run_id: 1,
+            attempt: 1,
+        };
+
+        let command = step_with_check_run_id.to_container().command.unwrap();
+
+        assert!(command[2].contains("setsid ./script.sh &"));
+        assert!(command[2].contains(
+            "(sleep 300 && touch /tmp/.kubesci-step-timed-out && \
+             (kill -9 -$step_pid 2>/dev/null || kill -9 $step_pid)) &"
+        ));
+        assert!(command[2].contains(
+            "printf kubesci-step-timed-out > /dev/termination-log 2>/dev/null; exit 124;"
+        ));
+    }
+
+    #[test]
+    fn should_only_retry_listed_exit_statuses_up_to_limit() {
+        let retry = Retry {
+            limit: 2,
+            exit_status: Some(vec![137, 143]),
+        };
+
+        assert!(retry.should_retry(1, Some(137)));
+        assert!(retry.should_retry(2, Some(143)));
+        assert!(!retry.should_retry(3, Some(137)));
+        assert!(!retry.should_retry(1, Some(1)));
+        assert!(!retry.should_retry(1, None));
+    }
+
+    #[test]
+    fn should_retry_any_failure_without_exit_statuses() {
+        let retry = Retry {
+            limit: 1,
+            exit_status: None,
+        };
+
+        assert!(retry.should_retry(1, Some(1)));
+        assert!(retry.should_retry(1, None));
+        assert!(!retry.should_retry(2, Some(1)));
+    }
+
+    #[test]
+    fn should_soft_fail_on_any_or_only_listed_exit_statuses() {
+        assert!(SoftFail::Enabled(true).allows(Some(1)));
+        assert!(SoftFail::Enabled(true).allows(None));
+        assert!(!SoftFail::Enabled(false).allows(Some(1)));
+        assert!(SoftFail::ExitStatuses(vec![1, 2]).allows(Some(2)));
+        assert!(!SoftFail::ExitStatuses(vec![1, 2]).allows(Some(3)));
+        assert!(!SoftFail::ExitStatuses(vec![1, 2]).allows(None));
+    }
+
+    #[test]
+    fn should_pass_soft_fail_to_container() {
+        let raw_pipeline = r#"
+steps:
+  - name: lint
+    image: some_image
+    soft_fail: true
+
+  - name: experimental lint
+    image: some_image
+    soft_fail: [1, 2]
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let soft_fails: Vec<Option<SoftFail>> = raw_pipeline
+            .steps
+            .iter()
+            .map(|step| match step {
+                StepType::Step(step) => {
+                    let step_with_check_run_id = StepWithCheckRunId {
+                        step,
+                        check_run_id: 1,
+                        attempt: 1,
+                    };
+
+                    step_with_check_run_id
+                        .to_container()
+                        .env
+                        .unwrap()
+                        .into_iter()
+                        .find(|env| env.name == "STEP_SOFT_FAIL")
+                        .and_then(|env| env.value)
+                        .map(|soft_fail| soft_fail.parse().unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            soft_fails,
+            vec![
+                Some(SoftFail::Enabled(true)),
+                Some(SoftFail::ExitStatuses(vec![1, 2]))
+            ]
+        );
+    }
+
     #[test]
     fn ensure_container_name_is_kubernetes_safe() {
         let step = Step {
@@ -252,11 +937,13 @@ mod tests {
             branch: None,
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let step_with_check_run_id = StepWithCheckRunId {
             step: &step,
             check_run_id: 1,
+            attempt: 1,
         };
 
         let container = step_with_check_run_id.to_container();
@@ -264,6 +951,48 @@ mod tests {
         assert_eq!(container.name, "step-test-container--abn-1");
     }
 
+    #[test]
+    fn ensure_matrix_step_container_names_fit_kubernetes() {
+        let step = Step {
+            name: "Test / Build".to_string(),
+            image: "some-image".to_string(),
+            commands: Some(vec!["cargo test".to_string()]),
+            matrix: Some(
+                serde_yaml::from_str(
+                    "{rust: [stable, nightly], target: [x86_64-unknown-linux-gnu, wasm32-unknown-unknown]}",
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+
+        let expanded_steps = step.expand_matrix();
+
+        let container_names: Vec<String> = expanded_steps
+            .iter()
+            .map(|step| {
+                StepWithCheckRunId {
+                    step,
+                    check_run_id: 1234567890,
+                    attempt: 1,
+                }
+                .to_container()
+                .name
+            })
+            .collect();
+
+        let name_regex = Regex::new(r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?$").unwrap();
+
+        assert_eq!(container_names.len(), 4);
+        assert!(container_names
+            .iter()
+            .all(|name| name.len() <= 63 && name_regex.is_match(name)));
+        assert_eq!(
+            container_names[0],
+            "step-test--build-rust-stable-target-x8664-unknown-li-1234567890"
+        );
+    }
+
     #[test]
     fn ensure_raw_pipeline_can_correctly_be_decoded() {
         let raw_pipeline = r#"
@@ -292,4 +1021,421 @@ steps:
 
         assert!(raw_pipeline.is_ok());
     }
+
+    #[test]
+    fn ensure_wait_with_continue_on_failure_can_be_decoded() {
+        let raw_pipeline = r#"
+steps:
+  - name: test step
+    image: some_image
+
+  - wait:
+      continue_on_failure: true
+
+  - name: another test step
+    image: some_image
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        match raw_pipeline.steps.get(1) {
+            Some(StepType::Wait(wait)) => assert!(wait.continue_on_failure()),
+            other => panic!("Expected a wait step, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_expand_matrix_into_a_step_per_combination() {
+        let raw_pipeline = r#"
+steps:
+  - name: test
+    image: rust:{{ matrix.rust }}
+    commands:
+      - cargo test --target {{matrix.target}}
+    env:
+      - name: TARGET
+        value: "{{ matrix.target }}"
+    matrix:
+      rust: [stable, beta]
+      target: [x86_64, aarch64]
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let steps: Vec<Step> = raw_pipeline
+            .expand_matrices()
+            .steps
+            .into_iter()
+            .filter_map(|step| match step {
+                StepType::Step(step) => Some(step),
+                _ => None,
+            })
+            .collect();
+
+        let names: Vec<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "test (rust: stable, target: x86_64)",
+                "test (rust: stable, target: aarch64)",
+                "test (rust: beta, target: x86_64)",
+                "test (rust: beta, target: aarch64)",
+            ]
+        );
+
+        assert_eq!(steps[3].image, "rust:beta");
+        assert_eq!(
+            steps[3].commands,
+            Some(vec!["cargo test --target aarch64".to_string()])
+        );
+
+        match steps[3].env.as_ref().map(|envs| envs.first()) {
+            Some(Environment::BasicEnv { value, .. }) => assert_eq!(value, "aarch64"),
+            other => panic!("Expected a basic env, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_not_change_steps_without_a_matrix() {
+        let step = Step {
+            name: "test".to_string(),
+            image: "rust:{{ matrix.rust }}".to_string(),
+            ..Default::default()
+        };
+
+        let steps = step.expand_matrix();
+
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].name, "test");
+        assert_eq!(steps[0].image, "rust:{{ matrix.rust }}");
+    }
+
+    #[test]
+    fn ensure_step_dependencies_can_be_decoded() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+    key: build
+
+  - block: release?
+    key: release
+    depends_on:
+      - build
+
+  - name: deploy
+    image: some_image
+    depends_on:
+      - build
+      - release
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let keys: Vec<Option<&String>> = raw_pipeline.steps.iter().map(StepType::key).collect();
+
+        assert_eq!(
+            keys,
+            vec![
+                Some(&"build".to_string()),
+                Some(&"release".to_string()),
+                None
+            ]
+        );
+        assert_eq!(
+            raw_pipeline
+                .steps
+                .last()
+                .depends_on()
+                .map(|keys| keys.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn should_apply_pipeline_resources_to_every_step() {
+        let raw_pipeline = r#"
+resources:
+  requests:
+    cpu: 500m
+    memory: 1Gi
+steps:
+  - name: build
+    image: some_image
+    resources:
+      requests:
+        memory: 2Gi
+      limits:
+        memory: 4Gi
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let raw_pipeline = raw_pipeline
+            .resolve_resources(&ResourceValues::default())
+            .unwrap();
+
+        match raw_pipeline.steps.first() {
+            StepType::Step(step) => {
+                let requirements = step
+                    .resources
+                    .as_ref()
+                    .map(Resources::to_resource_requirements)
+                    .unwrap();
+                let requests = requirements.requests.unwrap();
+
+                assert_eq!(requests["cpu"].0, "500m");
+                assert_eq!(requests["memory"].0, "2Gi");
+                assert_eq!(requirements.limits.unwrap()["memory"].0, "4Gi");
+            }
+            other => panic!("Expected a step, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_only_use_the_max_resources_as_a_cap() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+  - name: test
+    image: some_image
+    resources:
+      limits:
+        memory: 1Gi
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let max_resources = ResourceValues {
+            cpu: Some("2".to_string()),
+            memory: Some("4Gi".to_string()),
+            ephemeral_storage: None,
+        };
+
+        let raw_pipeline = raw_pipeline.resolve_resources(&max_resources).unwrap();
+
+        let requirements: Vec<k8s_openapi::api::core::v1::ResourceRequirements> = raw_pipeline
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                StepType::Step(step) => step.resources.as_ref(),
+                _ => None,
+            })
+            .map(Resources::to_resource_requirements)
+            .collect();
+
+        assert_eq!(requirements[0].requests, None);
+        assert_eq!(requirements[0].limits, None);
+        assert_eq!(requirements[1].requests, None);
+        assert_eq!(requirements[1].limits.as_ref().unwrap()["memory"].0, "1Gi");
+        assert!(!requirements[1].limits.as_ref().unwrap().contains_key("cpu"));
+    }
+
+    #[test]
+    fn should_reject_steps_over_the_max_resources() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+    resources:
+      limits:
+        memory: 8Gi
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let max_resources = ResourceValues {
+            memory: Some("4Gi".to_string()),
+            ..Default::default()
+        };
+
+        assert!(raw_pipeline.resolve_resources(&max_resources).is_err());
+    }
+
+    #[test]
+    fn should_need_commands_to_save_a_cache_after() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+    args: ["build"]
+    cache:
+      key: cargo
+      paths:
+        - target
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        assert_eq!(
+            raw_pipeline.validate_steps().unwrap_err().to_string(),
+            "The step build has a cache but no commands to save it after"
+        );
+    }
+
+    #[test]
+    fn should_need_commands_to_time_out() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+    args: ["build"]
+    timeout_in_minutes: 10
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        assert_eq!(
+            raw_pipeline.validate_steps().unwrap_err().to_string(),
+            "The step build has timeout_in_minutes but no commands to time out"
+        );
+    }
+
+    #[test]
+    fn should_need_commands_to_stop_services_after() {
+        let raw_pipeline = r#"
+steps:
+  - name: integration
+    image: some_image
+    args: ["integration"]
+    services:
+      - name: db
+        image: postgres:12
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        assert_eq!(
+            raw_pipeline.validate_steps().unwrap_err().to_string(),
+            "The step integration has services but no commands to stop them after"
+        );
+    }
+
+    #[test]
+    fn should_fail_steps_with_services_of_the_same_name() {
+        let raw_pipeline = r#"
+steps:
+  - name: integration
+    image: some_image
+    commands: ["make integration"]
+    services:
+      - name: db
+        image: postgres:12
+      - name: db
+        image: mysql:8
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        assert_eq!(
+            raw_pipeline.validate_steps().unwrap_err().to_string(),
+            "The step integration has more than one service called db"
+        );
+    }
+
+    #[test]
+    fn should_fail_pipelines_with_invalid_resources() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+    resources:
+      limits:
+        cpu: two
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let error = raw_pipeline
+            .resolve_resources(&ResourceValues::default())
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Invalid resources for the step build: two is not a valid cpu quantity"
+        );
+    }
+
+    #[test]
+    fn should_merge_pipeline_env_into_every_step() {
+        let raw_pipeline = r#"
+env:
+  - name: RUST_LOG
+    value: info
+  - name: CARGO_TERM_COLOR
+    value: always
+steps:
+  - name: build
+    image: some_image
+    env:
+      - name: RUST_LOG
+        value: debug
+  - name: test
+    image: some_image
+"#;
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline).unwrap();
+
+        let envs: Vec<Vec<(String, String)>> = raw_pipeline
+            .apply_env()
+            .steps
+            .iter()
+            .map(|step| match step {
+                StepType::Step(step) => step
+                    .env
+                    .iter()
+                    .flatten()
+                    .map(|env| match env {
+                        Environment::BasicEnv { name, value } => (name.clone(), value.clone()),
+                        other => panic!("Expected a basic env, got {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("Expected a step, got {:?}", other),
+            })
+            .collect();
+
+        let env = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+        assert_eq!(
+            envs,
+            vec![
+                vec![env("CARGO_TERM_COLOR", "always"), env("RUST_LOG", "debug")],
+                vec![env("RUST_LOG", "info"), env("CARGO_TERM_COLOR", "always")],
+            ]
+        );
+    }
+
+    #[test]
+    fn should_say_which_step_is_invalid() {
+        let raw_pipeline = r#"
+steps:
+  - name: build
+    image: some_image
+  - wait
+  - name: deploy
+    image: some_image
+    env:
+      - name: TOKEN
+        valueFrom:
+          secretKeyRef:
+            name: deploy-token
+"#;
+
+        let error = serde_yaml::from_str::<RawPipeline>(raw_pipeline).unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("steps: Invalid step deploy: missing field `key`"));
+    }
+
+    #[test]
+    fn should_only_read_wait_from_a_plain_string() {
+        let wait = serde_yaml::from_str::<StepType>("wait").unwrap();
+        assert!(matches!(wait, StepType::Wait(Wait::Basic)));
+
+        let error = serde_yaml::from_str::<StepType>("wiat").unwrap_err();
+        assert!(error.to_string().starts_with("Invalid wait"));
+    }
 }
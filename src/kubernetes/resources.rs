@@ -0,0 +1,243 @@
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use regex::Regex;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct ResourceValues {
+    pub cpu: Option<String>,
+    pub memory: Option<String>,
+    #[serde(rename = "ephemeral-storage")]
+    pub ephemeral_storage: Option<String>,
+}
+
+impl ResourceValues {
+    fn values(&self) -> Vec<(&'static str, &String)> {
+        vec![
+            ("cpu", self.cpu.as_ref()),
+            ("memory", self.memory.as_ref()),
+            ("ephemeral-storage", self.ephemeral_storage.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, maybe_value)| maybe_value.map(|value| (name, value)))
+        .collect()
+    }
+
+    fn get(&self, name: &str) -> Option<&String> {
+        self.values()
+            .into_iter()
+            .find(|(value_name, _)| *value_name == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn or(&self, defaults: &ResourceValues) -> ResourceValues {
+        ResourceValues {
+            cpu: self.cpu.clone().or_else(|| defaults.cpu.clone()),
+            memory: self.memory.clone().or_else(|| defaults.memory.clone()),
+            ephemeral_storage: self
+                .ephemeral_storage
+                .clone()
+                .or_else(|| defaults.ephemeral_storage.clone()),
+        }
+    }
+
+    fn to_quantities(&self) -> Option<BTreeMap<String, Quantity>> {
+        let quantities: BTreeMap<String, Quantity> = self
+            .values()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Quantity(value.clone())))
+            .collect();
+
+        if quantities.is_empty() {
+            None
+        } else {
+            Some(quantities)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Resources {
+    pub requests: Option<ResourceValues>,
+    pub limits: Option<ResourceValues>,
+}
+
+impl Resources {
+    // Anything the step sets takes precedence over the pipeline defaults
+    pub fn or(&self, defaults: &Resources) -> Resources {
+        Resources {
+            requests: merge_values(self.requests.as_ref(), defaults.requests.as_ref()),
+            limits: merge_values(self.limits.as_ref(), defaults.limits.as_ref()),
+        }
+    }
+
+    pub fn validate(&self, max_resources: &ResourceValues) -> Result<(), String> {
+        let requests = self.requests.clone().unwrap_or_default();
+        let limits = self.limits.clone().unwrap_or_default();
+
+        for (kind, values) in &[("requests", &requests), ("limits", &limits)] {
+            for (name, value) in values.values() {
+                let quantity = parse_quantity(value)
+                    .map_err(|_| format!("{} is not a valid {} quantity", value, name))?;
+
+                if let Some(max_value) = max_resources.get(name) {
+                    if quantity > parse_quantity(max_value)? {
+                        return Err(format!(
+                            "{} {} {} is more than the {} allowed",
+                            kind, value, name, max_value
+                        ));
+                    }
+                }
+            }
+        }
+
+        for (name, request) in requests.values() {
+            if let Some(limit) = limits.get(name) {
+                if parse_quantity(request)? > parse_quantity(limit)? {
+                    return Err(format!(
+                        "requests {} {} is more than its limit of {}",
+                        request, name, limit
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_resource_requirements(&self) -> ResourceRequirements {
+        ResourceRequirements {
+            limits: self.limits.as_ref().and_then(ResourceValues::to_quantities),
+            requests: self
+                .requests
+                .as_ref()
+                .and_then(ResourceValues::to_quantities),
+        }
+    }
+}
+
+fn merge_values(
+    values: Option<&ResourceValues>,
+    defaults: Option<&ResourceValues>,
+) -> Option<ResourceValues> {
+    match (values, defaults) {
+        (Some(values), Some(defaults)) => Some(values.or(defaults)),
+        (values, defaults) => values.or(defaults).cloned(),
+    }
+}
+
+// Kubernetes quantities, such as 500m, 2, 1.5Gi or 100M, in their base unit
+pub fn parse_quantity(quantity: &str) -> Result<f64, String> {
+    let regex = Regex::new(r"^([0-9]+(?:\.[0-9]+)?)(m|k|M|G|T|P|E|Ki|Mi|Gi|Ti|Pi|Ei)?$").unwrap();
+
+    let captures = regex
+        .captures(quantity)
+        .ok_or_else(|| format!("{} is not a valid quantity", quantity))?;
+
+    let number: f64 = captures[1]
+        .parse()
+        .map_err(|_| format!("{} is not a valid quantity", quantity))?;
+
+    let multiplier = match captures.get(2).map(|suffix| suffix.as_str()) {
+        None => 1.0,
+        Some("m") => 0.001,
+        Some("k") => 1e3,
+        Some("M") => 1e6,
+        Some("G") => 1e9,
+        Some("T") => 1e12,
+        Some("P") => 1e15,
+        Some("E") => 1e18,
+        Some("Ki") => 1024_f64,
+        Some("Mi") => 1024_f64.powi(2),
+        Some("Gi") => 1024_f64.powi(3),
+        Some("Ti") => 1024_f64.powi(4),
+        Some("Pi") => 1024_f64.powi(5),
+        Some(_) => 1024_f64.powi(6),
+    };
+
+    Ok(number * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resources(request_memory: &str, limit_memory: &str) -> Resources {
+        Resources {
+            requests: Some(ResourceValues {
+                memory: Some(request_memory.to_string()),
+                ..Default::default()
+            }),
+            limits: Some(ResourceValues {
+                memory: Some(limit_memory.to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn should_parse_kubernetes_quantities() {
+        assert_eq!(parse_quantity("500m"), Ok(0.5));
+        assert_eq!(parse_quantity("2"), Ok(2.0));
+        assert_eq!(parse_quantity("1.5Gi"), Ok(1.5 * 1024.0 * 1024.0 * 1024.0));
+        assert_eq!(parse_quantity("100M"), Ok(100_000_000.0));
+        assert!(parse_quantity("1.5GB").is_err());
+        assert!(parse_quantity("-1").is_err());
+    }
+
+    #[test]
+    fn should_use_pipeline_defaults_for_values_the_step_does_not_set() {
+        let step_resources = Resources {
+            requests: Some(ResourceValues {
+                cpu: Some("2".to_string()),
+                ..Default::default()
+            }),
+            limits: None,
+        };
+
+        let merged = step_resources.or(&resources("1Gi", "2Gi"));
+
+        assert_eq!(
+            merged.requests,
+            Some(ResourceValues {
+                cpu: Some("2".to_string()),
+                memory: Some("1Gi".to_string()),
+                ephemeral_storage: None,
+            })
+        );
+        assert_eq!(merged.limits, resources("1Gi", "2Gi").limits);
+    }
+
+    #[test]
+    fn should_reject_invalid_quantities() {
+        let error = resources("lots", "2Gi")
+            .validate(&ResourceValues::default())
+            .unwrap_err();
+
+        assert_eq!(error, "lots is not a valid memory quantity");
+    }
+
+    #[test]
+    fn should_reject_values_above_the_controller_maximum() {
+        let max_resources = ResourceValues {
+            memory: Some("4Gi".to_string()),
+            ..Default::default()
+        };
+
+        assert!(resources("1Gi", "2Gi").validate(&max_resources).is_ok());
+
+        let error = resources("1Gi", "8Gi")
+            .validate(&max_resources)
+            .unwrap_err();
+
+        assert_eq!(error, "limits 8Gi memory is more than the 4Gi allowed");
+    }
+
+    #[test]
+    fn should_reject_requests_above_limits() {
+        assert!(resources("4Gi", "2Gi")
+            .validate(&ResourceValues::default())
+            .is_err());
+    }
+}
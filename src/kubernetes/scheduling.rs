@@ -0,0 +1,104 @@
+use k8s_openapi::api::core::v1::{Affinity, Toleration};
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+// Where a step's pod can be scheduled. Steps only share a pod with steps placed the same way.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Placement {
+    #[serde(alias = "agents")]
+    pub node_selector: Option<BTreeMap<String, String>>,
+    pub tolerations: Option<Vec<Toleration>>,
+    pub affinity: Option<Affinity>,
+}
+
+impl Placement {
+    // Each setting the step has replaces the pipeline's, rather than being merged with it
+    pub fn or(&self, defaults: &Placement) -> Placement {
+        Placement {
+            node_selector: self
+                .node_selector
+                .clone()
+                .or_else(|| defaults.node_selector.clone()),
+            tolerations: self
+                .tolerations
+                .clone()
+                .or_else(|| defaults.tolerations.clone()),
+            affinity: self.affinity.clone().or_else(|| defaults.affinity.clone()),
+        }
+    }
+}
+
+// Groups items by their placement, keeping the order each placement is first seen in
+pub fn group_by_placement<T>(items: Vec<T>, placement: impl Fn(&T) -> &Placement) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = Vec::new();
+
+    for item in items {
+        let maybe_group = groups
+            .iter_mut()
+            .find(|group| placement(&group[0]) == placement(&item));
+
+        match maybe_group {
+            Some(group) => group.push(item),
+            None => groups.push(vec![item]),
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(node_selector: Option<(&str, &str)>) -> Placement {
+        Placement {
+            node_selector: node_selector.map(|(key, value)| {
+                let mut node_selector = BTreeMap::new();
+                node_selector.insert(key.to_string(), value.to_string());
+                node_selector
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_prefer_the_step_placement_over_the_pipeline_defaults() {
+        let defaults = Placement {
+            tolerations: Some(vec![Toleration {
+                key: Some("dedicated".to_string()),
+                operator: Some("Exists".to_string()),
+                ..Default::default()
+            }]),
+            ..placement(Some(("kubernetes.io/arch", "amd64")))
+        };
+
+        let resolved = placement(Some(("kubernetes.io/arch", "arm64"))).or(&defaults);
+
+        assert_eq!(
+            resolved.node_selector,
+            placement(Some(("kubernetes.io/arch", "arm64"))).node_selector
+        );
+        assert_eq!(resolved.tolerations, defaults.tolerations);
+    }
+
+    #[test]
+    fn should_group_items_with_the_same_placement() {
+        let items = vec![
+            ("build", placement(None)),
+            (
+                "build-arm",
+                placement(Some(("kubernetes.io/arch", "arm64"))),
+            ),
+            ("lint", placement(None)),
+        ];
+
+        let groups = group_by_placement(items, |(_, placement)| placement);
+
+        let names: Vec<Vec<&str>> = groups
+            .iter()
+            .map(|group| group.iter().map(|(name, _)| *name).collect())
+            .collect();
+
+        assert_eq!(names, vec![vec!["build", "lint"], vec!["build-arm"]]);
+    }
+}
@@ -0,0 +1,503 @@
+use crate::kubernetes::artifacts::wait_for_step_function;
+use crate::kubernetes::environment::Environment;
+use crate::kubernetes::{container_name, KubernetesContainer, StepWithCheckRunId};
+use k8s_openapi::api::core::v1::{
+    Capabilities, Container, EnvVar, Probe, SecurityContext, VolumeMount,
+};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use serde_derive::Deserialize;
+use vec1::Vec1;
+
+pub const SERVICES_STOP_CONTAINER_NAME: &str = "kubesci-services-stop";
+// Set on every service container, so they can be told apart from steps and stopped with them
+const SERVICE_OF_ENV: &str = "KUBESCI_SERVICE_OF";
+// Set on every service container with its name, so its readiness probe can run in its files
+const SERVICE_NAME_ENV: &str = "KUBESCI_SERVICE_NAME";
+// Written into a step's volume once its services are ready, as the step waits for it before running
+pub const SERVICES_READY_MARKER: &str = ".kubesci-services-ready";
+
+const SERVICES_STOP_IMAGE: &str = "busybox:1.32";
+
+// Long running containers, such as databases, started alongside a step. They share the pod's
+// network, so are reached on localhost.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Service {
+    pub name: String,
+    pub image: String,
+    pub command: Option<Vec<String>>,
+    pub args: Option<Vec<String>>,
+    pub env: Option<Vec1<Environment>>,
+    #[serde(rename = "readinessProbe")]
+    pub readiness_probe: Option<Probe>,
+}
+
+impl Service {
+    // Kubernetes readiness doesn't hold back the other containers of a pod, so the services stop
+    // container runs the probe itself. It shares the service's network and processes, so exec
+    // probes run in the service's files.
+    fn readiness_check(&self) -> Option<String> {
+        let probe = self.readiness_probe.as_ref()?;
+
+        if let Some(exec) = &probe.exec {
+            let command: Vec<String> = exec
+                .command
+                .iter()
+                .flatten()
+                .map(|arg| format!("'{}'", arg.replace("'", "'\\''")))
+                .collect();
+
+            Some(format!(
+                "chroot \"/proc/$(processes_with \"{}={}\" | head -n 1)/root\" {} >/dev/null 2>&1",
+                SERVICE_NAME_ENV,
+                self.name,
+                command.join(" ")
+            ))
+        } else if let Some(http_get) = &probe.http_get {
+            Some(format!(
+                "wget -q -O /dev/null '{}://127.0.0.1:{}{}'",
+                http_get.scheme.as_deref().unwrap_or("http").to_lowercase(),
+                probe_port(&http_get.port),
+                http_get.path.as_deref().unwrap_or("/")
+            ))
+        } else {
+            probe
+                .tcp_socket
+                .as_ref()
+                .map(|tcp_socket| format!("nc -z 127.0.0.1 {}", probe_port(&tcp_socket.port)))
+        }
+    }
+
+    // Services don't name their ports, so probes have to use numbers
+    pub fn validate_readiness_probe(&self) -> Result<(), String> {
+        let port = self.readiness_probe.as_ref().and_then(|probe| {
+            probe
+                .http_get
+                .as_ref()
+                .map(|http_get| &http_get.port)
+                .or_else(|| probe.tcp_socket.as_ref().map(|tcp_socket| &tcp_socket.port))
+        });
+
+        match port {
+            Some(IntOrString::String(name)) => Err(format!(
+                "The readiness probe of the service {} needs a port number rather than {}",
+                self.name, name
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn probe_port(port: &IntOrString) -> String {
+    match port {
+        IntOrString::Int(port) => port.to_string(),
+        IntOrString::String(port) => port.to_string(),
+    }
+}
+
+pub struct ServiceContainer<'a> {
+    pub service: &'a Service,
+    pub check_run_id: u32,
+}
+
+impl<'a> KubernetesContainer for ServiceContainer<'a> {
+    fn to_container(&self) -> Container {
+        let service_of_env = EnvVar {
+            name: SERVICE_OF_ENV.to_string(),
+            value: Some(self.check_run_id.to_string()),
+            value_from: None,
+        };
+
+        let service_name_env = EnvVar {
+            name: SERVICE_NAME_ENV.to_string(),
+            value: Some(self.service.name.to_string()),
+            value_from: None,
+        };
+
+        let envs: Vec<EnvVar> = self
+            .service
+            .env
+            .iter()
+            .flatten()
+            .map(Environment::to_env_var)
+            .chain(vec![service_of_env, service_name_env])
+            .collect();
+
+        Container {
+            args: self.service.args.clone(),
+            command: self.service.command.clone(),
+            env: Some(envs),
+            env_from: None,
+            image: Some(self.service.image.to_string()),
+            image_pull_policy: None,
+            lifecycle: None,
+            liveness_probe: None,
+            name: container_name("service", &self.service.name, self.check_run_id),
+            ports: None,
+            readiness_probe: self.service.readiness_probe.clone(),
+            resources: None,
+            security_context: None,
+            startup_probe: None,
+            stdin: None,
+            stdin_once: None,
+            termination_message_path: None,
+            termination_message_policy: None,
+            tty: None,
+            volume_devices: None,
+            volume_mounts: None,
+            working_dir: None,
+        }
+    }
+}
+
+// Kubernetes doesn't stop sidecars once the main container exits, so this tells each step once its
+// services are ready, then stops them once the step is done. Finding the services' processes needs
+// the pod's shared process namespace.
+pub struct ServicesStopContainer<'a> {
+    pub steps_with_check_run_id: &'a [StepWithCheckRunId<'a>],
+}
+
+impl<'a> ServicesStopContainer<'a> {
+    pub fn is_needed(&self) -> bool {
+        self.steps_with_check_run_id
+            .iter()
+            .any(|step_with_check_run_id| step_with_check_run_id.step.services.is_some())
+    }
+}
+
+impl<'a> KubernetesContainer for ServicesStopContainer<'a> {
+    fn to_container(&self) -> Container {
+        let mut script = format!(
+            "processes_with() {{ for environ in /proc/[0-9]*/environ; do \
+             if tr '\\0' '\\n' < \"$environ\" 2>/dev/null | grep -qx \"$1\"; then \
+             pid=\"${{environ#/proc/}}\"; echo \"${{pid%/environ}}\"; fi; done; }}\n\
+             {wait_for_step}\
+             stop_services_of() {{ \
+             echo \"Stopping the services of $1\"; \
+             kill -TERM $(processes_with \"{service_of}=$1\") 2>/dev/null; \
+             sleep 10; \
+             kill -KILL $(processes_with \"{service_of}=$1\") 2>/dev/null; \
+             true; }}\n",
+            wait_for_step = wait_for_step_function(),
+            service_of = SERVICE_OF_ENV
+        );
+        let mut volume_mounts = Vec::new();
+
+        for step_with_check_run_id in self.steps_with_check_run_id {
+            if let Some(services) = &step_with_check_run_id.step.services {
+                let check_run_id = step_with_check_run_id.check_run_id;
+
+                let readiness_checks: Vec<String> = services
+                    .iter()
+                    .filter_map(Service::readiness_check)
+                    .collect();
+
+                let readiness_check = if readiness_checks.is_empty() {
+                    "true".to_string()
+                } else {
+                    readiness_checks.join(" && ")
+                };
+
+                script += &format!(
+                    "(until {check}; do step_done {id} && break; sleep 1; done; \
+                     touch /{id}/{ready}; \
+                     wait_for_step {id}; \
+                     stop_services_of {id}) &\n",
+                    check = readiness_check,
+                    id = check_run_id,
+                    ready = SERVICES_READY_MARKER
+                );
+
+                volume_mounts.push(VolumeMount {
+                    mount_path: format!("/{}", check_run_id),
+                    mount_propagation: None,
+                    name: check_run_id.to_string(),
+                    read_only: None,
+                    sub_path: None,
+                    sub_path_expr: None,
+                });
+            }
+        }
+
+        script += "wait\n";
+
+        Container {
+            args: None,
+            command: Some(vec!["/bin/sh".to_string(), "-c".to_string(), script]),
+            env: None,
+            env_from: None,
+            image: Some(SERVICES_STOP_IMAGE.to_string()),
+            image_pull_policy: None,
+            lifecycle: None,
+            liveness_probe: None,
+            name: SERVICES_STOP_CONTAINER_NAME.to_string(),
+            ports: None,
+            readiness_probe: None,
+            resources: None,
+            // Services often run as their own user, so reading their environment needs ptrace, and
+            // running their readiness probes in their files needs chroot
+            security_context: Some(SecurityContext {
+                allow_privilege_escalation: None,
+                capabilities: Some(Capabilities {
+                    add: Some(vec!["SYS_PTRACE".to_string(), "SYS_CHROOT".to_string()]),
+                    drop: None,
+                }),
+                privileged: None,
+                proc_mount: None,
+                read_only_root_filesystem: None,
+                run_as_group: None,
+                run_as_non_root: None,
+                run_as_user: None,
+                se_linux_options: None,
+                windows_options: None,
+            }),
+            startup_probe: None,
+            stdin: None,
+            stdin_once: None,
+            termination_message_path: None,
+            termination_message_policy: None,
+            tty: None,
+            volume_devices: None,
+            volume_mounts: Some(volume_mounts),
+            working_dir: None,
+        }
+    }
+}
+
+pub fn service_containers(steps_with_check_run_id: &[StepWithCheckRunId]) -> Vec<Container> {
+    steps_with_check_run_id
+        .iter()
+        .flat_map(|step_with_check_run_id| {
+            step_with_check_run_id
+                .step
+                .services
+                .iter()
+                .flatten()
+                .map(move |service| {
+                    ServiceContainer {
+                        service,
+                        check_run_id: step_with_check_run_id.check_run_id,
+                    }
+                    .to_container()
+                })
+        })
+        .collect()
+}
+
+// Services of different steps would fight over the same ports, so steps with services get a pod
+// each
+pub fn separate_steps_with_services<T>(
+    pods: Vec<Vec<T>>,
+    has_services: impl Fn(&T) -> bool,
+) -> Vec<Vec<T>> {
+    let mut separated_pods = Vec::new();
+
+    for pod in pods {
+        let (with_services, without_services): (Vec<T>, Vec<T>) =
+            pod.into_iter().partition(|item| has_services(item));
+
+        if !without_services.is_empty() {
+            separated_pods.push(without_services);
+        }
+
+        separated_pods.extend(with_services.into_iter().map(|item| vec![item]));
+    }
+
+    separated_pods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::Step;
+
+    #[test]
+    fn should_parse_services_with_readiness_probes() {
+        let services: Vec<Service> = serde_yaml::from_str(
+            r#"
+- name: postgres
+  image: postgres:12
+  env:
+    - name: POSTGRES_PASSWORD
+      value: password
+  readinessProbe:
+    exec:
+      command: ["pg_isready", "-U", "postgres"]
+    periodSeconds: 2
+"#,
+        )
+        .unwrap();
+
+        let container = ServiceContainer {
+            service: &services[0],
+            check_run_id: 7,
+        }
+        .to_container();
+
+        assert_eq!(container.name, "service-postgres-7");
+        assert_eq!(
+            container
+                .env
+                .unwrap()
+                .iter()
+                .map(|env| (env.name.as_str(), env.value.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("POSTGRES_PASSWORD", Some("password")),
+                ("KUBESCI_SERVICE_OF", Some("7")),
+                ("KUBESCI_SERVICE_NAME", Some("postgres")),
+            ]
+        );
+        assert_eq!(
+            container.readiness_probe.unwrap().exec.unwrap().command,
+            Some(vec![
+                "pg_isready".to_string(),
+                "-U".to_string(),
+                "postgres".to_string()
+            ])
+        );
+        assert_eq!(
+            services[0].readiness_check(),
+            Some(
+                "chroot \"/proc/$(processes_with \"KUBESCI_SERVICE_NAME=postgres\" | head -n 1)/root\" \
+                 'pg_isready' '-U' 'postgres' >/dev/null 2>&1"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_keep_long_service_container_names_within_kubernetes_limits() {
+        let services: Vec<Service> = serde_yaml::from_str(
+            r#"
+- name: Elasticsearch with the analysis/icu plugin and a few more besides
+  image: elasticsearch:7
+"#,
+        )
+        .unwrap();
+
+        let container = ServiceContainer {
+            service: &services[0],
+            check_run_id: 1234567890,
+        }
+        .to_container();
+
+        assert_eq!(
+            container.name,
+            "service-elasticsearch-with-the-analysisicu-plugin-an-1234567890"
+        );
+        assert_eq!(container.name.len(), 63);
+    }
+
+    #[test]
+    fn should_check_readiness_over_the_pod_network() {
+        let services: Vec<Service> = serde_yaml::from_str(
+            r#"
+- name: api
+  image: api
+  readinessProbe:
+    httpGet:
+      path: /health
+      port: 8080
+- name: redis
+  image: redis:6
+  readinessProbe:
+    tcpSocket:
+      port: 6379
+- name: queue
+  image: queue
+  readinessProbe:
+    tcpSocket:
+      port: amqp
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            services[0].readiness_check(),
+            Some("wget -q -O /dev/null 'http://127.0.0.1:8080/health'".to_string())
+        );
+        assert_eq!(
+            services[1].readiness_check(),
+            Some("nc -z 127.0.0.1 6379".to_string())
+        );
+        assert!(services[1].validate_readiness_probe().is_ok());
+        assert_eq!(
+            services[2].validate_readiness_probe(),
+            Err(
+                "The readiness probe of the service queue needs a port number rather than amqp"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_only_stop_services_of_steps_that_have_them() {
+        let integration = Step {
+            name: "integration".to_string(),
+            services: Some(vec1![Service {
+                name: "redis".to_string(),
+                image: "redis:6".to_string(),
+                command: None,
+                args: None,
+                env: None,
+                readiness_probe: None,
+            }]),
+            ..Default::default()
+        };
+        let lint = Step {
+            name: "lint".to_string(),
+            ..Default::default()
+        };
+
+        let steps_with_check_run_id = vec![
+            StepWithCheckRunId {
+                step: &integration,
+                check_run_id: 1,
+                attempt: 1,
+            },
+            StepWithCheckRunId {
+                step: &lint,
+                check_run_id: 2,
+                attempt: 1,
+            },
+        ];
+
+        let stop_container = ServicesStopContainer {
+            steps_with_check_run_id: &steps_with_check_run_id,
+        };
+
+        assert!(stop_container.is_needed());
+
+        let script = &stop_container.to_container().command.unwrap()[2];
+
+        assert!(script.contains(
+            "(until true; do step_done 1 && break; sleep 1; done; touch /1/.kubesci-services-ready; \
+             wait_for_step 1; stop_services_of 1) &\n"
+        ));
+        assert!(!script.contains("step_done 2"));
+        assert_eq!(
+            service_containers(&steps_with_check_run_id)
+                .iter()
+                .map(|container| container.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["service-redis-1"]
+        );
+    }
+
+    #[test]
+    fn should_give_steps_with_services_their_own_pod() {
+        let pods = separate_steps_with_services(
+            vec![vec![("a", false), ("b", true), ("c", false), ("d", true)]],
+            |(_, has_services)| *has_services,
+        );
+
+        assert_eq!(
+            pods,
+            vec![
+                vec![("a", false), ("c", false)],
+                vec![("b", true)],
+                vec![("d", true)],
+            ]
+        );
+    }
+}
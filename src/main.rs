@@ -9,17 +9,26 @@ use kube::{api::Api, Client};
 extern crate vec1;
 
 use handlers::{
-    check_run::handle_check_run_request, check_suite::handle_check_suite_request,
-    pipeline::handle_get_pipeline, pipelines::handle_get_pipelines, steps::handle_get_steps,
+    artifacts::{handle_get_artifact, handle_list_artifacts, handle_upload_artifact},
+    caches::{handle_get_cache, handle_upload_cache},
+    check_run::handle_check_run_request,
+    check_suite::handle_check_suite_request,
+    pipeline::handle_get_pipeline,
+    pipeline_uploads::handle_upload_pipeline,
+    pipelines::handle_get_pipelines,
+    pull_request::handle_pull_request_request,
+    steps::handle_get_steps,
 };
 use pipeline::PipelineService;
 use routes::{
-    check_run_route, check_suite_route, get_pipeline_route, get_pipeline_steps_route,
-    get_pipelines_route,
+    check_run_route, check_suite_route, get_artifact_route, get_cache_route, get_pipeline_route,
+    get_pipeline_steps_route, get_pipelines_route, handle_rejection, list_artifacts_route,
+    pull_request_route, upload_artifact_route, upload_cache_route, upload_pipeline_route,
 };
 
 use pod_informer::PodInformer;
 
+mod artifacts;
 mod config;
 mod github;
 mod handlers;
@@ -39,18 +48,28 @@ async fn main() {
                 application_id: config.application_id.clone(),
                 namespace: config.namespace.clone(),
                 github_base_url: config.github_base_url.clone(),
+                github_url: config.github_url.clone(),
+                max_step_resources: config.max_step_resources.clone(),
+                kubesci_url: config.kubesci_url.clone(),
+                webhook_secret: config.webhook_secret.clone(),
+                artifact_store: config.artifact_store.clone(),
+                templates_repo: config.templates_repo.clone(),
             };
 
             let pipeline_service_handler = warp::any().map(move || pipeline_service.clone());
 
-            let check_suite_handler = check_suite_route()
+            let check_suite_handler = check_suite_route(config.webhook_secret.clone())
                 .and(pipeline_service_handler.clone())
                 .and_then(handle_check_suite_request);
 
-            let check_run_handler = check_run_route()
+            let check_run_handler = check_run_route(config.webhook_secret.clone())
                 .and(pipeline_service_handler.clone())
                 .and_then(handle_check_run_request);
 
+            let pull_request_handler = pull_request_route(config.webhook_secret.clone())
+                .and(pipeline_service_handler.clone())
+                .and_then(handle_pull_request_request);
+
             let cors = warp::cors().allow_origin("http://localhost:3000");
 
             let get_pipelines_handler = get_pipelines_route()
@@ -69,11 +88,57 @@ async fn main() {
                 .and_then(handle_get_steps)
                 .with(steps_cors);
 
+            let artifact_store = config.artifact_store.clone();
+            let artifact_store_handler = warp::any().map(move || artifact_store.clone());
+
+            let list_artifacts_handler = list_artifacts_route(config.webhook_secret.clone())
+                .and(artifact_store_handler.clone())
+                .and_then(handle_list_artifacts);
+
+            let get_artifact_handler = get_artifact_route(config.webhook_secret.clone())
+                .and(artifact_store_handler.clone())
+                .and_then(handle_get_artifact);
+
+            let upload_artifact_handler =
+                upload_artifact_route(config.webhook_secret.clone(), config.max_artifact_size)
+                    .and(artifact_store_handler.clone())
+                    .and_then(handle_upload_artifact);
+
+            let get_cache_handler = get_cache_route(config.webhook_secret.clone())
+                .and(artifact_store_handler.clone())
+                .and_then(handle_get_cache);
+
+            let max_cache_size = config.max_cache_size;
+
+            let upload_cache_handler =
+                upload_cache_route(config.webhook_secret.clone(), max_cache_size)
+                    .and(artifact_store_handler.clone())
+                    .and(warp::any().map(move || max_cache_size))
+                    .and_then(handle_upload_cache);
+
+            let max_step_resources = config.max_step_resources.clone();
+
+            let upload_pipeline_handler = upload_pipeline_route(
+                config.webhook_secret.clone(),
+                config.max_pipeline_upload_size,
+            )
+            .and(artifact_store_handler.clone())
+            .and(warp::any().map(move || max_step_resources.clone()))
+            .and_then(handle_upload_pipeline);
+
             let app_routes = check_suite_handler
                 .or(check_run_handler)
+                .or(pull_request_handler)
                 .or(get_pipeline_steps_handler)
                 .or(get_pipeline_handler)
-                .or(get_pipelines_handler);
+                .or(get_pipelines_handler)
+                .or(list_artifacts_handler)
+                .or(get_artifact_handler)
+                .or(upload_artifact_handler)
+                .or(get_cache_handler)
+                .or(upload_cache_handler)
+                .or(upload_pipeline_handler)
+                .recover(handle_rejection);
 
             let address =
                 std::env::var("SOCKET_ADDRESS").unwrap_or_else(|_| "127.0.0.1".to_string());
@@ -91,6 +156,12 @@ async fn main() {
                 application_id: config.application_id.clone(),
                 namespace: config.namespace.clone(),
                 github_base_url: config.github_base_url.clone(),
+                github_url: config.github_url.clone(),
+                max_step_resources: config.max_step_resources.clone(),
+                kubesci_url: config.kubesci_url.clone(),
+                webhook_secret: config.webhook_secret.clone(),
+                artifact_store: config.artifact_store.clone(),
+                templates_repo: config.templates_repo.clone(),
             };
 
             let pod_informer = PodInformer {
@@ -108,6 +179,6 @@ async fn main() {
 
             futures::future::join_all(tasks).await;
         }
-        Err(var_error) => error!("Config error: {}", var_error),
+        Err(config_error) => error!("Config error: {}", config_error),
     }
 }
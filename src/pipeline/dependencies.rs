@@ -0,0 +1,322 @@
+use crate::github::client::installation::{GetCheckRunResponse, UNBLOCKED_BLOCK_SUMMARY};
+use crate::kubernetes::StepType;
+use crate::pipeline::steps_filter::{filter_dependent_steps, filter_step_sections, BuildContext};
+use crate::pipeline::StepLocation;
+use either::Either::{Left, Right};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DependencyState {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+pub struct KeyedStep<'a> {
+    pub name: &'a str,
+    pub is_block: bool,
+    pub location: StepLocation,
+}
+
+pub fn validate_dependencies(steps: &[StepType]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut dependencies_by_key: HashMap<&str, Vec<&str>> = HashMap::new();
+
+    for step in steps {
+        if let Some(key) = step.key() {
+            let dependencies = step
+                .depends_on()
+                .map(|depends_on| depends_on.iter().map(String::as_str).collect())
+                .unwrap_or_default();
+
+            if dependencies_by_key.insert(key, dependencies).is_some() {
+                return Err(format!("The key {} is used by more than one step", key).into());
+            }
+        }
+    }
+
+    for dependency in steps.iter().filter_map(StepType::depends_on).flatten() {
+        if !dependencies_by_key.contains_key(dependency.as_str()) {
+            return Err(format!("A step depends on the unknown key {}", dependency).into());
+        }
+    }
+
+    // Only keyed steps can be depended on, so any cycle has to be made up of them
+    let mut visited = HashSet::new();
+
+    for key in dependencies_by_key.keys() {
+        find_cycle(key, &dependencies_by_key, &mut Vec::new(), &mut visited)?;
+    }
+
+    Ok(())
+}
+
+fn find_cycle<'a>(
+    key: &'a str,
+    dependencies_by_key: &HashMap<&'a str, Vec<&'a str>>,
+    path: &mut Vec<&'a str>,
+    visited: &mut HashSet<&'a str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if path.contains(&key) {
+        path.push(key);
+
+        return Err(format!("The step dependencies form a cycle: {}", path.join(" -> ")).into());
+    }
+
+    if !visited.insert(key) {
+        return Ok(());
+    }
+
+    path.push(key);
+
+    for dependency in dependencies_by_key.get(key).into_iter().flatten() {
+        find_cycle(dependency, dependencies_by_key, path, visited)?;
+    }
+
+    path.pop();
+
+    Ok(())
+}
+
+// Steps filtered out by their branch or event never run, so aren't included. Matrix steps share
+// their key between every combination.
+pub fn locate_keyed_steps<'a>(
+    steps: &'a [StepType],
+    build_context: &BuildContext,
+) -> HashMap<&'a str, Vec<KeyedStep<'a>>> {
+    let mut keyed_steps: HashMap<&str, Vec<KeyedStep>> = HashMap::new();
+
+    let step_sections = filter_step_sections(steps, build_context);
+
+    for (step_section_index, step_section) in step_sections.into_iter().enumerate() {
+        let location = StepLocation::Section(step_section_index);
+
+        match step_section.steps {
+            Left(block) => {
+                if let Some(key) = &block.key {
+                    keyed_steps
+                        .entry(key.as_str())
+                        .or_default()
+                        .push(KeyedStep {
+                            name: &block.name,
+                            is_block: true,
+                            location,
+                        });
+                }
+            }
+            Right(steps) => {
+                for step in steps {
+                    if let Some(key) = &step.key {
+                        keyed_steps
+                            .entry(key.as_str())
+                            .or_default()
+                            .push(KeyedStep {
+                                name: &step.name,
+                                is_block: false,
+                                location,
+                            });
+                    }
+                }
+            }
+        }
+    }
+
+    for dependent_step in filter_dependent_steps(steps, build_context) {
+        let location = StepLocation::Dependent(dependent_step.index);
+
+        let (maybe_key, name, is_block) = match dependent_step.steps {
+            Left(block) => (&block.key, &block.name, true),
+            Right(step) => (&step.key, &step.name, false),
+        };
+
+        if let Some(key) = maybe_key {
+            keyed_steps
+                .entry(key.as_str())
+                .or_default()
+                .push(KeyedStep {
+                    name,
+                    is_block,
+                    location,
+                });
+        }
+    }
+
+    keyed_steps
+}
+
+// Blocks are completed as soon as they're created, so they only count once they've been unblocked
+pub fn dependency_state(check_run: &GetCheckRunResponse, is_block: bool) -> DependencyState {
+    if check_run.status != "completed" {
+        return DependencyState::Pending;
+    }
+
+    match check_run.conclusion.as_deref() {
+        Some("success") if is_block => {
+            if check_run.output.summary.as_deref() == Some(UNBLOCKED_BLOCK_SUMMARY) {
+                DependencyState::Succeeded
+            } else {
+                DependencyState::Pending
+            }
+        }
+        Some("success") | Some("neutral") => DependencyState::Succeeded,
+        _ => DependencyState::Failed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::client::installation::{CheckRunCheckSuite, GetCheckRunOutput};
+    use crate::kubernetes::{Block, Step};
+
+    fn step(name: &str, key: Option<&str>, depends_on: Vec<&str>) -> StepType {
+        StepType::Step(Step {
+            name: name.to_string(),
+            image: "some_image".to_string(),
+            key: key.map(str::to_string),
+            depends_on: vec1::Vec1::try_from_vec(
+                depends_on.into_iter().map(str::to_string).collect(),
+            )
+            .ok(),
+            ..Default::default()
+        })
+    }
+
+    fn check_run(
+        status: &str,
+        conclusion: Option<&str>,
+        summary: Option<&str>,
+    ) -> GetCheckRunResponse {
+        GetCheckRunResponse {
+            id: 1,
+            name: "some_step".to_string(),
+            started_at: "2020-01-01T00:00:00Z".to_string(),
+            status: status.to_string(),
+            conclusion: conclusion.map(str::to_string),
+            external_id: None,
+            output: GetCheckRunOutput {
+                summary: summary.map(str::to_string),
+                text: None,
+            },
+            check_suite: CheckRunCheckSuite { id: 1 },
+        }
+    }
+
+    #[test]
+    fn should_accept_valid_dependencies() {
+        let steps = vec![
+            step("build", Some("build"), vec![]),
+            step("test", Some("test"), vec!["build"]),
+            step("deploy", None, vec!["build", "test"]),
+        ];
+
+        assert!(validate_dependencies(&steps).is_ok());
+    }
+
+    #[test]
+    fn should_reject_dependencies_on_unknown_keys() {
+        let steps = vec![
+            step("build", Some("build"), vec![]),
+            step("test", None, vec!["biuld"]),
+        ];
+
+        let error = validate_dependencies(&steps).unwrap_err();
+
+        assert_eq!(error.to_string(), "A step depends on the unknown key biuld");
+    }
+
+    #[test]
+    fn should_reject_duplicate_keys() {
+        let block = StepType::Block(Block {
+            name: "release?".to_string(),
+            key: Some("build".to_string()),
+            ..Default::default()
+        });
+
+        let steps = vec![step("build", Some("build"), vec![]), block];
+
+        assert!(validate_dependencies(&steps).is_err());
+    }
+
+    #[test]
+    fn should_reject_dependency_cycles() {
+        let steps = vec![
+            step("build", Some("build"), vec!["deploy"]),
+            step("test", Some("test"), vec!["build"]),
+            step("deploy", Some("deploy"), vec!["test"]),
+        ];
+
+        let error = validate_dependencies(&steps).unwrap_err();
+
+        assert!(error
+            .to_string()
+            .starts_with("The step dependencies form a cycle"));
+    }
+
+    #[test]
+    fn should_reject_steps_depending_on_themselves() {
+        let steps = vec![step("build", Some("build"), vec!["build"])];
+
+        assert!(validate_dependencies(&steps).is_err());
+    }
+
+    #[test]
+    fn should_locate_keyed_steps_in_step_sections_and_dependent_steps() {
+        let steps = vec![
+            step("build", Some("build"), vec![]),
+            step("test", Some("test"), vec!["build"]),
+            step("lint", None, vec![]),
+        ];
+
+        let keyed_steps = locate_keyed_steps(
+            &steps,
+            &BuildContext {
+                branch: "some_branch".to_string(),
+                event: "push",
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(keyed_steps.len(), 2);
+        assert_eq!(keyed_steps["build"][0].location, StepLocation::Section(0));
+        assert_eq!(keyed_steps["test"][0].location, StepLocation::Dependent(1));
+    }
+
+    #[test]
+    fn should_only_treat_successful_check_runs_as_succeeded() {
+        assert_eq!(
+            dependency_state(&check_run("in_progress", None, None), false),
+            DependencyState::Pending
+        );
+        assert_eq!(
+            dependency_state(&check_run("completed", Some("success"), None), false),
+            DependencyState::Succeeded
+        );
+        assert_eq!(
+            dependency_state(&check_run("completed", Some("failure"), None), false),
+            DependencyState::Failed
+        );
+        assert_eq!(
+            dependency_state(&check_run("completed", Some("skipped"), None), false),
+            DependencyState::Failed
+        );
+        assert_eq!(
+            dependency_state(&check_run("completed", Some("neutral"), None), false),
+            DependencyState::Succeeded
+        );
+    }
+
+    #[test]
+    fn should_only_treat_blocks_as_succeeded_once_unblocked() {
+        assert_eq!(
+            dependency_state(&check_run("completed", Some("success"), None), true),
+            DependencyState::Pending
+        );
+        assert_eq!(
+            dependency_state(
+                &check_run("completed", Some("success"), Some(UNBLOCKED_BLOCK_SUMMARY)),
+                true
+            ),
+            DependencyState::Succeeded
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use crate::pipeline::includes::{PipelineSource, PIPELINE_FILE_PATH};
+
+const PIPELINES_DIRECTORY: &str = ".kubesci/";
+const DIRECTORY_PIPELINE_FILE_NAME: &str = "kubesci.yml";
+// Kubernetes label values can't be any longer
+const MAX_LABEL_LENGTH: usize = 63;
+
+// One of a repo's pipelines, each run on its own with its own check runs and pods. The repo's main
+// pipeline is left as it always was, so its check runs keep their names.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelinePath {
+    pub path: String,
+}
+
+impl PipelinePath {
+    pub fn root() -> PipelinePath {
+        PipelinePath {
+            path: PIPELINE_FILE_PATH.to_string(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.path == PIPELINE_FILE_PATH
+    }
+
+    pub fn source(&self) -> PipelineSource {
+        PipelineSource {
+            repo: None,
+            git_ref: None,
+            file: self.path.clone(),
+        }
+    }
+
+    // `.kubesci/deploy.yml` is deploy, and `services/api/kubesci.yml` is services/api
+    pub fn name(&self) -> Option<&str> {
+        if self.is_root() {
+            return None;
+        }
+
+        let name = match self.path.strip_prefix(PIPELINES_DIRECTORY) {
+            Some(file) => file.strip_suffix(".yml").unwrap_or(file),
+            None => self
+                .path
+                .strip_suffix(DIRECTORY_PIPELINE_FILE_NAME)
+                .map(|directory| directory.trim_end_matches('/'))
+                .unwrap_or(&self.path),
+        };
+
+        Some(name)
+    }
+
+    pub fn check_run_name(&self, step_name: &str) -> String {
+        match self.name() {
+            Some(name) => format!("{} / {}", name, step_name),
+            None => step_name.to_string(),
+        }
+    }
+
+    // Check runs GitHub hands back have the prefixed name, while the pipeline has the step's own
+    pub fn step_name<'a>(&self, check_run_name: &'a str) -> &'a str {
+        self.name()
+            .and_then(|name| check_run_name.strip_prefix(name))
+            .and_then(|step_name| step_name.strip_prefix(" / "))
+            .unwrap_or(check_run_name)
+    }
+
+    // Paths have characters that labels can't, which become dots like the repo name's slash
+    pub fn label(&self) -> String {
+        let label: String = self
+            .path
+            .chars()
+            .map(|character| {
+                if character.is_ascii_alphanumeric() || character == '-' || character == '_' {
+                    character
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        label
+            .trim_matches(|character: char| !character.is_ascii_alphanumeric())
+            .chars()
+            .take(MAX_LABEL_LENGTH)
+            .collect::<String>()
+            .trim_end_matches(|character: char| !character.is_ascii_alphanumeric())
+            .to_string()
+    }
+
+    // Pod names are lowercase DNS names, and have to tell pipelines for the same commit apart
+    pub fn pod_name_part(&self) -> Option<String> {
+        if self.is_root() {
+            return None;
+        }
+
+        let part: String = self
+            .label()
+            .to_lowercase()
+            .chars()
+            .map(|character| {
+                if character.is_ascii_alphanumeric() {
+                    character
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+
+        Some(part)
+    }
+}
+
+// Pipelines are the main `.kubesci/pipeline.yml`, any other `.kubesci/*.yml`, and a `kubesci.yml`
+// in any directory below the root. Files that are included by another pipeline are left out by
+// the caller once the pipelines are resolved.
+pub fn discover_pipeline_paths(paths: &[String]) -> Vec<PipelinePath> {
+    let mut pipeline_paths: Vec<PipelinePath> = paths
+        .iter()
+        .filter(|path| {
+            let is_pipelines_directory_file = path
+                .strip_prefix(PIPELINES_DIRECTORY)
+                .map(|file| file.ends_with(".yml") && !file.contains('/'))
+                .unwrap_or(false);
+
+            let is_directory_pipeline_path = path
+                .strip_suffix(DIRECTORY_PIPELINE_FILE_NAME)
+                .map(|directory| directory.ends_with('/'))
+                .unwrap_or(false);
+
+            is_pipelines_directory_file || is_directory_pipeline_path
+        })
+        .map(|path| PipelinePath { path: path.clone() })
+        .collect();
+
+    // The main pipeline goes first, so it's reported first
+    pipeline_paths.sort_by(|a, b| (!a.is_root(), &a.path).cmp(&(!b.is_root(), &b.path)));
+
+    pipeline_paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_discover_pipelines_in_the_pipelines_directory_and_other_directories() {
+        let paths: Vec<String> = vec![
+            "README.md",
+            ".kubesci/templates/rust.yml",
+            ".kubesci/release.yml",
+            ".kubesci/pipeline.yml",
+            "kubesci.yml",
+            "services/api/kubesci.yml",
+            "services/web/not-kubesci.yml",
+        ]
+        .into_iter()
+        .map(|path| path.to_string())
+        .collect();
+
+        let pipeline_paths = discover_pipeline_paths(&paths);
+
+        assert_eq!(
+            pipeline_paths
+                .iter()
+                .map(|pipeline_path| pipeline_path.path.as_str())
+                .collect::<Vec<&str>>(),
+            vec![
+                ".kubesci/pipeline.yml",
+                ".kubesci/release.yml",
+                "services/api/kubesci.yml"
+            ]
+        );
+        assert_eq!(
+            pipeline_paths
+                .iter()
+                .map(|pipeline_path| pipeline_path.name())
+                .collect::<Vec<Option<&str>>>(),
+            vec![None, Some("release"), Some("services/api")]
+        );
+    }
+
+    #[test]
+    fn should_prefix_check_runs_of_every_pipeline_but_the_main_one() {
+        let pipeline_path = PipelinePath {
+            path: "services/api/kubesci.yml".to_string(),
+        };
+
+        assert_eq!(pipeline_path.check_run_name("Test"), "services/api / Test");
+        assert_eq!(pipeline_path.step_name("services/api / Test"), "Test");
+        assert_eq!(PipelinePath::root().check_run_name("Test"), "Test");
+        assert_eq!(PipelinePath::root().step_name("Test"), "Test");
+    }
+
+    #[test]
+    fn should_make_labels_and_pod_names_from_the_path() {
+        let pipeline_path = PipelinePath {
+            path: ".kubesci/Release_Candidate.yml".to_string(),
+        };
+
+        assert_eq!(pipeline_path.label(), "kubesci.Release_Candidate.yml");
+        assert_eq!(
+            pipeline_path.pod_name_part(),
+            Some("kubesci-release-candidate-yml".to_string())
+        );
+        assert_eq!(PipelinePath::root().pod_name_part(), None);
+        assert_eq!(
+            PipelinePath {
+                path: format!("{}/kubesci.yml", "a".repeat(80))
+            }
+            .label()
+            .len(),
+            63
+        );
+    }
+}
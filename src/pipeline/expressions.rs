@@ -0,0 +1,500 @@
+use crate::pipeline::steps_filter::BuildContext;
+use regex::Regex;
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+// A condition on the build, such as `build.branch == "master" && build.message !~ /\[skip ci\]/`.
+// Checked when the pipeline is loaded, so mistakes are reported rather than skipping the step.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression {
+    node: Node,
+}
+
+impl Expression {
+    pub fn evaluate(&self, build_context: &BuildContext) -> bool {
+        evaluate(&self.node, build_context) == Value::Bool(true)
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let node = parse(&source)
+            .and_then(|node| match type_of(&node)? {
+                Type::Bool => Ok(node),
+                other => Err(format!("it has to be true or false, not {}", other)),
+            })
+            .map_err(|error| format!("Invalid if expression `{}`: {}", source, error))?;
+
+        Ok(Expression { node })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(Value),
+    Variable(Variable),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Equals(Box<Node>, Box<Node>),
+    NotEquals(Box<Node>, Box<Node>),
+    Matches(Box<Node>, Regex),
+    NotMatches(Box<Node>, Regex),
+    Includes(Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variable {
+    Branch,
+    Tag,
+    Event,
+    Message,
+    Author,
+    PullRequestLabels,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Variable> {
+        match name {
+            "build.branch" => Some(Variable::Branch),
+            "build.tag" => Some(Variable::Tag),
+            "build.event" => Some(Variable::Event),
+            "build.message" => Some(Variable::Message),
+            "build.author" => Some(Variable::Author),
+            "build.pull_request.labels" => Some(Variable::PullRequestLabels),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Null,
+    Bool(bool),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    String,
+    // Only `build.tag`, which is null for builds of a branch
+    OptionalString,
+    Null,
+    Bool,
+    List,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::String => write!(f, "a string"),
+            Type::OptionalString => write!(f, "a string or null"),
+            Type::Null => write!(f, "null"),
+            Type::Bool => write!(f, "true or false"),
+            Type::List => write!(f, "a list"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Regex(String),
+    Equals,
+    NotEquals,
+    Matches,
+    NotMatches,
+    And,
+    Or,
+    Not,
+    LeftParenthesis,
+    RightParenthesis,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut characters = source.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        let token = match character {
+            ' ' | '\t' | '\n' => continue,
+            '(' => Token::LeftParenthesis,
+            ')' => Token::RightParenthesis,
+            '=' | '!' | '&' | '|' => match (character, characters.peek()) {
+                ('=', Some('=')) => Token::Equals,
+                ('=', Some('~')) => Token::Matches,
+                ('!', Some('=')) => Token::NotEquals,
+                ('!', Some('~')) => Token::NotMatches,
+                ('&', Some('&')) => Token::And,
+                ('|', Some('|')) => Token::Or,
+                ('!', _) => {
+                    tokens.push(Token::Not);
+                    continue;
+                }
+                _ => return Err(format!("unexpected {}", character)),
+            },
+            '"' | '\'' | '/' => {
+                let mut value = String::new();
+                let mut is_closed = false;
+
+                while let Some(next_character) = characters.next() {
+                    match next_character {
+                        '\\' if character != '/' => value.extend(characters.next()),
+                        // Regexes keep their escapes, other than for the closing slash
+                        '\\' if characters.peek() == Some(&'/') => value.extend(characters.next()),
+                        _ if next_character == character => {
+                            is_closed = true;
+                            break;
+                        }
+                        _ => value.push(next_character),
+                    }
+                }
+
+                if !is_closed {
+                    return Err(format!("missing the closing {}", character));
+                }
+
+                if character == '/' {
+                    Token::Regex(value)
+                } else {
+                    Token::String(value)
+                }
+            }
+            _ if character.is_ascii_alphabetic() || character == '_' => {
+                let mut identifier = character.to_string();
+
+                while let Some(next_character) = characters.peek() {
+                    if next_character.is_ascii_alphanumeric()
+                        || *next_character == '_'
+                        || *next_character == '.'
+                    {
+                        identifier.push(*next_character);
+                        characters.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                tokens.push(Token::Identifier(identifier));
+                continue;
+            }
+            _ => return Err(format!("unexpected {}", character)),
+        };
+
+        // Every token left is made up of two characters, other than parentheses and strings
+        if let Token::Equals
+        | Token::Matches
+        | Token::NotEquals
+        | Token::NotMatches
+        | Token::And
+        | Token::Or = token
+        {
+            characters.next();
+        }
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn parse(source: &str) -> Result<Node, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+
+    let node = parser.parse_or()?;
+
+    match parser.tokens.get(parser.position) {
+        None => Ok(node),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+// Precedence from lowest to highest: `||`, `&&`, `!`, then comparisons
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is(&self, token: &Token) -> bool {
+        self.tokens.get(self.position) == Some(token)
+    }
+
+    fn parse_or(&mut self) -> Result<Node, String> {
+        let mut node = self.parse_and()?;
+
+        while self.next_is(&Token::Or) {
+            self.position += 1;
+            node = Node::Or(Box::new(node), Box::new(self.parse_and()?));
+        }
+
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, String> {
+        let mut node = self.parse_not()?;
+
+        while self.next_is(&Token::And) {
+            self.position += 1;
+            node = Node::And(Box::new(node), Box::new(self.parse_not()?));
+        }
+
+        Ok(node)
+    }
+
+    fn parse_not(&mut self) -> Result<Node, String> {
+        if self.next_is(&Token::Not) {
+            self.position += 1;
+
+            return Ok(Node::Not(Box::new(self.parse_not()?)));
+        }
+
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Node, String> {
+        let left = Box::new(self.parse_operand()?);
+
+        let node = match self.tokens.get(self.position) {
+            Some(Token::Equals) => {
+                self.position += 1;
+                Node::Equals(left, Box::new(self.parse_operand()?))
+            }
+            Some(Token::NotEquals) => {
+                self.position += 1;
+                Node::NotEquals(left, Box::new(self.parse_operand()?))
+            }
+            Some(Token::Matches) => {
+                self.position += 1;
+                Node::Matches(left, self.parse_regex()?)
+            }
+            Some(Token::NotMatches) => {
+                self.position += 1;
+                Node::NotMatches(left, self.parse_regex()?)
+            }
+            Some(Token::Identifier(identifier)) if identifier == "includes" => {
+                self.position += 1;
+                Node::Includes(left, Box::new(self.parse_operand()?))
+            }
+            _ => *left,
+        };
+
+        Ok(node)
+    }
+
+    fn parse_regex(&mut self) -> Result<Regex, String> {
+        match self.next_token() {
+            Some(Token::Regex(regex)) => Regex::new(&regex)
+                .map_err(|error| format!("{} is not a valid regex: {}", regex, error)),
+            _ => Err("expected a regex such as /^release-/ after =~ or !~".to_string()),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Node, String> {
+        match self.next_token() {
+            Some(Token::LeftParenthesis) => {
+                let node = self.parse_or()?;
+
+                match self.next_token() {
+                    Some(Token::RightParenthesis) => Ok(node),
+                    _ => Err("missing a closing )".to_string()),
+                }
+            }
+            Some(Token::String(value)) => Ok(Node::Literal(Value::String(value))),
+            Some(Token::Identifier(identifier)) => match identifier.as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                name => Variable::from_name(name)
+                    .map(Node::Variable)
+                    .ok_or_else(|| format!("unknown variable {}", name)),
+            },
+            Some(Token::Regex(regex)) => Err(format!(
+                "the regex /{}/ can only be used after =~ or !~",
+                regex
+            )),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of the expression".to_string()),
+        }
+    }
+}
+
+fn type_of(node: &Node) -> Result<Type, String> {
+    let is_string =
+        |node_type: Type| matches!(node_type, Type::String | Type::OptionalString | Type::Null);
+
+    let expect_bool = |node: &Node| match type_of(node)? {
+        Type::Bool => Ok(Type::Bool),
+        other => Err(format!("expected true or false, not {}", other)),
+    };
+
+    match node {
+        Node::Literal(Value::String(_)) => Ok(Type::String),
+        Node::Literal(Value::Null) => Ok(Type::Null),
+        Node::Literal(Value::Bool(_)) => Ok(Type::Bool),
+        Node::Literal(Value::List(_)) => Ok(Type::List),
+        Node::Variable(Variable::Tag) => Ok(Type::OptionalString),
+        Node::Variable(Variable::PullRequestLabels) => Ok(Type::List),
+        Node::Variable(_) => Ok(Type::String),
+        Node::Not(node) => expect_bool(node),
+        Node::And(left, right) | Node::Or(left, right) => {
+            expect_bool(left)?;
+            expect_bool(right)
+        }
+        Node::Equals(left, right) | Node::NotEquals(left, right) => {
+            match (type_of(left)?, type_of(right)?) {
+                (Type::Bool, Type::Bool) => Ok(Type::Bool),
+                (left, right) if is_string(left) && is_string(right) => Ok(Type::Bool),
+                (left, right) => Err(format!("can't compare {} with {}", left, right)),
+            }
+        }
+        Node::Matches(node, _) | Node::NotMatches(node, _) => match type_of(node)? {
+            node_type if is_string(node_type) => Ok(Type::Bool),
+            other => Err(format!("can't match {} against a regex", other)),
+        },
+        Node::Includes(list, item) => match (type_of(list)?, type_of(item)?) {
+            (Type::List, Type::String) => Ok(Type::Bool),
+            (list, item) => Err(format!("can't check if {} includes {}", list, item)),
+        },
+    }
+}
+
+// Only called once the types have been checked
+fn evaluate(node: &Node, build_context: &BuildContext) -> Value {
+    let evaluate_bool = |node: &Node| evaluate(node, build_context) == Value::Bool(true);
+
+    let evaluate_match = |node: &Node, regex: &Regex| match evaluate(node, build_context) {
+        Value::String(value) => regex.is_match(&value),
+        _ => false,
+    };
+
+    match node {
+        Node::Literal(value) => value.clone(),
+        Node::Variable(variable) => match variable {
+            Variable::Branch => Value::String(build_context.branch.clone()),
+            Variable::Tag => build_context
+                .tag
+                .clone()
+                .map(Value::String)
+                .unwrap_or(Value::Null),
+            Variable::Event => Value::String(build_context.event.to_string()),
+            Variable::Message => Value::String(build_context.message.clone()),
+            Variable::Author => Value::String(build_context.author.clone()),
+            Variable::PullRequestLabels => Value::List(build_context.pull_request_labels.clone()),
+        },
+        Node::Not(node) => Value::Bool(!evaluate_bool(node)),
+        Node::And(left, right) => Value::Bool(evaluate_bool(left) && evaluate_bool(right)),
+        Node::Or(left, right) => Value::Bool(evaluate_bool(left) || evaluate_bool(right)),
+        Node::Equals(left, right) => {
+            Value::Bool(evaluate(left, build_context) == evaluate(right, build_context))
+        }
+        Node::NotEquals(left, right) => {
+            Value::Bool(evaluate(left, build_context) != evaluate(right, build_context))
+        }
+        Node::Matches(node, regex) => Value::Bool(evaluate_match(node, regex)),
+        Node::NotMatches(node, regex) => Value::Bool(!evaluate_match(node, regex)),
+        Node::Includes(list, item) => {
+            match (evaluate(list, build_context), evaluate(item, build_context)) {
+                (Value::List(list), Value::String(item)) => Value::Bool(list.contains(&item)),
+                _ => Value::Bool(false),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expression(source: &str) -> Result<Expression, String> {
+        Expression::try_from(source.to_string())
+    }
+
+    fn build_context() -> BuildContext {
+        BuildContext {
+            branch: "master".to_string(),
+            event: "push",
+            message: "Bump serde [skip deploy]".to_string(),
+            author: "Jo Bloggs".to_string(),
+            pull_request_labels: vec!["dependencies".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_evaluate_expressions_against_the_build() {
+        let build_context = build_context();
+
+        let evaluate = |source: &str| expression(source).unwrap().evaluate(&build_context);
+
+        assert!(evaluate(
+            r#"build.branch == "master" && build.event != "pull_request""#
+        ));
+        assert!(!evaluate(r#"build.message !~ /\[skip deploy\]/"#));
+        assert!(evaluate(r#"build.tag == null || build.tag =~ /^v/"#));
+        assert!(evaluate(
+            r#"build.pull_request.labels includes 'dependencies'"#
+        ));
+        assert!(evaluate(
+            r#"!(build.author == "Someone Else") && (false || true)"#
+        ));
+        assert!(evaluate(r#"build.branch =~ /^feature\/|^master$/"#));
+    }
+
+    #[test]
+    fn should_bind_and_tighter_than_or() {
+        let build_context = build_context();
+
+        assert!(expression(r#"build.branch == "master" || false && false"#)
+            .unwrap()
+            .evaluate(&build_context));
+    }
+
+    #[test]
+    fn should_reject_expressions_that_do_not_type_check() {
+        let errors: Vec<String> = vec![
+            r#"build.branch"#,
+            r#"build.branch == true"#,
+            r#"build.pull_request.labels =~ /deploy/"#,
+            r#"build.branch includes "master""#,
+            r#"build.commit == "abc""#,
+            r#"build.branch == /master/"#,
+            r#"build.branch =~ /(/"#,
+            r#"(build.branch == "master""#,
+            r#"build.branch == "master"#,
+        ]
+        .into_iter()
+        .map(|source| expression(source).unwrap_err())
+        .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "Invalid if expression `build.branch`: it has to be true or false, not a string",
+                "Invalid if expression `build.branch == true`: can't compare a string with true or false",
+                "Invalid if expression `build.pull_request.labels =~ /deploy/`: can't match a list against a regex",
+                "Invalid if expression `build.branch includes \"master\"`: can't check if a string includes a string",
+                "Invalid if expression `build.commit == \"abc\"`: unknown variable build.commit",
+                "Invalid if expression `build.branch == /master/`: the regex /master/ can only be used after =~ or !~",
+                "Invalid if expression `build.branch =~ /(/`: ( is not a valid regex: regex parse error:\n    (\n    ^\nerror: unclosed group",
+                "Invalid if expression `(build.branch == \"master\"`: missing a closing )",
+                "Invalid if expression `build.branch == \"master`: missing the closing \"",
+            ]
+        );
+    }
+}
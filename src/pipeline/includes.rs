@@ -0,0 +1,798 @@
+use crate::kubernetes::StepType;
+use regex::{Captures, Regex};
+use serde_derive::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+pub const PIPELINE_FILE_PATH: &str = ".kubesci/pipeline.yml";
+// However they're nested, a pipeline can't pull in more files than this
+pub const MAX_INCLUDED_FILES: usize = 50;
+
+// A file in the build's repo at its commit, or in another repo at a pinned ref
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineSource {
+    pub repo: Option<String>,
+    pub git_ref: Option<String>,
+    pub file: String,
+}
+
+impl PipelineSource {
+    // Paths are relative to the repo of the file including them, so templates can include their
+    // own files
+    fn include(&self, include: &Include) -> PipelineSource {
+        match include {
+            Include::File(file) => PipelineSource {
+                repo: self.repo.clone(),
+                git_ref: self.git_ref.clone(),
+                file: file.to_string(),
+            },
+            Include::Repo {
+                repo,
+                git_ref,
+                file,
+            } => PipelineSource {
+                repo: Some(repo.to_string()),
+                git_ref: Some(git_ref.to_string()),
+                file: file.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for PipelineSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.repo, &self.git_ref) {
+            (Some(repo), Some(git_ref)) => write!(f, "{}@{}:{}", repo, git_ref, self.file),
+            _ => write!(f, "{}", self.file),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+enum Include {
+    File(String),
+    Repo {
+        repo: String,
+        #[serde(rename = "ref")]
+        git_ref: String,
+        file: String,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludeStep {
+    include: Include,
+}
+
+#[derive(Deserialize)]
+struct TemplateStep {
+    template: String,
+    #[serde(default)]
+    with: Mapping,
+}
+
+// What a pipeline file's steps can be before includes and templates are resolved
+#[derive(Debug)]
+enum FileStep {
+    Include(Include),
+    Template {
+        name: String,
+        parameters: Mapping,
+        // Any other keys are set on the step made from the template
+        overrides: Mapping,
+    },
+    Step(Value),
+}
+
+impl TryFrom<Value> for FileStep {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if value.get("include").is_some() {
+            serde_yaml::from_value::<IncludeStep>(value)
+                .map(|include_step| FileStep::Include(include_step.include))
+                .map_err(|error| format!("Invalid include: {}", error))
+        } else if value.get("template").is_some() {
+            let template_step: TemplateStep = serde_yaml::from_value(value.clone())
+                .map_err(|error| format!("Invalid template step: {}", error))?;
+
+            let mut overrides = value.as_mapping().cloned().unwrap_or_default();
+            overrides.remove(&Value::from("template"));
+            overrides.remove(&Value::from("with"));
+
+            Ok(FileStep::Template {
+                name: template_step.template,
+                parameters: template_step.with,
+                overrides,
+            })
+        } else {
+            StepType::try_from(value.clone())?;
+
+            Ok(FileStep::Step(value))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PipelineFile {
+    templates: Option<BTreeMap<String, Value>>,
+    #[serde(default)]
+    steps: Vec<Value>,
+}
+
+enum ResolvedStep {
+    Step(Value),
+    Template {
+        source: PipelineSource,
+        name: String,
+        parameters: Mapping,
+        overrides: Mapping,
+    },
+}
+
+pub struct ResolvedPipeline {
+    pub pipeline: String,
+    // Pipelines without includes or templates are left as they are, so are not worth recording
+    pub has_includes: bool,
+    // A repo's files that are included by the pipeline, so aren't pipelines of their own
+    pub included_files: Vec<PipelineSource>,
+}
+
+pub enum Resolution {
+    Resolved(ResolvedPipeline),
+    // Files that have to be fetched before the pipeline can be resolved
+    Missing(Vec<PipelineSource>),
+}
+
+// Files that couldn't be found are given as `None`. Other repos are fetched with the build repo's
+// installation token, so they can only be included from the one templates repo.
+pub fn resolve_pipeline(
+    files: &HashMap<PipelineSource, Option<String>>,
+    root: &PipelineSource,
+    templates_repo: Option<&str>,
+) -> Result<Resolution, String> {
+    let mut resolver = Resolver {
+        files,
+        templates_repo,
+        templates: BTreeMap::new(),
+        missing: Vec::new(),
+        has_includes: false,
+        included_files: Vec::new(),
+    };
+
+    let resolved_steps = resolver.resolve_steps(root, &mut vec![root.clone()])?;
+
+    if !resolver.missing.is_empty() {
+        return Ok(Resolution::Missing(resolver.missing));
+    }
+
+    let root_pipeline = match files.get(root) {
+        Some(Some(root_pipeline)) => root_pipeline,
+        _ => return Err(format!("{} not found", root)),
+    };
+
+    if !resolver.has_includes {
+        return Ok(Resolution::Resolved(ResolvedPipeline {
+            pipeline: root_pipeline.to_string(),
+            has_includes: false,
+            included_files: Vec::new(),
+        }));
+    }
+
+    let steps = resolved_steps
+        .into_iter()
+        .map(|resolved_step| resolver.expand_template(resolved_step))
+        .collect::<Result<Vec<Value>, String>>()?;
+
+    let mut pipeline: Mapping = serde_yaml::from_str(root_pipeline)
+        .map_err(|error| format!("Invalid {}: {}", root, error))?;
+
+    pipeline.remove(&Value::from("templates"));
+    pipeline.insert(Value::from("steps"), Value::Sequence(steps));
+
+    let included_files = resolver.included_files;
+
+    serde_yaml::to_string(&pipeline)
+        .map(|pipeline| {
+            Resolution::Resolved(ResolvedPipeline {
+                pipeline,
+                has_includes: true,
+                included_files,
+            })
+        })
+        .map_err(|error| error.to_string())
+}
+
+struct Resolver<'a> {
+    files: &'a HashMap<PipelineSource, Option<String>>,
+    templates_repo: Option<&'a str>,
+    templates: BTreeMap<String, (Value, PipelineSource)>,
+    missing: Vec<PipelineSource>,
+    has_includes: bool,
+    included_files: Vec<PipelineSource>,
+}
+
+impl<'a> Resolver<'a> {
+    fn resolve_steps(
+        &mut self,
+        source: &PipelineSource,
+        include_stack: &mut Vec<PipelineSource>,
+    ) -> Result<Vec<ResolvedStep>, String> {
+        let text = match self.files.get(source) {
+            Some(Some(text)) => text,
+            Some(None) => {
+                return Err(format!(
+                    "{} not found, included by {}",
+                    source,
+                    include_chain(&include_stack[..include_stack.len() - 1])
+                ))
+            }
+            None => {
+                if !self.missing.contains(source) {
+                    self.missing.push(source.clone());
+                }
+
+                return Ok(vec![]);
+            }
+        };
+
+        let pipeline_file: PipelineFile =
+            serde_yaml::from_str(text).map_err(|error| format!("Invalid {}: {}", source, error))?;
+
+        // Everything else about the pipeline is set by the file it starts from
+        if include_stack.len() > 1 {
+            let mapping: Mapping = serde_yaml::from_str(text)
+                .map_err(|error| format!("Invalid {}: {}", source, error))?;
+
+            let has_other_keys = mapping
+                .iter()
+                .any(|(key, _)| key.as_str() != Some("steps") && key.as_str() != Some("templates"));
+
+            if has_other_keys {
+                return Err(format!(
+                    "Invalid {}: included files can only have steps and templates",
+                    source
+                ));
+            }
+        }
+
+        for (name, template) in pipeline_file.templates.unwrap_or_default() {
+            match self.templates.get(&name) {
+                Some((existing_template, existing_source)) if existing_template != &template => {
+                    return Err(format!(
+                        "The template {} is defined in both {} and {}",
+                        name, existing_source, source
+                    ));
+                }
+                _ => {
+                    self.templates.insert(name, (template, source.clone()));
+                }
+            }
+        }
+
+        let mut resolved_steps = Vec::new();
+
+        let lines = step_lines(text);
+
+        for (index, step) in pipeline_file.steps.into_iter().enumerate() {
+            let step_error = |error: String| match lines.get(index) {
+                Some(line) => format!(
+                    "Invalid {}: steps[{}] at line {}: {}",
+                    source, index, line, error
+                ),
+                None => format!("Invalid {}: steps[{}]: {}", source, index, error),
+            };
+
+            let step = FileStep::try_from(step).map_err(step_error)?;
+
+            if let FileStep::Include(Include::Repo { repo, .. }) = &step {
+                if Some(repo.as_str()) != self.templates_repo {
+                    return Err(step_error(match self.templates_repo {
+                        Some(templates_repo) => format!(
+                            "Can't include from {}, only from the templates repo {}",
+                            repo, templates_repo
+                        ),
+                        None => format!(
+                            "Can't include from {}, as no templates repo is set up",
+                            repo
+                        ),
+                    }));
+                }
+            }
+
+            match step {
+                FileStep::Include(include) => {
+                    self.has_includes = true;
+
+                    let included_source = source.include(&include);
+
+                    if included_source.repo.is_none()
+                        && !self.included_files.contains(&included_source)
+                    {
+                        self.included_files.push(included_source.clone());
+                    }
+
+                    include_stack.push(included_source.clone());
+
+                    if include_stack[..include_stack.len() - 1].contains(&included_source) {
+                        return Err(format!("Include cycle: {}", include_chain(include_stack)));
+                    }
+
+                    resolved_steps.extend(self.resolve_steps(&included_source, include_stack)?);
+
+                    include_stack.pop();
+                }
+                FileStep::Template {
+                    name,
+                    parameters,
+                    overrides,
+                } => {
+                    self.has_includes = true;
+
+                    resolved_steps.push(ResolvedStep::Template {
+                        source: source.clone(),
+                        name,
+                        parameters,
+                        overrides,
+                    });
+                }
+                FileStep::Step(step) => resolved_steps.push(ResolvedStep::Step(step)),
+            }
+        }
+
+        Ok(resolved_steps)
+    }
+
+    // Templates are expanded once every file is in, so they can be used before the file that
+    // defines them is included
+    fn expand_template(&self, resolved_step: ResolvedStep) -> Result<Value, String> {
+        let (source, name, parameters, overrides) = match resolved_step {
+            ResolvedStep::Step(step) => return Ok(step),
+            ResolvedStep::Template {
+                source,
+                name,
+                parameters,
+                overrides,
+            } => (source, name, parameters, overrides),
+        };
+
+        let (template, _) = self
+            .templates
+            .get(&name)
+            .ok_or_else(|| format!("The template {} used in {} isn't defined", name, source))?;
+
+        let mut step = substitute_parameters(template, &parameters).map_err(|parameter| {
+            format!(
+                "The template {} used in {} needs the parameter {}",
+                name, source, parameter
+            )
+        })?;
+
+        if let Some(step) = step.as_mapping_mut() {
+            for (key, value) in overrides {
+                step.insert(key, value);
+            }
+        }
+
+        StepType::try_from(step.clone()).map_err(|error| {
+            format!(
+                "Invalid step from the template {} used in {}: {}",
+                name, source, error
+            )
+        })?;
+
+        Ok(step)
+    }
+}
+
+// serde_yaml doesn't say where values are, so the lines of the steps are found from where each
+// item under `steps:` starts. Only block sequences are found, flow sequences have no lines.
+fn step_lines(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut in_steps = false;
+    let mut item_indent = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let indent = line.len() - line.trim_start().len();
+        let trimmed_line = line.trim_start();
+
+        if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
+            continue;
+        }
+
+        if indent == 0 && !trimmed_line.starts_with('-') {
+            in_steps = trimmed_line.trim_end() == "steps:";
+            item_indent = None;
+            continue;
+        }
+
+        if in_steps && trimmed_line.starts_with('-') {
+            match item_indent {
+                None => {
+                    item_indent = Some(indent);
+                    lines.push(index + 1);
+                }
+                Some(item_indent) if item_indent == indent => lines.push(index + 1),
+                Some(_) => {}
+            }
+        }
+    }
+
+    lines
+}
+
+fn include_chain(sources: &[PipelineSource]) -> String {
+    sources
+        .iter()
+        .map(PipelineSource::to_string)
+        .collect::<Vec<String>>()
+        .join(" -> ")
+}
+
+// Replaces `{{ parameter }}` in every string, keeping the parameter's type when it's the whole
+// string. Fails with the name of the first parameter that wasn't given.
+fn substitute_parameters(value: &Value, parameters: &Mapping) -> Result<Value, String> {
+    let regex = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap();
+
+    match value {
+        Value::String(string) => {
+            for captures in regex.captures_iter(string) {
+                let parameter = parameters
+                    .get(&Value::from(&captures[1]))
+                    .ok_or_else(|| captures[1].to_string())?;
+
+                if captures.get(0).unwrap().as_str() == string {
+                    return Ok(parameter.clone());
+                }
+            }
+
+            Ok(Value::String(
+                regex
+                    .replace_all(string, |captures: &Captures| {
+                        match parameters.get(&Value::from(&captures[1])) {
+                            Some(Value::String(parameter)) => parameter.to_string(),
+                            Some(Value::Number(parameter)) => parameter.to_string(),
+                            Some(Value::Bool(parameter)) => parameter.to_string(),
+                            _ => "".to_string(),
+                        }
+                    })
+                    .to_string(),
+            ))
+        }
+        Value::Sequence(sequence) => sequence
+            .iter()
+            .map(|value| substitute_parameters(value, parameters))
+            .collect::<Result<Vec<Value>, String>>()
+            .map(Value::Sequence),
+        Value::Mapping(mapping) => {
+            let mut substituted = Mapping::new();
+
+            for (key, value) in mapping {
+                substituted.insert(key.clone(), substitute_parameters(value, parameters)?);
+            }
+
+            Ok(Value::Mapping(substituted))
+        }
+        other => Ok(other.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubernetes::RawPipeline;
+
+    const TEMPLATES_REPO: Option<&str> = Some("org/templates");
+
+    fn templates_source(file: &str) -> PipelineSource {
+        PipelineSource {
+            repo: Some("org/templates".to_string()),
+            git_ref: Some("v1".to_string()),
+            file: file.to_string(),
+        }
+    }
+
+    fn local_source(file: &str) -> PipelineSource {
+        PipelineSource {
+            repo: None,
+            git_ref: None,
+            file: file.to_string(),
+        }
+    }
+
+    fn resolve(
+        files: &HashMap<PipelineSource, Option<String>>,
+    ) -> Result<ResolvedPipeline, String> {
+        match resolve_pipeline(files, &local_source(PIPELINE_FILE_PATH), TEMPLATES_REPO)? {
+            Resolution::Resolved(resolved_pipeline) => Ok(resolved_pipeline),
+            Resolution::Missing(sources) => panic!("Missing {:?}", sources),
+        }
+    }
+
+    #[test]
+    fn should_ask_for_included_files_until_they_are_all_fetched() {
+        let mut files = HashMap::new();
+
+        assert!(matches!(
+            resolve_pipeline(&files, &local_source(PIPELINE_FILE_PATH), TEMPLATES_REPO),
+            Ok(Resolution::Missing(sources)) if sources == vec![local_source(PIPELINE_FILE_PATH)]
+        ));
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some(
+                r#"
+steps:
+  - include: .kubesci/lint.yml
+  - include:
+      repo: org/templates
+      ref: v1
+      file: rust.yml
+"#
+                .to_string(),
+            ),
+        );
+
+        assert!(matches!(
+            resolve_pipeline(&files, &local_source(PIPELINE_FILE_PATH), TEMPLATES_REPO),
+            Ok(Resolution::Missing(sources))
+                if sources == vec![local_source(".kubesci/lint.yml"), templates_source("rust.yml")]
+        ));
+    }
+
+    #[test]
+    fn should_splice_included_steps_and_expand_templates() {
+        let mut files = HashMap::new();
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some(
+                r#"
+timeout_in_minutes: 30
+steps:
+  - include: .kubesci/lint.yml
+  - wait
+  - template: cargo
+    with:
+      command: test
+      toolchain: "1.48"
+    key: cargo-test
+  - include:
+      repo: org/templates
+      ref: v1
+      file: rust.yml
+"#
+                .to_string(),
+            ),
+        );
+        files.insert(
+            local_source(".kubesci/lint.yml"),
+            Some(
+                r#"
+steps:
+  - name: lint
+    image: node
+    commands: ["npm run lint"]
+"#
+                .to_string(),
+            ),
+        );
+        files.insert(
+            templates_source("rust.yml"),
+            Some(
+                r#"
+templates:
+  cargo:
+    name: "cargo {{ command }} on {{ toolchain }}"
+    image: "rust:{{ toolchain }}"
+    commands: ["cargo {{ command }}"]
+"#
+                .to_string(),
+            ),
+        );
+
+        let resolved_pipeline = resolve(&files).unwrap();
+
+        assert!(resolved_pipeline.has_includes);
+        assert_eq!(
+            resolved_pipeline.included_files,
+            vec![local_source(".kubesci/lint.yml")]
+        );
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(&resolved_pipeline.pipeline).unwrap();
+
+        assert_eq!(raw_pipeline.timeout_in_minutes, Some(30));
+
+        let steps: Vec<(&str, &str, Option<&str>)> = raw_pipeline
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                StepType::Step(step) => {
+                    Some((step.name.as_str(), step.image.as_str(), step.key.as_deref()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            steps,
+            vec![
+                ("lint", "node", None),
+                ("cargo test on 1.48", "rust:1.48", Some("cargo-test")),
+            ]
+        );
+        assert_eq!(raw_pipeline.steps.len(), 3);
+    }
+
+    #[test]
+    fn should_leave_pipelines_without_includes_as_they_are() {
+        let pipeline = "steps:\n  - name: build\n    image: some_image\n";
+
+        let mut files = HashMap::new();
+        files.insert(local_source(PIPELINE_FILE_PATH), Some(pipeline.to_string()));
+
+        let resolved_pipeline = resolve(&files).unwrap();
+
+        assert!(!resolved_pipeline.has_includes);
+        assert_eq!(resolved_pipeline.pipeline, pipeline);
+    }
+
+    #[test]
+    fn should_detect_include_cycles() {
+        let mut files = HashMap::new();
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some("steps:\n  - include: a.yml\n".to_string()),
+        );
+        files.insert(
+            local_source("a.yml"),
+            Some("steps:\n  - include: b.yml\n".to_string()),
+        );
+        files.insert(
+            local_source("b.yml"),
+            Some("steps:\n  - include: a.yml\n".to_string()),
+        );
+
+        assert_eq!(
+            resolve(&files).err(),
+            Some("Include cycle: .kubesci/pipeline.yml -> a.yml -> b.yml -> a.yml".to_string())
+        );
+    }
+
+    #[test]
+    fn should_say_which_file_and_line_is_invalid() {
+        let mut files = HashMap::new();
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some(
+                "steps:\n  - include:\n      repo: org/templates\n      ref: v1\n      file: rust.yml\n"
+                    .to_string(),
+            ),
+        );
+        files.insert(
+            templates_source("rust.yml"),
+            Some("steps:\n  - name: test\n    image: rust\n  - name: build\n".to_string()),
+        );
+
+        let error = resolve(&files).err().unwrap();
+
+        assert_eq!(
+            error,
+            "Invalid org/templates@v1:rust.yml: steps[1] at line 4: Invalid step build: missing field `image`"
+        );
+    }
+
+    #[test]
+    fn should_only_include_from_the_templates_repo() {
+        let mut files = HashMap::new();
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some(
+                "steps:\n  - include:\n      repo: someone/else\n      ref: v1\n      file: rust.yml\n"
+                    .to_string(),
+            ),
+        );
+
+        assert_eq!(
+            resolve(&files).err(),
+            Some(
+                "Invalid .kubesci/pipeline.yml: steps[0] at line 2: Can't include from someone/else, \
+                 only from the templates repo org/templates"
+                    .to_string()
+            )
+        );
+
+        assert_eq!(
+            resolve_pipeline(&files, &local_source(PIPELINE_FILE_PATH), None).err(),
+            Some(
+                "Invalid .kubesci/pipeline.yml: steps[0] at line 2: Can't include from someone/else, \
+                 as no templates repo is set up"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_fail_templates_without_their_parameters() {
+        let mut files = HashMap::new();
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some(
+                r#"
+templates:
+  deploy:
+    name: "deploy to {{ environment }}"
+    image: deployer
+steps:
+  - template: deploy
+"#
+                .to_string(),
+            ),
+        );
+
+        assert_eq!(
+            resolve(&files).err(),
+            Some(
+                "The template deploy used in .kubesci/pipeline.yml needs the parameter environment"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_report_missing_included_files() {
+        let mut files = HashMap::new();
+
+        files.insert(
+            local_source(PIPELINE_FILE_PATH),
+            Some("steps:\n  - include: missing.yml\n".to_string()),
+        );
+        files.insert(local_source("missing.yml"), None);
+
+        assert_eq!(
+            resolve(&files).err(),
+            Some("missing.yml not found, included by .kubesci/pipeline.yml".to_string())
+        );
+    }
+
+    #[test]
+    fn should_find_the_line_of_each_step() {
+        let text = r#"
+env:
+  - name: A
+    value: b
+steps:
+  # Checks first
+  - name: lint
+    commands:
+      - npm run lint
+  - wait
+timeout_in_minutes: 10
+"#;
+
+        assert_eq!(step_lines(text), vec![7, 10]);
+    }
+
+    #[test]
+    fn should_keep_parameter_types_when_they_are_the_whole_value() {
+        let mut parameters = Mapping::new();
+        parameters.insert(Value::from("timeout"), Value::from(10));
+
+        assert_eq!(
+            substitute_parameters(&Value::from("{{ timeout }}"), &parameters),
+            Ok(Value::from(10))
+        );
+        assert_eq!(
+            substitute_parameters(&Value::from("{{timeout}} minutes"), &parameters),
+            Ok(Value::from("10 minutes"))
+        );
+    }
+}
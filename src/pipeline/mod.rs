@@ -1,18 +1,90 @@
+pub mod dependencies;
+pub mod discovery;
+pub mod expressions;
+pub mod includes;
 pub mod steps_filter;
+pub mod uploads;
 
+use crate::artifacts::{
+    artifact_token, cache_token, pipeline_upload_token, uri_encode, ArtifactStore, PipelineUpload,
+};
 use crate::github::client::auth::GithubAuthorisationClient;
-use crate::github::client::installation::GithubInstallationClient;
-use crate::kubernetes::generate::generate_pod_for_steps;
+use crate::github::client::installation::{GetCheckRunResponse, GithubInstallationClient};
+use crate::github::pull_request::{github_event, PullRequest};
+use crate::kubernetes::artifacts::ArtifactsEndpoint;
+use crate::kubernetes::cache::CacheEndpoint;
+use crate::kubernetes::generate::{
+    generate_git_credentials_secret, generate_pipeline_upload_tokens_secret, generate_pod_for_steps,
+};
+use crate::kubernetes::resources::ResourceValues;
+use crate::kubernetes::scheduling::group_by_placement;
+use crate::kubernetes::services::separate_steps_with_services;
 use crate::kubernetes::RawPipeline;
-use crate::kubernetes::StepWithCheckRunId;
-use crate::pipeline::steps_filter::filter;
-use either::Either::{Left, Right};
-use k8s_openapi::api::core::v1::Pod;
+use crate::kubernetes::{Block, Step, StepWithCheckRunId};
+use crate::pipeline::dependencies::{
+    dependency_state, locate_keyed_steps, validate_dependencies, DependencyState,
+};
+use crate::pipeline::discovery::{discover_pipeline_paths, PipelinePath};
+use crate::pipeline::includes::{
+    resolve_pipeline, PipelineSource, Resolution, ResolvedPipeline, MAX_INCLUDED_FILES,
+};
+use crate::pipeline::steps_filter::{
+    filter, filter_dependent_steps, filter_skipped_steps, filter_step_sections, BuildContext,
+};
+use crate::pipeline::uploads::{insert_uploaded_steps, pipeline_build, PipelineUploadEndpoint};
+use chrono::{DateTime, Duration, Utc};
+use either::{
+    Either,
+    Either::{Left, Right},
+};
+use k8s_openapi::api::core::v1::{Pod, Secret};
 use kube::{
-    api::{Api, Meta, PostParams},
+    api::{Api, DeleteParams, Meta, PostParams},
     Client,
 };
 use log::info;
+use std::collections::HashMap;
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use vec1::Vec1;
+
+// Where a step lives in the pipeline: either a step section separated by waits, the position of
+// a step with dependencies, which runs on its own as soon as they've succeeded, or the position of
+// a step its filters skipped, which never runs in this build
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StepLocation {
+    Section(usize),
+    Dependent(usize),
+    Filtered(usize),
+}
+
+impl fmt::Display for StepLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepLocation::Section(step_section) => write!(f, "{}", step_section),
+            StepLocation::Dependent(index) => write!(f, "d{}", index),
+            StepLocation::Filtered(index) => write!(f, "f{}", index),
+        }
+    }
+}
+
+impl FromStr for StepLocation {
+    type Err = ParseIntError;
+
+    fn from_str(step_location: &str) -> Result<Self, Self::Err> {
+        if let Some(index) = step_location.strip_prefix('d') {
+            Ok(StepLocation::Dependent(index.parse()?))
+        } else if let Some(index) = step_location.strip_prefix('f') {
+            Ok(StepLocation::Filtered(index.parse()?))
+        } else {
+            Ok(StepLocation::Section(step_location.parse()?))
+        }
+    }
+}
+
+const INVALID_PIPELINE_CHECK_RUN_NAME: &str = "Invalid pipeline";
+const RESOLVED_PIPELINE_CHECK_RUN_NAME: &str = "Resolved pipeline";
 
 #[derive(Clone)]
 pub struct PipelineService {
@@ -20,9 +92,99 @@ pub struct PipelineService {
     pub application_id: String,
     pub namespace: String,
     pub github_base_url: String,
+    pub github_url: String,
+    pub max_step_resources: ResourceValues,
+    // Where step pods can reach the controller, to upload and download artifacts
+    pub kubesci_url: String,
+    pub webhook_secret: String,
+    // Also holds the steps uploaded by running steps, which are part of the pipeline
+    pub artifact_store: ArtifactStore,
+    // The only other repo pipelines can include files from
+    pub templates_repo: Option<String>,
 }
 
 impl PipelineService {
+    // Starts every pipeline in the repo on its own, leaving out files another pipeline includes.
+    // One invalid pipeline doesn't stop the others from starting.
+    pub async fn start_pipelines(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        pull_request: Option<&PullRequest>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        let files = github_installation_client.list_files(commit_sha).await?;
+
+        // The tree can be truncated for big repos, which shouldn't lose the main pipeline
+        let mut pipeline_paths = discover_pipeline_paths(&files);
+        if !pipeline_paths.iter().any(PipelinePath::is_root) {
+            pipeline_paths.insert(0, PipelinePath::root());
+        }
+
+        let mut included_files = Vec::new();
+        let mut resolved_pipelines = Vec::new();
+
+        // Each pipeline is resolved once, both to find what it includes and to start it
+        for pipeline_path in pipeline_paths {
+            // A pipeline that can't be fetched is reported with the others, not instead of them
+            let maybe_resolved_pipeline = self
+                .get_pipeline(&github_installation_client, commit_sha, &pipeline_path)
+                .await
+                .map_err(|error| error.to_string());
+
+            if let Ok(Some(Ok(resolved_pipeline))) = &maybe_resolved_pipeline {
+                included_files.extend(resolved_pipeline.included_files.iter().cloned());
+            }
+
+            resolved_pipelines.push((pipeline_path, maybe_resolved_pipeline));
+        }
+
+        let mut first_error = None;
+
+        for (pipeline_path, maybe_resolved_pipeline) in resolved_pipelines
+            .into_iter()
+            .filter(|(pipeline_path, _)| !included_files.contains(&pipeline_path.source()))
+        {
+            let result = match maybe_resolved_pipeline {
+                Ok(maybe_resolved_pipeline) => self
+                    .start_resolved_step_section(
+                        &github_installation_client,
+                        maybe_resolved_pipeline,
+                        installation_id,
+                        repo_name,
+                        commit_sha,
+                        branch_name,
+                        None,
+                        pull_request,
+                        &pipeline_path,
+                        None,
+                    )
+                    .await
+                    .map_err(|error| error.to_string()),
+                Err(message) => Err(message),
+            };
+
+            if let Err(message) = result {
+                info!(
+                    "Couldn't start the pipeline {}: {}",
+                    pipeline_path.path, message
+                );
+                first_error = first_error.or(Some(message));
+            }
+        }
+
+        match first_error {
+            Some(message) => Err(message.into()),
+            None => Ok(()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_step_section(
         &self,
         installation_id: u32,
@@ -30,81 +192,1782 @@ impl PipelineService {
         commit_sha: &str,
         branch_name: &str,
         step_section: Option<usize>,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        pipeline_deadline: Option<DateTime<Utc>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let github_authorisation_client =
-            GithubAuthorisationClient::new(&self.github_private_key, &self.application_id)?;
-
-        let installation_access_token = github_authorisation_client
-            .get_installation_access_token(installation_id)
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
             .await?;
 
-        let github_installation_client = GithubInstallationClient {
-            repository_name: repo_name,
-            github_installation_token: installation_access_token,
-            base_url: &self.github_base_url,
+        let maybe_resolved_pipeline = match step_section {
+            None => {
+                self.get_pipeline(&github_installation_client, commit_sha, pipeline_path)
+                    .await?
+            }
+            Some(_) => {
+                self.get_build_pipeline(
+                    &github_installation_client,
+                    commit_sha,
+                    &pipeline_build(pipeline_path, pull_request),
+                    pipeline_path,
+                )
+                .await?
+            }
         };
 
-        let maybe_raw_pipeline = github_installation_client
-            .get_pipeline_file(commit_sha)
+        self.start_resolved_step_section(
+            &github_installation_client,
+            maybe_resolved_pipeline,
+            installation_id,
+            repo_name,
+            commit_sha,
+            branch_name,
+            step_section,
+            pull_request,
+            pipeline_path,
+            pipeline_deadline,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn start_resolved_step_section(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        maybe_resolved_pipeline: Option<Result<ResolvedPipeline, String>>,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_section: Option<usize>,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        pipeline_deadline: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if step_section.is_none() {
+            self.record_changed_files(
+                github_installation_client,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
             .await?;
+        }
 
-        if let Some(raw_pipeline) = maybe_raw_pipeline {
-            let raw_pipeline: RawPipeline = serde_yaml::from_str(&raw_pipeline)?;
+        let build_context = self
+            .build_context(
+                github_installation_client,
+                branch_name,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        let build = pipeline_build(pipeline_path, pull_request);
+
+        // Includes can point at branches, which can move on while the build runs, so they're
+        // resolved once when the build starts and kept for the rest of its step sections
+        if step_section.is_none() {
+            if let Some(Ok(resolved_pipeline)) = &maybe_resolved_pipeline {
+                self.artifact_store
+                    .put_resolved_pipeline(
+                        repo_name,
+                        commit_sha,
+                        &build,
+                        &resolved_pipeline.pipeline,
+                    )
+                    .await?;
+            }
+        }
+
+        if let Some(resolved_pipeline) = maybe_resolved_pipeline {
+            // Steps uploaded by an earlier run of the build would otherwise run again
+            if step_section.is_none() {
+                self.artifact_store
+                    .delete_pipeline_uploads(repo_name, commit_sha, &build)
+                    .await?;
+            }
+
+            let uploads = self
+                .artifact_store
+                .get_pipeline_uploads(repo_name, commit_sha, &build)
+                .await?;
+
+            let maybe_pipeline = resolved_pipeline.and_then(|resolved_pipeline| {
+                parse_pipeline(
+                    &resolved_pipeline.pipeline,
+                    &uploads,
+                    &build_context,
+                    &self.max_step_resources,
+                )
+                .map(|raw_pipeline| (raw_pipeline, resolved_pipeline))
+                .map_err(|error| error.to_string())
+            });
+
+            let (raw_pipeline, resolved_pipeline) = match maybe_pipeline {
+                Ok(pipeline) => pipeline,
+                // Report an invalid pipeline once, when it's first started, rather than failing silently
+                Err(message) => {
+                    if step_section.is_none() {
+                        github_installation_client
+                            .create_failed_check_run(
+                                &pipeline_path.check_run_name(INVALID_PIPELINE_CHECK_RUN_NAME),
+                                commit_sha,
+                                &step_identifier(
+                                    pipeline_path,
+                                    &StepLocation::Section(0),
+                                    pull_request,
+                                ),
+                                &message,
+                            )
+                            .await?;
+                    }
+
+                    return Err(message.into());
+                }
+            };
+
+            // Recorded on the build, as the pipeline that ran isn't in any one file
+            if step_section.is_none() && resolved_pipeline.has_includes {
+                github_installation_client
+                    .create_successful_check_run(
+                        &pipeline_path.check_run_name(RESOLVED_PIPELINE_CHECK_RUN_NAME),
+                        commit_sha,
+                        &step_identifier(pipeline_path, &StepLocation::Section(0), pull_request),
+                        "The pipeline with its includes and templates resolved",
+                        &resolved_pipeline_text(&resolved_pipeline.pipeline),
+                    )
+                    .await?;
+            }
+
+            let pipeline_deadline = resolve_pipeline_deadline(&raw_pipeline, pipeline_deadline);
 
             let next_step_section = step_section
                 .map(|previous_step_section| previous_step_section + 1)
                 .unwrap_or_else(|| 0);
 
-            let maybe_steps = filter(&raw_pipeline.steps, branch_name, next_step_section);
+            let maybe_steps = filter(&raw_pipeline.steps, &build_context, next_step_section);
 
-            if let Some(Right(steps)) = maybe_steps {
-                let mut steps_with_check_run_id: Vec<StepWithCheckRunId> =
-                    Vec::with_capacity(steps.len());
+            if let Some(steps) = maybe_steps {
+                self.run_step_section(
+                    github_installation_client,
+                    steps,
+                    installation_id,
+                    repo_name,
+                    commit_sha,
+                    branch_name,
+                    &StepLocation::Section(next_step_section),
+                    pull_request,
+                    pipeline_path,
+                    false,
+                    pipeline_deadline,
+                )
+                .await?;
+            }
 
-                for step in steps {
-                    let checkrun_response = github_installation_client
-                        .create_check_run(&step.name, commit_sha)
+            if step_section.is_none() {
+                for skipped_step in filter_skipped_steps(&raw_pipeline.steps, &build_context) {
+                    github_installation_client
+                        .create_skipped_check_run(
+                            &pipeline_path.check_run_name(skipped_step.name),
+                            commit_sha,
+                            &step_identifier(pipeline_path, &skipped_step.location, pull_request),
+                            &skipped_step.reason.summary(),
+                        )
                         .await?;
-
-                    steps_with_check_run_id.push(StepWithCheckRunId {
-                        step,
-                        check_run_id: checkrun_response.id,
-                    });
                 }
+            }
 
-                let namespace = &self.namespace;
-
-                let pod_deployment = generate_pod_for_steps(
-                    &steps_with_check_run_id,
-                    commit_sha,
-                    repo_name,
-                    &namespace,
+            // Dependencies filtered out by branch or event never run, so steps can be ready upfront
+            if step_section.is_none() {
+                self.run_ready_dependent_steps(
+                    github_installation_client,
+                    &raw_pipeline,
+                    &build_context,
                     installation_id,
-                    next_step_section,
+                    repo_name,
+                    commit_sha,
                     branch_name,
-                );
+                    pull_request,
+                    pipeline_path,
+                    pipeline_deadline,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn start_dependent_steps(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        pipeline_deadline: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        let build_context = self
+            .build_context(
+                &github_installation_client,
+                branch_name,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
 
-                let client = Client::try_default().await?;
+        let maybe_raw_pipeline = self
+            .get_raw_pipeline(
+                &github_installation_client,
+                repo_name,
+                commit_sha,
+                &build_context,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
 
-                let pods: Api<Pod> = Api::namespaced(client, &namespace);
+        if let Some(raw_pipeline) = maybe_raw_pipeline {
+            let pipeline_deadline = resolve_pipeline_deadline(&raw_pipeline, pipeline_deadline);
 
-                info!("Creating Pod for checks...");
+            self.run_ready_dependent_steps(
+                &github_installation_client,
+                &raw_pipeline,
+                &build_context,
+                installation_id,
+                repo_name,
+                commit_sha,
+                branch_name,
+                pull_request,
+                pipeline_path,
+                pipeline_deadline,
+            )
+            .await?;
+        }
+        Ok(())
+    }
 
-                let pp = PostParams::default();
-                match pods.create(&pp, &pod_deployment).await {
-                    Ok(o) => {
-                        let name = Meta::name(&o);
-                        info!("Created pod: {}!", name);
-                    }
-                    Err(kube::Error::Api(ae)) => assert_eq!(ae.code, 409),
-                    Err(e) => return Err(e.into()),
+    #[allow(clippy::too_many_arguments)]
+    pub async fn unblock_step(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_location: StepLocation,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        check_run_id: i64,
+        check_run_name: &str,
+        started_at: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        github_installation_client
+            .unblock_block_step(check_run_id, check_run_name, started_at)
+            .await?;
+
+        if let StepLocation::Section(step_section) = step_location {
+            self.start_step_section(
+                installation_id,
+                repo_name,
+                commit_sha,
+                branch_name,
+                Some(step_section),
+                pull_request,
+                pipeline_path,
+                None,
+            )
+            .await?;
+        }
+
+        self.start_dependent_steps(
+            installation_id,
+            repo_name,
+            commit_sha,
+            branch_name,
+            pull_request,
+            pipeline_path,
+            None,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn skip_step_sections_after_failure(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        failed_step_section: usize,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        pipeline_deadline: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        let build_context = self
+            .build_context(
+                &github_installation_client,
+                branch_name,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        let maybe_raw_pipeline = self
+            .get_raw_pipeline(
+                &github_installation_client,
+                repo_name,
+                commit_sha,
+                &build_context,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        if let Some(raw_pipeline) = maybe_raw_pipeline {
+            let pipeline_deadline = resolve_pipeline_deadline(&raw_pipeline, pipeline_deadline);
+
+            let remaining_step_sections = filter_step_sections(&raw_pipeline.steps, &build_context)
+                .into_iter()
+                .enumerate()
+                .skip(failed_step_section + 1);
+
+            for (step_section_index, step_section) in remaining_step_sections {
+                if step_section.continue_on_failure {
+                    info!(
+                        "Continuing on to step section {} despite failure...",
+                        step_section_index
+                    );
+
+                    return self
+                        .run_step_section(
+                            &github_installation_client,
+                            step_section.steps,
+                            installation_id,
+                            repo_name,
+                            commit_sha,
+                            branch_name,
+                            &StepLocation::Section(step_section_index),
+                            pull_request,
+                            pipeline_path,
+                            false,
+                            pipeline_deadline,
+                        )
+                        .await;
                 }
-            } else if let Some(Left(block)) = maybe_steps {
-                github_installation_client
-                    .create_block_step(&block.name, commit_sha, next_step_section)
+
+                info!("Skipping step section {}...", step_section_index);
+
+                let names: Vec<&str> = match &step_section.steps {
+                    Left(block) => vec![&block.name],
+                    Right(steps) => steps.iter().map(|step| step.name.as_str()).collect(),
+                };
+
+                for name in names {
+                    github_installation_client
+                        .create_skipped_check_run(
+                            &pipeline_path.check_run_name(name),
+                            commit_sha,
+                            &step_identifier(
+                                pipeline_path,
+                                &StepLocation::Section(step_section_index),
+                                pull_request,
+                            ),
+                            "Skipped because a previous step failed.",
+                        )
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn rerun_step(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_location: StepLocation,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        step_name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        let build_context = self
+            .build_context(
+                &github_installation_client,
+                branch_name,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        let maybe_raw_pipeline = self
+            .get_raw_pipeline(
+                &github_installation_client,
+                repo_name,
+                commit_sha,
+                &build_context,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        if let Some(raw_pipeline) = maybe_raw_pipeline {
+            let pipeline_deadline = resolve_pipeline_deadline(&raw_pipeline, None);
+
+            let maybe_step = find_step(
+                &raw_pipeline,
+                &build_context,
+                step_location,
+                pipeline_path.step_name(step_name),
+            );
+
+            match maybe_step {
+                Some(step) => {
+                    info!("Rerunning step {}...", step_name);
+
+                    self.run_step_section(
+                        &github_installation_client,
+                        Right(vec1![step]),
+                        installation_id,
+                        repo_name,
+                        commit_sha,
+                        branch_name,
+                        &step_location,
+                        pull_request,
+                        pipeline_path,
+                        true,
+                        pipeline_deadline,
+                    )
                     .await?;
+                }
+                None => {
+                    return Err(
+                        format!("Unable to find step {} at {}", step_name, step_location).into(),
+                    )
+                }
             }
         }
         Ok(())
     }
+
+    // Reruns a failed step in a new pod under the same check run, if its retry rules allow it
+    #[allow(clippy::too_many_arguments)]
+    pub async fn retry_step(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_location: StepLocation,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        step_rerun: bool,
+        pipeline_deadline: Option<DateTime<Utc>>,
+        step_name: &str,
+        check_run_id: u32,
+        attempt: u32,
+        exit_code: Option<i32>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        let build_context = self
+            .build_context(
+                &github_installation_client,
+                branch_name,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        let maybe_raw_pipeline = self
+            .get_raw_pipeline(
+                &github_installation_client,
+                repo_name,
+                commit_sha,
+                &build_context,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        if let Some(raw_pipeline) = maybe_raw_pipeline {
+            let maybe_step = find_step(
+                &raw_pipeline,
+                &build_context,
+                step_location,
+                pipeline_path.step_name(step_name),
+            )
+            .filter(|step| {
+                step.retry
+                    .as_ref()
+                    .map(|retry| retry.should_retry(attempt, exit_code))
+                    .unwrap_or(false)
+            });
+
+            if let Some(step) = maybe_step {
+                info!("Retrying step {}, attempt {}...", step_name, attempt + 1);
+
+                let check_run = github_installation_client
+                    .get_check_run(check_run_id as i32)
+                    .await?;
+
+                let steps_with_check_run_id = vec![StepWithCheckRunId {
+                    step,
+                    check_run_id,
+                    attempt: attempt + 1,
+                }];
+
+                self.create_step_pod(
+                    &github_installation_client,
+                    &steps_with_check_run_id,
+                    installation_id,
+                    repo_name,
+                    commit_sha,
+                    branch_name,
+                    &step_location,
+                    pull_request,
+                    pipeline_path,
+                    step_rerun,
+                    pipeline_deadline,
+                    check_run.check_suite.id,
+                )
+                .await?;
+
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // A step section can be spread over several pods once steps are retried, so whether it
+    // succeeded comes from the check runs rather than any one pod
+    #[allow(clippy::too_many_arguments)]
+    pub async fn finish_step_section(
+        &self,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_section: usize,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        pipeline_deadline: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let github_installation_client = self
+            .github_installation_client(installation_id, repo_name)
+            .await?;
+
+        let build_context = self
+            .build_context(
+                &github_installation_client,
+                branch_name,
+                commit_sha,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        let maybe_raw_pipeline = self
+            .get_raw_pipeline(
+                &github_installation_client,
+                repo_name,
+                commit_sha,
+                &build_context,
+                pull_request,
+                pipeline_path,
+            )
+            .await?;
+
+        let raw_pipeline = match maybe_raw_pipeline {
+            Some(raw_pipeline) => raw_pipeline,
+            None => return Ok(()),
+        };
+
+        let step_names: Vec<&str> = filter(&raw_pipeline.steps, &build_context, step_section)
+            .and_then(|steps| steps.right())
+            .map(|steps| steps.iter().map(|step| step.name.as_str()).collect())
+            .unwrap_or_default();
+
+        let identifier = step_identifier(
+            pipeline_path,
+            &StepLocation::Section(step_section),
+            pull_request,
+        );
+
+        let check_runs = github_installation_client
+            .list_check_runs(commit_sha)
+            .await?;
+
+        let section_check_runs: Vec<Option<&GetCheckRunResponse>> = step_names
+            .iter()
+            .map(|step_name| {
+                check_runs.iter().find(|check_run| {
+                    check_run.name == pipeline_path.check_run_name(step_name)
+                        && check_run.external_id.as_deref() == Some(identifier.as_str())
+                })
+            })
+            .collect();
+
+        // Every pod of a section finishes it, and a pod can be finished again after a restart, so
+        // only the first to find it finished moves the pipeline on
+        let section_check_run_ids: Vec<i64> = section_check_runs
+            .iter()
+            .flatten()
+            .map(|check_run| check_run.id)
+            .collect();
+
+        if has_moved_past_section(
+            &check_runs,
+            &section_check_run_ids,
+            step_section,
+            pull_request,
+            pipeline_path,
+        ) {
+            info!(
+                "The pipeline has already moved past step section {}",
+                step_section
+            );
+
+            return Ok(());
+        }
+
+        let conclusions: Vec<Option<&str>> = section_check_runs
+            .iter()
+            .map(|maybe_check_run| {
+                maybe_check_run
+                    .filter(|check_run| check_run.status == "completed")
+                    .and_then(|check_run| check_run.conclusion.as_deref())
+            })
+            .collect();
+
+        if conclusions.iter().any(Option::is_none) {
+            info!(
+                "Step section {} is still waiting on retried steps",
+                step_section
+            );
+
+            return Ok(());
+        }
+
+        let succeeded = conclusions
+            .iter()
+            .all(|conclusion| matches!(conclusion, Some("success") | Some("neutral")));
+
+        self.artifact_store
+            .finish_pipeline_upload_section(
+                repo_name,
+                commit_sha,
+                &pipeline_build(pipeline_path, pull_request),
+                step_section,
+            )
+            .await?;
+
+        if succeeded
+            && !has_earlier_section_failed(&check_runs, step_section, pull_request, pipeline_path)
+        {
+            self.start_step_section(
+                installation_id,
+                repo_name,
+                commit_sha,
+                branch_name,
+                Some(step_section),
+                pull_request,
+                pipeline_path,
+                pipeline_deadline,
+            )
+            .await
+        } else {
+            self.skip_step_sections_after_failure(
+                installation_id,
+                repo_name,
+                commit_sha,
+                branch_name,
+                step_section,
+                pull_request,
+                pipeline_path,
+                pipeline_deadline,
+            )
+            .await
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn run_step_section(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        steps: Either<&Block, Vec1<&Step>>,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_location: &StepLocation,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        step_rerun: bool,
+        pipeline_deadline: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let identifier = step_identifier(pipeline_path, step_location, pull_request);
+
+        match steps {
+            Right(steps) => {
+                let mut steps_with_check_run_id: Vec<StepWithCheckRunId> =
+                    Vec::with_capacity(steps.len());
+                let mut build_number = 0;
+
+                for step in steps {
+                    let checkrun_response = github_installation_client
+                        .create_check_run(
+                            &pipeline_path.check_run_name(&step.name),
+                            commit_sha,
+                            &identifier,
+                        )
+                        .await?;
+
+                    build_number = checkrun_response.check_suite.id;
+
+                    steps_with_check_run_id.push(StepWithCheckRunId {
+                        step,
+                        check_run_id: checkrun_response.id,
+                        attempt: 1,
+                    });
+                }
+
+                // Steps asking for different nodes can't share a pod, so the section is split
+                let pod_steps =
+                    group_by_placement(steps_with_check_run_id, |step_with_check_run_id| {
+                        &step_with_check_run_id.step.placement
+                    });
+
+                let pod_steps = separate_steps_with_services(pod_steps, |step_with_check_run_id| {
+                    step_with_check_run_id.step.services.is_some()
+                });
+
+                for steps_with_check_run_id in pod_steps {
+                    self.create_step_pod(
+                        github_installation_client,
+                        &steps_with_check_run_id,
+                        installation_id,
+                        repo_name,
+                        commit_sha,
+                        branch_name,
+                        step_location,
+                        pull_request,
+                        pipeline_path,
+                        step_rerun,
+                        pipeline_deadline,
+                        build_number,
+                    )
+                    .await?;
+                }
+            }
+            Left(block) => {
+                github_installation_client
+                    .create_block_step(
+                        &pipeline_path.check_run_name(&block.name),
+                        commit_sha,
+                        &identifier,
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_step_pod(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        steps_with_check_run_id: &[StepWithCheckRunId<'_>],
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        step_location: &StepLocation,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        step_rerun: bool,
+        pipeline_deadline: Option<DateTime<Utc>>,
+        build_number: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let namespace = &self.namespace;
+
+        let artifacts_endpoint = ArtifactsEndpoint {
+            url: format!(
+                "{}/artifacts/{}/{}",
+                self.kubesci_url,
+                repo_name.replace("/", "."),
+                commit_sha
+            ),
+            token: artifact_token(&self.webhook_secret, repo_name, commit_sha),
+        };
+
+        // Only looked up for pods that restore caches
+        let fallback_branch = if steps_with_check_run_id
+            .iter()
+            .any(|step_with_check_run_id| step_with_check_run_id.step.cache.is_some())
+        {
+            Some(github_installation_client.get_default_branch().await?)
+                .filter(|default_branch| default_branch != branch_name)
+        } else {
+            None
+        };
+
+        let cache_endpoint = CacheEndpoint {
+            url: format!(
+                "{}/caches/{}/{}",
+                self.kubesci_url,
+                repo_name.replace("/", "."),
+                uri_encode(branch_name, true)
+            ),
+            token: cache_token(&self.webhook_secret, repo_name, branch_name),
+            fallback_branch,
+        };
+
+        let build = pipeline_build(pipeline_path, pull_request);
+
+        let pipeline_upload_endpoint = PipelineUploadEndpoint {
+            url: format!(
+                "{}/pipeline-uploads/{}/{}/{}",
+                self.kubesci_url,
+                repo_name.replace("/", "."),
+                commit_sha,
+                build
+            ),
+            tokens: match step_location {
+                StepLocation::Section(step_section) => steps_with_check_run_id
+                    .iter()
+                    .map(|step_with_check_run_id| {
+                        let step_name = &step_with_check_run_id.step.name;
+
+                        let token = pipeline_upload_token(
+                            &self.webhook_secret,
+                            repo_name,
+                            commit_sha,
+                            &build,
+                            *step_section,
+                            &hex::encode(step_name),
+                        );
+
+                        (step_name.to_string(), token)
+                    })
+                    .collect(),
+                StepLocation::Dependent(_) | StepLocation::Filtered(_) => HashMap::new(),
+            },
+        };
+
+        let pod_deployment = generate_pod_for_steps(
+            steps_with_check_run_id,
+            commit_sha,
+            repo_name,
+            namespace,
+            installation_id,
+            step_location,
+            branch_name,
+            &self.github_url,
+            pull_request,
+            pipeline_path,
+            step_rerun,
+            pipeline_deadline,
+            build_number,
+            &artifacts_endpoint,
+            &cache_endpoint,
+            &pipeline_upload_endpoint,
+        );
+
+        let client = Client::try_default().await?;
+
+        let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+        let secrets: Api<Secret> = Api::namespaced(client, namespace);
+
+        let pp = PostParams::default();
+
+        info!("Creating Pod for checks...");
+
+        let o = pods.create(&pp, &pod_deployment).await?;
+
+        let name = Meta::name(&o);
+        info!("Created pod: {}!", name);
+
+        // The pod waits for its git credentials before cloning. They're created owned by the pod,
+        // so Kubernetes cleans up the secret, and the token in it, along with the pod.
+        let git_credentials_secret = generate_git_credentials_secret(
+            &o,
+            &self.github_url,
+            &github_installation_client.github_installation_token,
+        );
+
+        info!("Creating git credentials secret for pod...");
+
+        if let Err(error) = secrets.create(&pp, &git_credentials_secret).await {
+            pods.delete(&name, &DeleteParams::default()).await?;
+
+            return Err(error.into());
+        }
+
+        // Steps wait for their upload tokens in the same way
+        let maybe_pipeline_upload_tokens_secret = generate_pipeline_upload_tokens_secret(
+            &o,
+            steps_with_check_run_id,
+            &pipeline_upload_endpoint,
+        );
+
+        if let Some(pipeline_upload_tokens_secret) = maybe_pipeline_upload_tokens_secret {
+            info!("Creating pipeline upload tokens secret for pod...");
+
+            if let Err(error) = secrets.create(&pp, &pipeline_upload_tokens_secret).await {
+                pods.delete(&name, &DeleteParams::default()).await?;
+
+                return Err(error.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Starts the steps whose dependencies have all succeeded, and skips those with a dependency that
+    // didn't. Check runs are the record of what has already run for the commit.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_ready_dependent_steps(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        raw_pipeline: &RawPipeline,
+        build_context: &BuildContext,
+        installation_id: u32,
+        repo_name: &str,
+        commit_sha: &str,
+        branch_name: &str,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+        pipeline_deadline: Option<DateTime<Utc>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut remaining_dependent_steps =
+            filter_dependent_steps(&raw_pipeline.steps, build_context);
+
+        if remaining_dependent_steps.is_empty() {
+            return Ok(());
+        }
+
+        let keyed_steps = locate_keyed_steps(&raw_pipeline.steps, build_context);
+
+        let check_runs = github_installation_client
+            .list_check_runs(commit_sha)
+            .await?;
+
+        let find_check_run = |name: &str, step_location: &StepLocation| {
+            let identifier = step_identifier(pipeline_path, step_location, pull_request);
+
+            check_runs.iter().find(|check_run| {
+                check_run.name == pipeline_path.check_run_name(name)
+                    && check_run.external_id.as_deref() == Some(identifier.as_str())
+            })
+        };
+
+        // Steps started or skipped in this pass, which won't be in the check runs fetched above
+        let mut handled_steps: HashMap<StepLocation, DependencyState> = HashMap::new();
+
+        loop {
+            let remaining_count = remaining_dependent_steps.len();
+            let mut pending_dependent_steps = Vec::new();
+
+            for dependent_step in remaining_dependent_steps {
+                let step_location = StepLocation::Dependent(dependent_step.index);
+
+                let name = match dependent_step.steps {
+                    Left(block) => &block.name,
+                    Right(step) => &step.name,
+                };
+
+                // Every step sharing a key has to succeed, such as each combination of a matrix
+                let dependencies: Vec<(DependencyState, Option<&str>)> = dependent_step
+                    .depends_on
+                    .iter()
+                    .flat_map(|key| match keyed_steps.get(key.as_str()) {
+                        None => vec![(DependencyState::Succeeded, None)],
+                        Some(keyed_steps) => keyed_steps
+                            .iter()
+                            .map(|keyed_step| match handled_steps.get(&keyed_step.location) {
+                                Some(dependency_state) => (*dependency_state, None),
+                                None => {
+                                    match find_check_run(keyed_step.name, &keyed_step.location) {
+                                        Some(check_run) => (
+                                            dependency_state(check_run, keyed_step.is_block),
+                                            Some(check_run.started_at.as_str()),
+                                        ),
+                                        None => (DependencyState::Pending, None),
+                                    }
+                                }
+                            })
+                            .collect(),
+                    })
+                    .collect();
+
+                // A check run started after its dependencies means this run has already handled it
+                let already_handled = find_check_run(name, &step_location)
+                    .map(|check_run| {
+                        dependencies.iter().all(|(_, maybe_started_at)| {
+                            maybe_started_at
+                                .map(|started_at| check_run.started_at.as_str() >= started_at)
+                                .unwrap_or(true)
+                        })
+                    })
+                    .unwrap_or(false);
+
+                if already_handled {
+                    continue;
+                }
+
+                let identifier = step_identifier(pipeline_path, &step_location, pull_request);
+
+                if dependencies
+                    .iter()
+                    .any(|(state, _)| *state == DependencyState::Failed)
+                {
+                    info!("Skipping dependent step {}...", name);
+
+                    github_installation_client
+                        .create_skipped_check_run(
+                            &pipeline_path.check_run_name(name),
+                            commit_sha,
+                            &identifier,
+                            "Skipped because a step it depends on did not succeed.",
+                        )
+                        .await?;
+
+                    handled_steps.insert(step_location, DependencyState::Failed);
+                } else if dependencies
+                    .iter()
+                    .all(|(state, _)| *state == DependencyState::Succeeded)
+                {
+                    info!("Starting dependent step {}...", name);
+
+                    let steps = match dependent_step.steps {
+                        Left(block) => Left(block),
+                        Right(step) => Right(vec1![step]),
+                    };
+
+                    self.run_step_section(
+                        github_installation_client,
+                        steps,
+                        installation_id,
+                        repo_name,
+                        commit_sha,
+                        branch_name,
+                        &step_location,
+                        pull_request,
+                        pipeline_path,
+                        false,
+                        pipeline_deadline,
+                    )
+                    .await?;
+
+                    handled_steps.insert(step_location, DependencyState::Pending);
+                } else {
+                    pending_dependent_steps.push(dependent_step);
+                }
+            }
+
+            // Skipping a step can cascade to the steps depending on it
+            if pending_dependent_steps.len() == remaining_count {
+                break;
+            }
+
+            remaining_dependent_steps = pending_dependent_steps;
+        }
+
+        Ok(())
+    }
+
+    // Fetches the pipeline with everything it includes. Fetching can fail, while an invalid
+    // pipeline is given as its message, so it can be reported.
+    #[allow(clippy::type_complexity)]
+    async fn get_pipeline(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        commit_sha: &str,
+        pipeline_path: &PipelinePath,
+    ) -> Result<Option<Result<ResolvedPipeline, String>>, Box<dyn std::error::Error>> {
+        let root = pipeline_path.source();
+
+        let mut files: HashMap<PipelineSource, Option<String>> = HashMap::new();
+
+        loop {
+            let missing_sources =
+                match resolve_pipeline(&files, &root, self.templates_repo.as_deref()) {
+                    Ok(Resolution::Resolved(resolved_pipeline)) => {
+                        return Ok(Some(Ok(resolved_pipeline)))
+                    }
+                    Ok(Resolution::Missing(missing_sources)) => missing_sources,
+                    Err(message) => return Ok(Some(Err(message))),
+                };
+
+            if files.len() + missing_sources.len() > MAX_INCLUDED_FILES + 1 {
+                return Ok(Some(Err(format!(
+                    "The pipeline includes more than {} files",
+                    MAX_INCLUDED_FILES
+                ))));
+            }
+
+            for source in missing_sources {
+                let repository_name = source
+                    .repo
+                    .as_deref()
+                    .unwrap_or(github_installation_client.repository_name);
+                let git_ref = source.git_ref.as_deref().unwrap_or(commit_sha);
+
+                let file = github_installation_client
+                    .get_repository_file(repository_name, &source.file, git_ref)
+                    .await?;
+
+                // Repos without a pipeline don't run anything
+                if source == root && file.is_none() {
+                    return Ok(None);
+                }
+
+                files.insert(source, file);
+            }
+        }
+    }
+
+    // Only the pipeline itself is kept, as what it included is only needed when the build starts.
+    // Builds started before it was kept resolve it again.
+    async fn get_build_pipeline(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        commit_sha: &str,
+        build: &str,
+        pipeline_path: &PipelinePath,
+    ) -> Result<Option<Result<ResolvedPipeline, String>>, Box<dyn std::error::Error>> {
+        let maybe_pipeline = self
+            .artifact_store
+            .get_resolved_pipeline(
+                github_installation_client.repository_name,
+                commit_sha,
+                build,
+            )
+            .await?;
+
+        match maybe_pipeline {
+            Some(pipeline) => Ok(Some(Ok(ResolvedPipeline {
+                pipeline,
+                has_includes: false,
+                included_files: Vec::new(),
+            }))),
+            None => {
+                self.get_pipeline(github_installation_client, commit_sha, pipeline_path)
+                    .await
+            }
+        }
+    }
+
+    // Builds of pushed tags are for the tag's name, rather than a branch's. The commit and labels are
+    // only needed by `if` conditions, but the pipeline isn't known until after the context is.
+    async fn build_context(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        branch_name: &str,
+        commit_sha: &str,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+    ) -> Result<BuildContext, Box<dyn std::error::Error>> {
+        let tag = match pull_request {
+            Some(_) => None,
+            None => github_installation_client
+                .get_tag_commit_sha(branch_name)
+                .await?
+                .filter(|tag_commit_sha| tag_commit_sha == commit_sha)
+                .map(|_| branch_name.to_string()),
+        };
+
+        let commit = github_installation_client.get_commit(commit_sha).await?;
+
+        let pull_request_labels = match pull_request {
+            Some(pull_request) => {
+                github_installation_client
+                    .list_pull_request_labels(pull_request.number)
+                    .await?
+            }
+            None => Vec::new(),
+        };
+
+        let changed_files = self
+            .artifact_store
+            .get_changed_files(
+                github_installation_client.repository_name,
+                commit_sha,
+                &pipeline_build(pipeline_path, pull_request),
+            )
+            .await?;
+
+        Ok(BuildContext {
+            branch: branch_name.to_string(),
+            event: github_event(pull_request),
+            tag,
+            message: commit.commit.message,
+            author: commit.commit.author.name,
+            pull_request_labels,
+            changed_files,
+        })
+    }
+
+    // Pull requests are compared with their base branch, pushes with the latest earlier commit
+    // whose push build succeeded
+    async fn record_changed_files(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        commit_sha: &str,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let maybe_base = match pull_request {
+            Some(pull_request) => Some(pull_request.base_branch.clone()),
+            None => {
+                self.previous_successful_commit(
+                    github_installation_client,
+                    commit_sha,
+                    pipeline_path,
+                )
+                .await?
+            }
+        };
+
+        let maybe_changed_files = match maybe_base {
+            Some(base) => {
+                github_installation_client
+                    .get_changed_files(&base, commit_sha)
+                    .await?
+            }
+            None => None,
+        };
+
+        let repo_name = github_installation_client.repository_name;
+        let build = pipeline_build(pipeline_path, pull_request);
+
+        match maybe_changed_files {
+            Some(changed_files) => {
+                self.artifact_store
+                    .put_changed_files(repo_name, commit_sha, &build, &changed_files)
+                    .await
+            }
+            None => {
+                self.artifact_store
+                    .delete_changed_files(repo_name, commit_sha, &build)
+                    .await
+            }
+        }
+    }
+
+    async fn previous_successful_commit(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        commit_sha: &str,
+        pipeline_path: &PipelinePath,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        const MAX_PREVIOUS_COMMITS: u32 = 20;
+
+        let commit_shas = github_installation_client
+            .list_commits(commit_sha, MAX_PREVIOUS_COMMITS)
+            .await?;
+
+        for previous_commit_sha in commit_shas.into_iter().filter(|sha| sha != commit_sha) {
+            let check_runs = github_installation_client
+                .list_check_runs(&previous_commit_sha)
+                .await?;
+
+            // Other pipelines and pull request builds of the commit don't say how this one went
+            let push_check_runs: Vec<_> = check_runs
+                .iter()
+                .filter(|check_run| is_build_check_run(check_run, None, pipeline_path))
+                .collect();
+
+            let succeeded = !push_check_runs.is_empty()
+                && push_check_runs.iter().all(|check_run| {
+                    check_run.status == "completed"
+                        && matches!(
+                            check_run.conclusion.as_deref(),
+                            Some("success") | Some("neutral") | Some("skipped")
+                        )
+                });
+
+            if succeeded {
+                return Ok(Some(previous_commit_sha));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // The pipeline as it runs for the build, with the steps uploaded by its steps so far
+    async fn get_raw_pipeline(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        repo_name: &str,
+        commit_sha: &str,
+        build_context: &BuildContext,
+        pull_request: Option<&PullRequest>,
+        pipeline_path: &PipelinePath,
+    ) -> Result<Option<RawPipeline>, Box<dyn std::error::Error>> {
+        let build = pipeline_build(pipeline_path, pull_request);
+
+        let maybe_resolved_pipeline = self
+            .get_build_pipeline(
+                github_installation_client,
+                commit_sha,
+                &build,
+                pipeline_path,
+            )
+            .await?
+            .transpose()?;
+
+        let resolved_pipeline = match maybe_resolved_pipeline {
+            Some(resolved_pipeline) => resolved_pipeline,
+            None => return Ok(None),
+        };
+
+        let uploads = self
+            .artifact_store
+            .get_pipeline_uploads(repo_name, commit_sha, &build)
+            .await?;
+
+        parse_pipeline(
+            &resolved_pipeline.pipeline,
+            &uploads,
+            build_context,
+            &self.max_step_resources,
+        )
+        .map(Some)
+    }
+
+    async fn github_installation_client<'a>(
+        &'a self,
+        installation_id: u32,
+        repo_name: &'a str,
+    ) -> Result<GithubInstallationClient<'a>, Box<dyn std::error::Error>> {
+        let github_authorisation_client = GithubAuthorisationClient::new(
+            &self.github_private_key,
+            &self.application_id,
+            &self.github_base_url,
+        )?;
+
+        let installation_access_token = github_authorisation_client
+            .get_installation_access_token(installation_id)
+            .await?;
+
+        Ok(GithubInstallationClient {
+            repository_name: repo_name,
+            github_installation_token: installation_access_token,
+            base_url: &self.github_base_url,
+        })
+    }
+}
+
+// Check run output is limited in size, so very large pipelines are cut short
+fn resolved_pipeline_text(pipeline: &str) -> String {
+    const MAX_PIPELINE_LENGTH: usize = 60000;
+
+    let truncated_pipeline: String = pipeline.chars().take(MAX_PIPELINE_LENGTH).collect();
+
+    if truncated_pipeline.len() < pipeline.len() {
+        format!(
+            "```yaml\n{}\n```\n\nThe pipeline was too long to show in full",
+            truncated_pipeline
+        )
+    } else {
+        format!("```yaml\n{}\n```", pipeline)
+    }
+}
+
+fn find_step<'a>(
+    raw_pipeline: &'a RawPipeline,
+    build_context: &BuildContext,
+    step_location: StepLocation,
+    step_name: &str,
+) -> Option<&'a Step> {
+    match step_location {
+        StepLocation::Section(step_section) => {
+            filter(&raw_pipeline.steps, build_context, step_section)
+                .and_then(|steps| steps.right())
+                .and_then(|steps| steps.into_iter().find(|step| step.name == step_name))
+        }
+        StepLocation::Dependent(index) => {
+            filter_dependent_steps(&raw_pipeline.steps, build_context)
+                .into_iter()
+                .find(|dependent_step| dependent_step.index == index)
+                .and_then(|dependent_step| dependent_step.steps.right())
+                .filter(|step| step.name == step_name)
+        }
+        StepLocation::Filtered(_) => None,
+    }
+}
+
+// Without a deadline carried over from earlier steps, this is the start of the pipeline
+fn resolve_pipeline_deadline(
+    raw_pipeline: &RawPipeline,
+    pipeline_deadline: Option<DateTime<Utc>>,
+) -> Option<DateTime<Utc>> {
+    pipeline_deadline.or_else(|| {
+        raw_pipeline
+            .timeout_in_minutes
+            .map(|timeout_in_minutes| Utc::now() + Duration::minutes(i64::from(timeout_in_minutes)))
+    })
+}
+
+fn parse_pipeline(
+    raw_pipeline: &str,
+    uploads: &[PipelineUpload],
+    build_context: &BuildContext,
+    max_step_resources: &ResourceValues,
+) -> Result<RawPipeline, Box<dyn std::error::Error>> {
+    let raw_pipeline: RawPipeline = serde_yaml::from_str(raw_pipeline)?;
+
+    validate_dependencies(&raw_pipeline.steps)?;
+    raw_pipeline.validate_steps()?;
+
+    let raw_pipeline = raw_pipeline
+        .expand_matrices()
+        .apply_placement()
+        .apply_paths_filter()
+        .apply_report_skipped()
+        .apply_env()
+        .resolve_resources(max_step_resources)?;
+
+    Ok(insert_uploaded_steps(
+        raw_pipeline,
+        uploads,
+        build_context,
+        max_step_resources,
+    )?)
+}
+
+// Later sections only get check runs once the one before has finished, so any newer than the
+// section's own, from the same build, mean it has already been finished
+fn has_moved_past_section(
+    check_runs: &[GetCheckRunResponse],
+    section_check_run_ids: &[i64],
+    step_section: usize,
+    pull_request: Option<&PullRequest>,
+    pipeline_path: &PipelinePath,
+) -> bool {
+    let latest_id = match section_check_run_ids.iter().max() {
+        Some(latest_id) => *latest_id,
+        None => return false,
+    };
+
+    check_runs
+        .iter()
+        .filter(|check_run| check_run.id > latest_id)
+        .filter_map(|check_run| check_run_step_section(check_run, pull_request, pipeline_path))
+        .any(|later_section| later_section > step_section)
+}
+
+// Sections that continue on failure still run after one, but the sections after them don't
+fn has_earlier_section_failed(
+    check_runs: &[GetCheckRunResponse],
+    step_section: usize,
+    pull_request: Option<&PullRequest>,
+    pipeline_path: &PipelinePath,
+) -> bool {
+    check_runs
+        .iter()
+        .filter(|check_run| {
+            check_run_step_section(check_run, pull_request, pipeline_path)
+                .map(|earlier_section| earlier_section < step_section)
+                .unwrap_or(false)
+        })
+        .any(|check_run| {
+            check_run.status == "completed"
+                && !matches!(
+                    check_run.conclusion.as_deref(),
+                    Some("success") | Some("neutral") | Some("skipped")
+                )
+        })
+}
+
+// The step section a check run is for, when it's one of this build of the pipeline's
+fn check_run_step_section(
+    check_run: &GetCheckRunResponse,
+    pull_request: Option<&PullRequest>,
+    pipeline_path: &PipelinePath,
+) -> Option<usize> {
+    let (step_location, maybe_pull_request_number, check_run_pipeline_path) =
+        parse_step_identifier(check_run.external_id.as_deref()?).ok()?;
+
+    match step_location {
+        StepLocation::Section(step_section)
+            if maybe_pull_request_number
+                == pull_request.map(|pull_request| pull_request.number)
+                && check_run_pipeline_path == *pipeline_path =>
+        {
+            Some(step_section)
+        }
+        _ => None,
+    }
+}
+
+// Whether a check run belongs to this build of the pipeline, whatever step it's for
+fn is_build_check_run(
+    check_run: &GetCheckRunResponse,
+    pull_request: Option<&PullRequest>,
+    pipeline_path: &PipelinePath,
+) -> bool {
+    match check_run.external_id.as_deref().map(parse_step_identifier) {
+        Some(Ok((_, maybe_pull_request_number, check_run_pipeline_path))) => {
+            maybe_pull_request_number == pull_request.map(|pull_request| pull_request.number)
+                && check_run_pipeline_path == *pipeline_path
+        }
+        _ => false,
+    }
+}
+
+// Used as the check run external id, which is all GitHub hands back when a check run is rerun or
+// unblocked. Pipelines other than the main one come last, as their files can have any characters.
+pub fn step_identifier(
+    pipeline_path: &PipelinePath,
+    step_location: &StepLocation,
+    pull_request: Option<&PullRequest>,
+) -> String {
+    let identifier = match pull_request {
+        Some(pull_request) => format!("{}:{}", step_location, pull_request.number),
+        None => step_location.to_string(),
+    };
+
+    if pipeline_path.is_root() {
+        identifier
+    } else {
+        format!("{}@{}", identifier, pipeline_path.path)
+    }
+}
+
+pub fn parse_step_identifier(
+    identifier: &str,
+) -> Result<(StepLocation, Option<u64>, PipelinePath), ParseIntError> {
+    let mut identifier_parts = identifier.splitn(2, '@');
+
+    let mut parts = identifier_parts.next().unwrap_or_default().splitn(2, ':');
+
+    let step_location = parts.next().unwrap_or_default().parse()?;
+
+    let maybe_pull_request_number = match parts.next() {
+        Some(pull_request_number) => Some(pull_request_number.parse()?),
+        None => None,
+    };
+
+    let pipeline_path = match identifier_parts.next() {
+        Some(path) => PipelinePath {
+            path: path.to_string(),
+        },
+        None => PipelinePath::root(),
+    };
+
+    Ok((step_location, maybe_pull_request_number, pipeline_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::client::installation::{CheckRunCheckSuite, GetCheckRunOutput};
+
+    fn check_run(id: i64, external_id: &str, conclusion: &str) -> GetCheckRunResponse {
+        GetCheckRunResponse {
+            id,
+            name: "test".to_string(),
+            started_at: "2020-01-01T00:00:00Z".to_string(),
+            status: "completed".to_string(),
+            conclusion: Some(conclusion.to_string()),
+            external_id: Some(external_id.to_string()),
+            output: GetCheckRunOutput {
+                summary: None,
+                text: None,
+            },
+            check_suite: CheckRunCheckSuite { id: 1 },
+        }
+    }
+
+    #[test]
+    fn should_only_move_past_a_section_once() {
+        let has_moved_past = |check_runs: &[GetCheckRunResponse]| {
+            has_moved_past_section(check_runs, &[10, 11], 0, None, &PipelinePath::root())
+        };
+
+        // Check runs left from an earlier run of the whole pipeline are older than the section's
+        assert!(!has_moved_past(&[
+            check_run(5, "1", "success"),
+            check_run(11, "0", "success")
+        ]));
+        assert!(!has_moved_past(&[
+            check_run(12, "1:42", "success"),
+            check_run(13, "d1", "success")
+        ]));
+        assert!(has_moved_past(&[check_run(12, "1", "success")]));
+        assert!(has_moved_past(&[check_run(12, "2", "success")]));
+    }
+
+    #[test]
+    fn should_not_move_past_a_section_for_steps_skipped_by_their_paths() {
+        // B, wait, A (left out by its paths), wait, C, where A is reported once B's section starts
+        let check_runs = [
+            check_run(10, "0", "success"),
+            check_run(11, "f2", "skipped"),
+        ];
+
+        assert!(!has_moved_past_section(
+            &check_runs,
+            &[10],
+            0,
+            None,
+            &PipelinePath::root()
+        ));
+        assert!(!has_earlier_section_failed(
+            &check_runs,
+            1,
+            None,
+            &PipelinePath::root()
+        ));
+    }
+
+    #[test]
+    fn should_know_when_an_earlier_section_failed() {
+        let has_failed = |check_runs: &[GetCheckRunResponse]| {
+            has_earlier_section_failed(check_runs, 2, None, &PipelinePath::root())
+        };
+
+        assert!(!has_failed(&[
+            check_run(1, "0", "success"),
+            check_run(1, "1", "skipped"),
+            check_run(1, "2", "failure"),
+            check_run(1, "0:42", "failure")
+        ]));
+        assert!(has_failed(&[
+            check_run(1, "0", "failure"),
+            check_run(1, "1", "skipped")
+        ]));
+        assert!(has_failed(&[check_run(1, "1", "timed_out")]));
+    }
+
+    #[test]
+    fn should_only_count_check_runs_of_the_build() {
+        let pipeline_path = PipelinePath {
+            path: "services/api/kubesci.yml".to_string(),
+        };
+
+        assert!(is_build_check_run(
+            &check_run(1, "0", "success"),
+            None,
+            &PipelinePath::root()
+        ));
+        assert!(is_build_check_run(
+            &check_run(1, "d2@services/api/kubesci.yml", "success"),
+            None,
+            &pipeline_path
+        ));
+        assert!(!is_build_check_run(
+            &check_run(1, "0@services/api/kubesci.yml", "failure"),
+            None,
+            &PipelinePath::root()
+        ));
+        assert!(!is_build_check_run(
+            &check_run(1, "0:42@services/api/kubesci.yml", "failure"),
+            None,
+            &pipeline_path
+        ));
+        assert!(!is_build_check_run(
+            &check_run(1, "0", "failure"),
+            None,
+            &pipeline_path
+        ));
+    }
+
+    #[test]
+    fn should_round_trip_step_identifier_for_push() {
+        let identifier = step_identifier(&PipelinePath::root(), &StepLocation::Section(3), None);
+
+        assert_eq!(
+            parse_step_identifier(&identifier),
+            Ok((StepLocation::Section(3), None, PipelinePath::root()))
+        );
+    }
+
+    #[test]
+    fn should_round_trip_step_identifier_for_dependent_step() {
+        let identifier = step_identifier(&PipelinePath::root(), &StepLocation::Dependent(5), None);
+
+        assert_eq!(identifier, "d5");
+        assert_eq!(
+            parse_step_identifier(&identifier),
+            Ok((StepLocation::Dependent(5), None, PipelinePath::root()))
+        );
+    }
+
+    #[test]
+    fn should_round_trip_step_identifier_for_filtered_step() {
+        let identifier = step_identifier(&PipelinePath::root(), &StepLocation::Filtered(4), None);
+
+        assert_eq!(identifier, "f4");
+        assert_eq!(
+            parse_step_identifier(&identifier),
+            Ok((StepLocation::Filtered(4), None, PipelinePath::root()))
+        );
+    }
+
+    #[test]
+    fn should_round_trip_step_identifier_for_pull_request() {
+        let pull_request = PullRequest {
+            number: 42,
+            base_branch: "master".to_string(),
+            head_branch: "some-feature".to_string(),
+        };
+
+        let identifier = step_identifier(
+            &PipelinePath::root(),
+            &StepLocation::Section(3),
+            Some(&pull_request),
+        );
+
+        assert_eq!(
+            parse_step_identifier(&identifier),
+            Ok((StepLocation::Section(3), Some(42), PipelinePath::root()))
+        );
+    }
+
+    #[test]
+    fn should_round_trip_step_identifier_for_other_pipelines() {
+        let pipeline_path = PipelinePath {
+            path: "services/api@v2/kubesci.yml".to_string(),
+        };
+
+        let identifier = step_identifier(&pipeline_path, &StepLocation::Dependent(2), None);
+
+        assert_eq!(identifier, "d2@services/api@v2/kubesci.yml");
+        assert_eq!(
+            parse_step_identifier(&identifier),
+            Ok((StepLocation::Dependent(2), None, pipeline_path))
+        );
+    }
 }
@@ -1,96 +1,387 @@
+use crate::artifacts::glob_matches;
 use crate::kubernetes::{Block, Step, StepType};
+use crate::pipeline::expressions::Expression;
+use crate::pipeline::StepLocation;
 use either::{Either, Either::Left, Either::Right};
+use regex::Regex;
+use serde_derive::Deserialize;
 use vec1::Vec1;
 
+// What a build's steps are filtered by
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildContext {
+    pub branch: String,
+    pub event: &'static str,
+    // Set when the build is for a tag rather than a branch
+    pub tag: Option<String>,
+    pub message: String,
+    pub author: String,
+    // Empty for builds of a push
+    pub pull_request_labels: Vec<String>,
+    // Unknown when there's nothing to compare with, in which case steps run whatever their paths
+    pub changed_files: Option<Vec<String>>,
+}
+
+// Globs such as `services/api/**` for the changed files a step runs for, or that it ignores
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct PathsFilter {
+    pub paths: Option<Vec1<String>>,
+    pub paths_ignore: Option<Vec1<String>>,
+}
+
+impl PathsFilter {
+    // The two only make sense together, so a step with either replaces both of the pipeline's
+    pub fn or(&self, defaults: &PathsFilter) -> PathsFilter {
+        if self.paths.is_none() && self.paths_ignore.is_none() {
+            defaults.clone()
+        } else {
+            self.clone()
+        }
+    }
+
+    // Some changed file has to match the paths, if there are any, without being ignored
+    fn matches(&self, changed_files: Option<&[String]>) -> bool {
+        let changed_files = match changed_files {
+            Some(changed_files) if *self != PathsFilter::default() => changed_files,
+            _ => return true,
+        };
+
+        let matches_any = |globs: &Vec1<String>, changed_file: &str| {
+            globs.iter().any(|glob| glob_matches(glob, changed_file))
+        };
+
+        changed_files.iter().any(|changed_file| {
+            self.paths
+                .as_ref()
+                .map(|paths| matches_any(paths, changed_file))
+                .unwrap_or(true)
+                && !self
+                    .paths_ignore
+                    .as_ref()
+                    .map(|paths_ignore| matches_any(paths_ignore, changed_file))
+                    .unwrap_or(false)
+        })
+    }
+}
+
+// Why a step or block doesn't run for the build, with the filter that left it out
+#[derive(Debug, PartialEq)]
+pub enum SkipReason<'a> {
+    Branch(&'a str),
+    Event(&'a str),
+    Tag(&'a str),
+    Condition,
+    Paths,
+}
+
+impl<'a> SkipReason<'a> {
+    pub fn summary(&self) -> String {
+        match self {
+            SkipReason::Branch(filter) => {
+                format!("Skipped because the branch doesn't match `{}`.", filter)
+            }
+            SkipReason::Event(filter) => {
+                format!("Skipped because the event doesn't match `{}`.", filter)
+            }
+            SkipReason::Tag(filter) => {
+                format!("Skipped because the tag doesn't match `{}`.", filter)
+            }
+            SkipReason::Condition => "Skipped because its if condition is false.".to_string(),
+            SkipReason::Paths => {
+                "Skipped because none of the changed files match its paths.".to_string()
+            }
+        }
+    }
+}
+
+// A step or block that's reported as skipped, and the location its check run is identified by
+pub struct SkippedStep<'a> {
+    pub location: StepLocation,
+    pub name: &'a str,
+    pub reason: SkipReason<'a>,
+}
+
+pub struct DependentStep<'a> {
+    // Position in the pipeline file, which identifies the step outside of the step sections
+    pub index: usize,
+    pub steps: Either<&'a Block, &'a Step>,
+    pub depends_on: &'a Vec1<String>,
+}
+
+pub struct StepSection<'a> {
+    pub steps: Either<&'a Block, Vec1<&'a Step>>,
+    // Set when the section follows a `wait` that should still run after a failure
+    pub continue_on_failure: bool,
+}
+
 pub fn filter<'a>(
     steps: &'a [StepType],
-    github_branch_name: &str,
+    build_context: &BuildContext,
     step_section: usize,
 ) -> Option<Either<&'a Block, Vec1<&'a Step>>> {
+    filter_step_sections(steps, build_context)
+        .into_iter()
+        .nth(step_section)
+        .map(|step_section| step_section.steps)
+}
+
+pub fn filter_step_sections<'a>(
+    steps: &'a [StepType],
+    build_context: &BuildContext,
+) -> Vec<StepSection<'a>> {
+    // Steps with dependencies are run as soon as those finish rather than in a step section
     let maybe_steps = steps
         .iter()
-        .filter(|step| skip_step_or_block(step, github_branch_name))
+        .filter(|step| step.depends_on().is_none())
+        .filter(|step| skip_step_or_block(step, build_context))
         .collect::<Vec<_>>();
 
     match Vec1::try_from_vec(maybe_steps).ok() {
-        Some(steps) => {
-            let split_steps = split_into_blocks_and_steps(steps);
-
-            split_steps
-                .get(step_section)
-                .map(|either| either.to_owned())
-        }
-        None => None,
+        Some(steps) => split_into_blocks_and_steps(steps),
+        None => Vec::new(),
     }
 }
 
+pub fn filter_dependent_steps<'a>(
+    steps: &'a [StepType],
+    build_context: &BuildContext,
+) -> Vec<DependentStep<'a>> {
+    steps
+        .iter()
+        .enumerate()
+        .filter(|(_, step)| skip_step_or_block(step, build_context))
+        .filter_map(|(index, step)| match step {
+            StepType::Block(block) => block.depends_on.as_ref().map(|depends_on| DependentStep {
+                index,
+                steps: Left(block),
+                depends_on,
+            }),
+            StepType::Step(step) => step.depends_on.as_ref().map(|depends_on| DependentStep {
+                index,
+                steps: Right(step),
+                depends_on,
+            }),
+            StepType::Wait(_) => None,
+        })
+        .collect()
+}
+
+// Reported as skipped when the build starts, as checks that never appear block required checks.
+// Steps left out by their paths always are, and steps left out by anything else only when they
+// ask to be. None of them are in any step section, so they're identified by their position in
+// the pipeline file.
+pub fn filter_skipped_steps<'a>(
+    steps: &'a [StepType],
+    build_context: &BuildContext,
+) -> Vec<SkippedStep<'a>> {
+    steps
+        .iter()
+        .enumerate()
+        .filter_map(|(index, step)| {
+            let name = match step {
+                StepType::Block(block) => &block.name,
+                StepType::Step(step) => &step.name,
+                StepType::Wait(_) => return None,
+            };
+
+            match skip_reason(step, build_context) {
+                Some(SkipReason::Paths) => Some(SkipReason::Paths),
+                Some(reason) if step.report_skipped() => Some(reason),
+                _ => None,
+            }
+            .map(|reason| SkippedStep {
+                location: StepLocation::Filtered(index),
+                name,
+                reason,
+            })
+        })
+        .collect()
+}
+
 // There be dragons...
-fn split_into_blocks_and_steps<'a>(
-    steps_or_blocks: Vec1<&'a StepType>,
-) -> Vec<Either<&'a Block, Vec1<&'a Step>>> {
+fn split_into_blocks_and_steps<'a>(steps_or_blocks: Vec1<&'a StepType>) -> Vec<StepSection<'a>> {
     let mut previous_step_was_wait = false;
+    let mut continue_on_failure = false;
 
     steps_or_blocks.iter().fold(
         Vec::new(),
-        |mut acc: Vec<Either<&'a Block, Vec1<&'a Step>>>, block_or_step| match block_or_step {
+        |mut acc: Vec<StepSection<'a>>, block_or_step| match block_or_step {
             StepType::Block(block) => {
-                acc.push(Left(&block));
+                acc.push(StepSection {
+                    steps: Left(block),
+                    continue_on_failure,
+                });
                 previous_step_was_wait = false;
+                continue_on_failure = false;
                 acc
             }
             StepType::Step(step) => {
                 let lastest_value = acc.pop();
 
                 match lastest_value {
-                    Some(Right(steps)) if !previous_step_was_wait => {
+                    Some(StepSection {
+                        steps: Right(steps),
+                        continue_on_failure: previous_continue_on_failure,
+                    }) if !previous_step_was_wait => {
                         let mut non_empty_vec = vec1![step];
                         non_empty_vec.extend(steps.to_owned());
 
-                        acc.push(Right(non_empty_vec));
+                        acc.push(StepSection {
+                            steps: Right(non_empty_vec),
+                            continue_on_failure: previous_continue_on_failure,
+                        });
                     }
                     Some(previous) => {
                         acc.push(previous);
-                        acc.push(Right(vec1![&step]));
+                        acc.push(StepSection {
+                            steps: Right(vec1![&step]),
+                            continue_on_failure,
+                        });
                     }
-                    None => acc.push(Right(vec1![&step])),
+                    None => acc.push(StepSection {
+                        steps: Right(vec1![&step]),
+                        continue_on_failure,
+                    }),
                 }
 
                 previous_step_was_wait = false;
+                continue_on_failure = false;
                 acc
             }
-            StepType::Wait(_) => {
+            StepType::Wait(wait) => {
                 previous_step_was_wait = true;
+                continue_on_failure = wait.continue_on_failure();
                 acc
             }
         },
     )
 }
 
-fn skip_step_or_block(step: &StepType, github_branch_name: &str) -> bool {
-    let branch = match step {
-        StepType::Block(block) => block.branch.clone(),
-        StepType::Step(step) => step.branch.clone(),
-        StepType::Wait(_) => None,
+fn skip_step_or_block(step: &StepType, build_context: &BuildContext) -> bool {
+    skip_reason(step, build_context).is_none()
+}
+
+fn skip_reason<'a>(step: &'a StepType, build_context: &BuildContext) -> Option<SkipReason<'a>> {
+    let (branch, event, tag, condition): (_, _, _, &Option<Expression>) = match step {
+        StepType::Block(block) => (&block.branch, &block.event, &block.tag, &block.condition),
+        StepType::Step(step) => (&step.branch, &step.event, &step.tag, &step.condition),
+        StepType::Wait(_) => (&None, &None, &None, &None),
+    };
+
+    let matches_paths = match step {
+        StepType::Step(step) => step
+            .paths_filter
+            .matches(build_context.changed_files.as_deref()),
+        _ => true,
     };
 
-    branch.is_none()
-        || branch == Some(github_branch_name.to_string())
-        || not_branch(branch.as_ref(), github_branch_name)
+    let matches_tag = match &build_context.tag {
+        Some(build_tag) => matches_filter(tag.as_deref(), build_tag),
+        // Builds for a branch have no tag, so only match tag filters made up of negations
+        None => tag
+            .iter()
+            .flat_map(|tag| tag.split_whitespace())
+            .all(|pattern| pattern.starts_with('!')),
+    };
+
+    if !matches_filter(branch.as_deref(), &build_context.branch) {
+        branch.as_deref().map(SkipReason::Branch)
+    } else if !matches_filter(event.as_deref(), build_context.event) {
+        event.as_deref().map(SkipReason::Event)
+    } else if !matches_tag {
+        tag.as_deref().map(SkipReason::Tag)
+    } else if !matches_paths {
+        Some(SkipReason::Paths)
+    } else if !condition
+        .as_ref()
+        .map(|condition| condition.evaluate(build_context))
+        .unwrap_or(true)
+    {
+        Some(SkipReason::Condition)
+    } else {
+        None
+    }
+}
+
+// A space separated list of names, globs such as `release/*` and regexes such as `/^v\d+$/`, any
+// of which can be negated with a leading `!`. The value has to match one of the patterns, if there
+// are any that aren't negated, and none of the negated ones.
+fn matches_filter(filter: Option<&str>, value: &str) -> bool {
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return true,
+    };
+
+    let (negated_patterns, patterns): (Vec<&str>, Vec<&str>) = filter
+        .split_whitespace()
+        .partition(|pattern| pattern.starts_with('!'));
+
+    let matches_patterns = patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, value));
+
+    matches_patterns
+        && !negated_patterns
+            .iter()
+            .any(|pattern| matches_pattern(&pattern[1..], value))
 }
 
-fn not_branch(branch: Option<&String>, github_branch_name: &str) -> bool {
-    branch
-        .map(|branch| branch.starts_with('!') && branch[1..] != *github_branch_name)
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    pattern_regex(pattern)
+        .map(|regex| regex.is_match(value))
         .unwrap_or(false)
 }
 
+fn pattern_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    match regex_pattern(pattern) {
+        Some(regex) => Regex::new(regex),
+        // Unlike artifact globs, `*` matches across `/`, as branch names are rarely paths
+        None => Regex::new(&format!(
+            "^{}$",
+            regex::escape(pattern).replace("\\*", ".*")
+        )),
+    }
+}
+
+fn regex_pattern(pattern: &str) -> Option<&str> {
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        Some(&pattern[1..pattern.len() - 1])
+    } else {
+        None
+    }
+}
+
+// Invalid regexes would otherwise silently never match
+pub fn validate_filter(filter: &str) -> Result<(), String> {
+    for pattern in filter.split_whitespace() {
+        let pattern = pattern.strip_prefix('!').unwrap_or(pattern);
+
+        if let Some(regex) = regex_pattern(pattern) {
+            Regex::new(regex)
+                .map_err(|error| format!("{} is not a valid regex: {}", regex, error))?;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::kubernetes::{Wait, WaitOptions};
+
+    fn build_context(branch: &str, event: &'static str) -> BuildContext {
+        BuildContext {
+            branch: branch.to_string(),
+            event,
+            ..Default::default()
+        }
+    }
     #[test]
     fn should_return_none_if_no_steps_to_run() {
         let empty_steps = &Vec::new();
-        let maybe_steps = filter(empty_steps, &"some_branch".to_string(), 0);
+        let maybe_steps = filter(empty_steps, &build_context("some_branch", "push"), 0);
 
         assert!(maybe_steps.is_none());
     }
@@ -107,6 +398,7 @@ mod tests {
             branch: Some(branch.to_string()),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let step_that_does_not_match_branch = Step {
@@ -117,6 +409,7 @@ mod tests {
             branch: Some("some_other_branch".to_string()),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let steps = vec![
@@ -124,7 +417,10 @@ mod tests {
             StepType::Step(step_that_matches_branch),
         ];
 
-        let filtered_steps = filter(&steps, branch, 0).unwrap().right().unwrap();
+        let filtered_steps = filter(&steps, &build_context(branch, "push"), 0)
+            .unwrap()
+            .right()
+            .unwrap();
 
         let filter_step_names: Vec<String> = filtered_steps
             .iter()
@@ -146,6 +442,7 @@ mod tests {
             branch: Some(branch.to_string()),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let step_that_does_not_match_branch = Step {
@@ -156,6 +453,7 @@ mod tests {
             branch: Some("some_other_branch".to_string()),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let steps = vec![
@@ -163,7 +461,10 @@ mod tests {
             StepType::Step(step_that_matches_branch),
         ];
 
-        let filtered_steps = filter(&steps, branch, 0).unwrap().right().unwrap();
+        let filtered_steps = filter(&steps, &build_context(branch, "push"), 0)
+            .unwrap()
+            .right()
+            .unwrap();
 
         let filter_step_names: Vec<String> = filtered_steps
             .iter()
@@ -185,6 +486,7 @@ mod tests {
             branch: Some(format!("!{}", branch)),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let step_with_exclamation_branch_that_does_not_match_branch = Step {
@@ -195,6 +497,7 @@ mod tests {
             branch: Some("!some_other_branch".to_string()),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let steps = vec![
@@ -202,7 +505,10 @@ mod tests {
             StepType::Step(step_with_exclamation_branch_that_does_not_match_branch),
         ];
 
-        let filtered_steps = filter(&steps, branch, 0).unwrap().right().unwrap();
+        let filtered_steps = filter(&steps, &build_context(branch, "push"), 0)
+            .unwrap()
+            .right()
+            .unwrap();
 
         let filter_step_names: Vec<String> = filtered_steps
             .iter()
@@ -225,6 +531,7 @@ mod tests {
             branch: Some(format!("!{}", branch)),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let step_with_exclamation_branch_that_does_not_match_branch = Step {
@@ -235,6 +542,7 @@ mod tests {
             branch: Some("!some_other_branch".to_string()),
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let steps = vec![
@@ -242,7 +550,10 @@ mod tests {
             StepType::Step(step_with_exclamation_branch_that_does_not_match_branch),
         ];
 
-        let filtered_steps = filter(&steps, branch, 0).unwrap().right().unwrap();
+        let filtered_steps = filter(&steps, &build_context(branch, "push"), 0)
+            .unwrap()
+            .right()
+            .unwrap();
 
         let filter_step_names: Vec<String> = filtered_steps
             .iter()
@@ -263,16 +574,18 @@ mod tests {
             branch: None,
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let block = Block {
             name: "block".to_string(),
             branch: None,
+            ..Default::default()
         };
 
         let steps = vec![StepType::Step(step), StepType::Block(block)];
 
-        let filtered_steps = filter(&steps, "some_branch", 0).unwrap();
+        let filtered_steps = filter(&steps, &build_context("some_branch", "push"), 0).unwrap();
 
         assert!(filtered_steps.is_right());
     }
@@ -287,17 +600,472 @@ mod tests {
             branch: None,
             env: None,
             mount_secret: None,
+            ..Default::default()
         };
 
         let block = Block {
             name: "block".to_string(),
             branch: None,
+            ..Default::default()
         };
 
         let steps = vec![StepType::Block(block), StepType::Step(step)];
 
-        let filtered_steps = filter(&steps, "some_branch", 0).unwrap();
+        let filtered_steps = filter(&steps, &build_context("some_branch", "push"), 0).unwrap();
 
         assert!(filtered_steps.is_left());
     }
+
+    #[test]
+    fn should_only_continue_on_failure_after_wait_with_continue_on_failure() {
+        let step = Step {
+            name: "step".to_string(),
+            image: "some_image".to_string(),
+            commands: None,
+            args: None,
+            branch: None,
+            env: None,
+            mount_secret: None,
+            ..Default::default()
+        };
+
+        let steps = vec![
+            StepType::Step(step.clone()),
+            StepType::Wait(Wait::Basic),
+            StepType::Step(step.clone()),
+            StepType::Wait(Wait::WithOptions {
+                wait: WaitOptions {
+                    continue_on_failure: true,
+                },
+            }),
+            StepType::Step(step),
+        ];
+
+        let continue_on_failure: Vec<bool> =
+            filter_step_sections(&steps, &build_context("some_branch", "push"))
+                .iter()
+                .map(|step_section| step_section.continue_on_failure)
+                .collect();
+
+        assert_eq!(continue_on_failure, vec![false, false, true]);
+    }
+
+    #[test]
+    fn should_run_steps_with_dependencies_outside_of_step_sections() {
+        let build = Step {
+            name: "build".to_string(),
+            image: "some_image".to_string(),
+            key: Some("build".to_string()),
+            ..Default::default()
+        };
+
+        let test = Step {
+            name: "test".to_string(),
+            image: "some_image".to_string(),
+            depends_on: Some(vec1!["build".to_string()]),
+            ..Default::default()
+        };
+
+        let lint = Step {
+            name: "lint".to_string(),
+            image: "some_image".to_string(),
+            ..Default::default()
+        };
+
+        let steps = vec![
+            StepType::Step(build),
+            StepType::Wait(Wait::Basic),
+            StepType::Step(test),
+            StepType::Step(lint),
+        ];
+
+        let section_step_names: Vec<Vec<String>> =
+            filter_step_sections(&steps, &build_context("some_branch", "push"))
+                .into_iter()
+                .map(|step_section| {
+                    step_section
+                        .steps
+                        .right()
+                        .unwrap()
+                        .iter()
+                        .map(|step| step.name.clone())
+                        .collect()
+                })
+                .collect();
+
+        assert_eq!(
+            section_step_names,
+            vec![vec!["build".to_string()], vec!["lint".to_string()]]
+        );
+
+        let dependent_steps = filter_dependent_steps(&steps, &build_context("some_branch", "push"));
+
+        assert_eq!(dependent_steps.len(), 1);
+        assert_eq!(dependent_steps[0].index, 2);
+        assert_eq!(dependent_steps[0].steps.right().unwrap().name, "test");
+    }
+
+    #[test]
+    fn should_filter_dependent_steps_with_defined_branch_that_does_not_match_current() {
+        let block = Block {
+            name: "release".to_string(),
+            branch: Some("master".to_string()),
+            depends_on: Some(vec1!["build".to_string()]),
+            ..Default::default()
+        };
+
+        let steps = vec![StepType::Block(block)];
+
+        assert!(filter_dependent_steps(&steps, &build_context("some_branch", "push")).is_empty());
+        assert_eq!(
+            filter_dependent_steps(&steps, &build_context("master", "push")).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn should_filter_steps_with_defined_event_that_does_not_match_current() {
+        let pull_request_step = Step {
+            name: "pull_request_step".to_string(),
+            image: "some_image".to_string(),
+            event: Some("pull_request".to_string()),
+            ..Default::default()
+        };
+
+        let push_step = Step {
+            name: "push_step".to_string(),
+            image: "some_image".to_string(),
+            event: Some("push".to_string()),
+            ..Default::default()
+        };
+
+        let any_event_step = Step {
+            name: "any_event_step".to_string(),
+            image: "some_image".to_string(),
+            ..Default::default()
+        };
+
+        let steps = vec![
+            StepType::Step(pull_request_step),
+            StepType::Step(push_step),
+            StepType::Step(any_event_step),
+        ];
+
+        let filtered_steps = filter(&steps, &build_context("some_branch", "pull_request"), 0)
+            .unwrap()
+            .right()
+            .unwrap();
+
+        let mut filter_step_names: Vec<String> = filtered_steps
+            .iter()
+            .map(|step| step.name.clone())
+            .collect();
+
+        filter_step_names.sort();
+
+        assert_eq!(
+            filter_step_names,
+            vec![
+                "any_event_step".to_string(),
+                "pull_request_step".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn should_match_lists_of_branches_globs_and_regexes() {
+        assert!(matches_filter(Some("master develop"), "develop"));
+        assert!(!matches_filter(Some("master develop"), "feature"));
+        assert!(matches_filter(Some("release/*"), "release/1.0/hotfix"));
+        assert!(!matches_filter(Some("release/*"), "pre-release/1.0"));
+        assert!(matches_filter(
+            Some("release/* !release/old-*"),
+            "release/1.0"
+        ));
+        assert!(!matches_filter(
+            Some("release/* !release/old-*"),
+            "release/old-1.0"
+        ));
+        assert!(matches_filter(Some("!feature/*"), "master"));
+        assert!(!matches_filter(Some("!feature/*"), "feature/login"));
+        assert!(matches_filter(Some("/^v\\d+$/"), "v12"));
+        assert!(!matches_filter(Some("/^v\\d+$/"), "v12-rc"));
+        assert!(!matches_filter(
+            Some("!/^dependabot/"),
+            "dependabot/cargo/serde"
+        ));
+        assert!(matches_filter(Some("master.*"), "master.*"));
+        assert!(!matches_filter(Some("master.*"), "masters"));
+    }
+
+    #[test]
+    fn should_filter_steps_by_the_tag_being_built() {
+        let release_step = Step {
+            name: "release_step".to_string(),
+            image: "some_image".to_string(),
+            tag: Some("v*".to_string()),
+            ..Default::default()
+        };
+
+        let untagged_step = Step {
+            name: "untagged_step".to_string(),
+            image: "some_image".to_string(),
+            tag: Some("!*".to_string()),
+            ..Default::default()
+        };
+
+        let steps = vec![StepType::Step(release_step), StepType::Step(untagged_step)];
+
+        let step_names = |build_context: &BuildContext| -> Vec<String> {
+            filter(&steps, build_context, 0)
+                .unwrap()
+                .right()
+                .unwrap()
+                .iter()
+                .map(|step| step.name.clone())
+                .collect()
+        };
+
+        let tag_build_context = BuildContext {
+            tag: Some("v1.2.0".to_string()),
+            ..build_context("v1.2.0", "push")
+        };
+
+        assert_eq!(step_names(&tag_build_context), vec!["release_step"]);
+        assert_eq!(
+            step_names(&build_context("master", "push")),
+            vec!["untagged_step"]
+        );
+    }
+
+    #[test]
+    fn should_filter_steps_by_their_if_condition() {
+        let steps: Vec<StepType> = serde_yaml::from_str(
+            r#"
+- name: deploy
+  image: some_image
+  branch: master
+  if: build.message !~ /\[skip deploy\]/
+- name: test
+  image: some_image
+"#,
+        )
+        .unwrap();
+
+        let step_names = |message: &str| -> Vec<String> {
+            let build_context = BuildContext {
+                message: message.to_string(),
+                ..build_context("master", "push")
+            };
+
+            let mut names: Vec<String> = filter(&steps, &build_context, 0)
+                .unwrap()
+                .right()
+                .unwrap()
+                .iter()
+                .map(|step| step.name.clone())
+                .collect();
+            names.sort_unstable();
+            names
+        };
+
+        assert_eq!(step_names("Fix the login"), vec!["deploy", "test"]);
+        assert_eq!(step_names("Fix the readme [skip deploy]"), vec!["test"]);
+        assert!(serde_yaml::from_str::<Vec<StepType>>(
+            "- name: deploy\n  image: some_image\n  if: build.branch\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_filter_steps_by_the_changed_files() {
+        let raw_pipeline: crate::kubernetes::RawPipeline = serde_yaml::from_str(
+            r#"
+paths_ignore: ["**.md"]
+steps:
+  - name: api
+    image: some_image
+    paths: ["services/api/**"]
+  - name: web
+    image: some_image
+    paths: ["services/web/**"]
+    paths_ignore: ["services/web/docs/**"]
+  - name: everything
+    image: some_image
+"#,
+        )
+        .unwrap();
+
+        let raw_pipeline = raw_pipeline.apply_paths_filter();
+
+        let step_names = |changed_files: Option<Vec<&str>>| -> (Vec<String>, Vec<String>) {
+            let build_context = BuildContext {
+                changed_files: changed_files
+                    .map(|files| files.into_iter().map(|file| file.to_string()).collect()),
+                ..build_context("master", "push")
+            };
+
+            let mut names: Vec<String> = filter(&raw_pipeline.steps, &build_context, 0)
+                .map(|steps| {
+                    steps
+                        .right()
+                        .unwrap()
+                        .iter()
+                        .map(|step| step.name.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
+            names.sort_unstable();
+
+            let mut skipped_names: Vec<String> =
+                filter_skipped_steps(&raw_pipeline.steps, &build_context)
+                    .iter()
+                    .map(|skipped_step| skipped_step.name.to_string())
+                    .collect();
+            skipped_names.sort_unstable();
+
+            (names, skipped_names)
+        };
+
+        assert_eq!(
+            step_names(Some(vec!["services/api/src/main.rs"])),
+            (
+                vec!["api".to_string(), "everything".to_string()],
+                vec!["web".to_string()]
+            )
+        );
+        assert_eq!(
+            step_names(Some(vec!["services/web/docs/index.md", "README.md"])),
+            (
+                Vec::new(),
+                vec![
+                    "api".to_string(),
+                    "everything".to_string(),
+                    "web".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            step_names(None),
+            (
+                vec![
+                    "api".to_string(),
+                    "everything".to_string(),
+                    "web".to_string()
+                ],
+                Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn should_identify_steps_skipped_by_their_paths_outside_of_the_step_sections() {
+        let raw_pipeline: crate::kubernetes::RawPipeline = serde_yaml::from_str(
+            r#"
+steps:
+  - name: B
+    image: some_image
+  - wait
+  - name: A
+    image: some_image
+    paths: ["services/a/**"]
+  - wait
+  - name: C
+    image: some_image
+"#,
+        )
+        .unwrap();
+
+        let build_context = BuildContext {
+            changed_files: Some(vec!["README.md".to_string()]),
+            ..build_context("master", "push")
+        };
+
+        let step_section_names: Vec<Vec<&str>> =
+            filter_step_sections(&raw_pipeline.steps, &build_context)
+                .into_iter()
+                .map(|step_section| {
+                    step_section
+                        .steps
+                        .right()
+                        .unwrap()
+                        .iter()
+                        .map(|step| step.name.as_str())
+                        .collect()
+                })
+                .collect();
+
+        assert_eq!(step_section_names, vec![vec!["B"], vec!["C"]]);
+
+        let skipped: Vec<(&str, StepLocation)> =
+            filter_skipped_steps(&raw_pipeline.steps, &build_context)
+                .iter()
+                .map(|skipped_step| (skipped_step.name, skipped_step.location))
+                .collect();
+
+        assert_eq!(skipped, vec![("A", StepLocation::Filtered(2))]);
+    }
+
+    #[test]
+    fn should_report_filtered_out_steps_as_skipped_when_asked_to() {
+        let raw_pipeline: crate::kubernetes::RawPipeline = serde_yaml::from_str(
+            r#"
+report_skipped: true
+steps:
+  - name: Release
+    image: some_image
+    branch: master
+  - name: Nightly
+    image: some_image
+    event: schedule
+    report_skipped: false
+  - block: Deploy?
+    if: build.branch == "master"
+  - name: test
+    image: some_image
+"#,
+        )
+        .unwrap();
+
+        let raw_pipeline = raw_pipeline.apply_report_skipped();
+
+        let skipped_steps =
+            filter_skipped_steps(&raw_pipeline.steps, &build_context("feature", "push"));
+
+        let skipped: Vec<(&str, StepLocation, String)> = skipped_steps
+            .iter()
+            .map(|skipped_step| {
+                (
+                    skipped_step.name,
+                    skipped_step.location,
+                    skipped_step.reason.summary(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            skipped,
+            vec![
+                (
+                    "Release",
+                    StepLocation::Filtered(0),
+                    "Skipped because the branch doesn't match `master`.".to_string()
+                ),
+                (
+                    "Deploy?",
+                    StepLocation::Filtered(2),
+                    "Skipped because its if condition is false.".to_string()
+                )
+            ]
+        );
+        assert!(
+            filter_skipped_steps(&raw_pipeline.steps, &build_context("master", "push")).is_empty()
+        );
+    }
+
+    #[test]
+    fn should_reject_filters_with_invalid_regexes() {
+        assert!(validate_filter("master /^release-\\d+$/ !release/*").is_ok());
+        assert!(validate_filter("master !/^release-(/").is_err());
+    }
 }
@@ -0,0 +1,338 @@
+use crate::artifacts::PipelineUpload;
+use crate::github::pull_request::PullRequest;
+use crate::kubernetes::resources::ResourceValues;
+use crate::kubernetes::{RawPipeline, StepType, Wait};
+use crate::pipeline::discovery::PipelinePath;
+use crate::pipeline::steps_filter::{filter_step_sections, BuildContext};
+use either::Either::{Left, Right};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use vec1::Vec1;
+
+// Where steps can upload more steps for the rest of their build, with a token for each step by name
+pub struct PipelineUploadEndpoint {
+    pub url: String,
+    pub tokens: HashMap<String, String>,
+}
+
+// Uploads are a pipeline file with only steps
+#[derive(Deserialize)]
+struct UploadedPipeline {
+    steps: Vec1<StepType>,
+}
+
+// A push and each pull request for a commit run their own copy of each pipeline, so each has its
+// own uploads. Pipeline files are hex encoded, so the build is a single path segment.
+pub fn pipeline_build(pipeline_path: &PipelinePath, pull_request: Option<&PullRequest>) -> String {
+    let build = match pull_request {
+        Some(pull_request) => format!("pull-request-{}", pull_request.number),
+        None => "push".to_string(),
+    };
+
+    if pipeline_path.is_root() {
+        build
+    } else {
+        format!("{}-{}", hex::encode(&pipeline_path.path), build)
+    }
+}
+
+// Uploaded steps go through the same checks and defaults as the pipeline's own. Steps with
+// dependencies are found by their position, which later uploads could change, so can't be uploaded.
+pub fn parse_uploaded_steps(
+    steps: &str,
+    pipeline_defaults: Option<&RawPipeline>,
+    max_step_resources: &ResourceValues,
+) -> Result<Vec1<StepType>, String> {
+    let uploaded_pipeline: UploadedPipeline = serde_yaml::from_str(steps)
+        .map_err(|error| format!("Invalid uploaded steps: {}", error))?;
+
+    for step in uploaded_pipeline.steps.iter() {
+        if step.key().is_some() || step.depends_on().is_some() {
+            let name = match step {
+                StepType::Block(block) => block.name.as_str(),
+                StepType::Step(step) => step.name.as_str(),
+                StepType::Wait(_) => "",
+            };
+
+            return Err(format!(
+                "The uploaded step {} can't have a key or depends_on",
+                name
+            ));
+        }
+    }
+
+    let raw_pipeline = RawPipeline {
+        steps: uploaded_pipeline.steps,
+        timeout_in_minutes: None,
+        resources: pipeline_defaults.and_then(|pipeline| pipeline.resources.clone()),
+        placement: pipeline_defaults
+            .map(|pipeline| pipeline.placement.clone())
+            .unwrap_or_default(),
+        env: pipeline_defaults.and_then(|pipeline| pipeline.env.clone()),
+        paths_filter: pipeline_defaults
+            .map(|pipeline| pipeline.paths_filter.clone())
+            .unwrap_or_default(),
+        report_skipped: pipeline_defaults.and_then(|pipeline| pipeline.report_skipped),
+    };
+
+    raw_pipeline
+        .validate_steps()
+        .map_err(|error| error.to_string())?;
+
+    raw_pipeline
+        .expand_matrices()
+        .apply_placement()
+        .apply_paths_filter()
+        .apply_report_skipped()
+        .apply_env()
+        .resolve_resources(max_step_resources)
+        .map(|raw_pipeline| raw_pipeline.steps)
+        .map_err(|error| error.to_string())
+}
+
+// Uploads run as new step sections straight after the section they were uploaded from, with
+// uploads from the same section running together
+pub fn insert_uploaded_steps(
+    mut raw_pipeline: RawPipeline,
+    uploads: &[PipelineUpload],
+    build_context: &BuildContext,
+    max_step_resources: &ResourceValues,
+) -> Result<RawPipeline, String> {
+    let mut step_sections: Vec<usize> = uploads.iter().map(|upload| upload.step_section).collect();
+
+    step_sections.sort_unstable();
+    step_sections.dedup();
+
+    // Sections after an upload move back, so earlier uploads are inserted first
+    for step_section in step_sections {
+        let mut uploaded_steps = vec![StepType::Wait(Wait::Basic)];
+
+        for upload in uploads
+            .iter()
+            .filter(|upload| upload.step_section == step_section)
+        {
+            uploaded_steps.extend(parse_uploaded_steps(
+                &upload.steps,
+                Some(&raw_pipeline),
+                max_step_resources,
+            )?);
+        }
+
+        let position = step_section_end(&raw_pipeline.steps, build_context, step_section)
+            .unwrap_or_else(|| raw_pipeline.steps.len());
+
+        let steps =
+            insert_keeping_dependent_steps(raw_pipeline.steps.into_vec(), uploaded_steps, position);
+
+        // Inserting only ever adds steps
+        raw_pipeline = RawPipeline {
+            steps: Vec1::try_from_vec(steps).unwrap(),
+            ..raw_pipeline
+        };
+    }
+
+    Ok(raw_pipeline)
+}
+
+// Just after the last step of the section, before anything waiting on it
+fn step_section_end(
+    steps: &[StepType],
+    build_context: &BuildContext,
+    step_section: usize,
+) -> Option<usize> {
+    let step_section = filter_step_sections(steps, build_context)
+        .into_iter()
+        .nth(step_section)?;
+
+    steps
+        .iter()
+        .rposition(|step| match (step, &step_section.steps) {
+            (StepType::Block(block), Left(section_block)) => std::ptr::eq(block, *section_block),
+            (StepType::Step(step), Right(section_steps)) => section_steps
+                .iter()
+                .any(|section_step| std::ptr::eq(step, *section_step)),
+            _ => false,
+        })
+        .map(|index| index + 1)
+}
+
+// Steps with dependencies are identified by their position in the pipeline and run regardless of
+// where they are, so they keep their position while every other step moves around them
+fn insert_keeping_dependent_steps(
+    steps: Vec<StepType>,
+    inserted_steps: Vec<StepType>,
+    position: usize,
+) -> Vec<StepType> {
+    let step_count = steps.len() + inserted_steps.len();
+
+    let mut dependent_steps = Vec::new();
+    let mut other_steps = Vec::with_capacity(step_count);
+    let mut maybe_inserted_steps = Some(inserted_steps);
+
+    for (index, step) in steps.into_iter().enumerate() {
+        if index == position {
+            other_steps.extend(maybe_inserted_steps.take().unwrap_or_default());
+        }
+
+        if step.depends_on().is_some() {
+            dependent_steps.push((index, step));
+        } else {
+            other_steps.push(step);
+        }
+    }
+
+    other_steps.extend(maybe_inserted_steps.unwrap_or_default());
+
+    let mut dependent_steps = dependent_steps.into_iter().peekable();
+    let mut other_steps = other_steps.into_iter();
+
+    (0..step_count)
+        .filter_map(|index| match dependent_steps.peek() {
+            Some((dependent_index, _)) if *dependent_index == index => {
+                dependent_steps.next().map(|(_, step)| step)
+            }
+            _ => other_steps.next(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::steps_filter::filter_dependent_steps;
+
+    fn build_context(branch: &str, event: &'static str) -> BuildContext {
+        BuildContext {
+            branch: branch.to_string(),
+            event,
+            ..Default::default()
+        }
+    }
+
+    fn raw_pipeline(pipeline: &str) -> RawPipeline {
+        serde_yaml::from_str(pipeline).unwrap()
+    }
+
+    fn step_names(steps: &[StepType]) -> Vec<&str> {
+        steps
+            .iter()
+            .map(|step| match step {
+                StepType::Block(block) => block.name.as_str(),
+                StepType::Step(step) => step.name.as_str(),
+                StepType::Wait(_) => "wait",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_run_uploaded_steps_after_the_section_that_uploaded_them() {
+        let pipeline = raw_pipeline(
+            r#"
+steps:
+  - name: generate
+    image: alpine
+    key: generate
+  - wait
+  - name: deploy
+    image: alpine
+  - name: notify
+    image: alpine
+    depends_on: [generate]
+"#,
+        );
+
+        let uploads = vec![PipelineUpload {
+            step_section: 0,
+            steps: "steps:\n  - name: test a\n    image: rust\n  - name: test b\n    image: rust\n"
+                .to_string(),
+        }];
+
+        let pipeline = insert_uploaded_steps(
+            pipeline,
+            &uploads,
+            &build_context("master", "push"),
+            &Default::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            step_names(&pipeline.steps),
+            vec!["generate", "wait", "test a", "notify", "test b", "wait", "deploy"]
+        );
+
+        let step_sections: Vec<Vec<&str>> =
+            filter_step_sections(&pipeline.steps, &build_context("master", "push"))
+                .into_iter()
+                .map(|step_section| {
+                    let mut names: Vec<&str> = step_section
+                        .steps
+                        .right()
+                        .unwrap()
+                        .iter()
+                        .map(|step| step.name.as_str())
+                        .collect();
+                    names.sort_unstable();
+                    names
+                })
+                .collect();
+
+        assert_eq!(
+            step_sections,
+            vec![vec!["generate"], vec!["test a", "test b"], vec!["deploy"]]
+        );
+
+        // The dependent step is still where the running pipeline expects it
+        let dependent_steps =
+            filter_dependent_steps(&pipeline.steps, &build_context("master", "push"));
+
+        assert_eq!(dependent_steps[0].index, 3);
+    }
+
+    #[test]
+    fn should_give_uploaded_steps_the_pipeline_env() {
+        let pipeline = raw_pipeline(
+            r#"
+env:
+  - name: CI
+    value: "true"
+steps:
+  - name: generate
+    image: alpine
+"#,
+        );
+
+        let uploads = vec![PipelineUpload {
+            step_section: 0,
+            steps: "steps:\n  - name: test\n    image: rust\n".to_string(),
+        }];
+
+        let pipeline = insert_uploaded_steps(
+            pipeline,
+            &uploads,
+            &build_context("master", "push"),
+            &Default::default(),
+        )
+        .unwrap();
+
+        match &pipeline.steps[2] {
+            StepType::Step(step) => assert_eq!(step.env.as_ref().unwrap()[0].name(), "CI"),
+            _ => panic!("Expected the uploaded step"),
+        }
+    }
+
+    #[test]
+    fn should_reject_uploaded_steps_with_dependencies() {
+        let error = parse_uploaded_steps(
+            "steps:\n  - name: test\n    image: rust\n    depends_on: [build]\n",
+            None,
+            &Default::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            "The uploaded step test can't have a key or depends_on"
+        );
+        assert!(parse_uploaded_steps("name: test", None, &Default::default()).is_err());
+    }
+}
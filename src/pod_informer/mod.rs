@@ -1,19 +1,32 @@
 use crate::github::client::auth::GithubAuthorisationClient;
 use crate::github::client::installation::GithubInstallationClient;
-use crate::kubernetes::helpers::extract_newly_finished_container_states;
-use crate::pipeline::PipelineService;
+use crate::github::pull_request::PullRequest;
+use crate::kubernetes::artifacts::ARTIFACTS_UPLOAD_CONTAINER_NAME;
+use crate::kubernetes::cache::cache_summary;
+use crate::kubernetes::helpers::{extract_newly_finished_container_states, step_containers};
+use crate::kubernetes::{SoftFail, STEP_TIMED_OUT_MESSAGE};
+use crate::pipeline::discovery::PipelinePath;
+use crate::pipeline::{PipelineService, StepLocation};
 use crate::routes::CompleteCheckRunRequest;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
-use k8s_openapi::api::core::v1::Container;
 use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::core::v1::{Container, ContainerStateTerminated};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::{
-    api::{Api, DeleteParams, ListParams, LogParams, Meta, WatchEvent},
+    api::{Api, DeleteParams, ListParams, LogParams, Meta, PatchParams, WatchEvent},
     runtime::Informer,
 };
 use log::{error, info};
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+// Set once a finished pod has moved the pipeline on, so a pod that is finished again, such as when
+// deleting it failed, is only deleted. Moving on again before it's set is safe, as the pipeline
+// finds what has already started from its check runs.
+const PIPELINE_ADVANCED_ANNOTATION: &str = "pipeline_advanced";
 
 #[derive(Clone)]
 struct RunningPod {
@@ -21,7 +34,14 @@ struct RunningPod {
     installation_id: u32,
     commit_sha: String,
     branch_name: String,
-    step_section: usize,
+    step_section: StepLocation,
+    pull_request: Option<PullRequest>,
+    pipeline_path: PipelinePath,
+    step_rerun: bool,
+    pipeline_deadline: Option<DateTime<Utc>>,
+    // Containers stay finished in every later pod status, so their check runs are only handled
+    // the first time
+    handled_check_run_ids: HashSet<i32>,
 }
 
 pub struct PodInformer {
@@ -34,9 +54,19 @@ pub struct PodInformer {
 
 impl PodInformer {
     pub async fn poll_pods(&self) {
+        let mut running_pods: HashMap<String, RunningPod> = HashMap::new();
+
         let inf = Informer::new(self.pods_api.clone()).params(ListParams::default().timeout(10));
 
-        let mut running_pods: HashMap<String, RunningPod> = HashMap::new();
+        // Start watching from where the reconciliation left off so the pods it has already
+        // handled aren't replayed as added events
+        let inf = match self.reconcile_pods(&mut running_pods).await {
+            Ok(resource_version) => inf.set_version(resource_version),
+            Err(e) => {
+                error!("Encountered error while reconciling pods: {}", e);
+                inf
+            }
+        };
 
         loop {
             let mut pods = inf.poll().await.unwrap().boxed();
@@ -50,6 +80,58 @@ impl PodInformer {
         }
     }
 
+    async fn reconcile_pods(
+        &self,
+        running_pods: &mut HashMap<String, RunningPod>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        info!("Reconciling pods that changed while the controller was down...");
+
+        let list_params = ListParams::default().labels("app=kubesci-step");
+
+        let pods = self.pods_api.list(&list_params).await?;
+
+        for pod in pods.items {
+            if let Err(e) = self.reconcile_pod(&pod, running_pods).await {
+                error!(
+                    "Encountered error while reconciling pod {}: {}",
+                    pod.name(),
+                    e
+                );
+            }
+        }
+
+        Ok(pods
+            .metadata
+            .resource_version
+            .unwrap_or_else(|| "0".to_string()))
+    }
+
+    async fn reconcile_pod(
+        &self,
+        pod: &Pod,
+        running_pods: &mut HashMap<String, RunningPod>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // A pod that is being deleted has already been finished
+        if pod.meta().deletion_timestamp.is_some() || running_pods.contains_key(&pod.name()) {
+            return Ok(());
+        }
+
+        if let Some(mut running_pod) = transform_to_running_pod(pod) {
+            if is_pod_finished(pod) {
+                info!("Pod {} finished while the controller was down", pod.name());
+
+                self.complete_finished_containers(pod, &mut running_pod)
+                    .await?;
+
+                self.finish_pod(pod, &running_pod).await?;
+            } else {
+                running_pods.insert(pod.name(), running_pod);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn handle_pod(
         &self,
         ev: WatchEvent<Pod>,
@@ -59,90 +141,29 @@ impl PodInformer {
             WatchEvent::Added(pod) => {
                 info!("Pod was added: {}", pod.name());
 
-                // TO DO: Add checks to clean up pipelines that finished when controller was down
-                let maybe_running_pod = transform_to_running_pod(&pod);
-
-                if let Some(running_pod) = maybe_running_pod {
-                    running_pods.insert(pod.name(), running_pod);
-                }
+                // Watches that have to resync replay every existing pod as added
+                self.reconcile_pod(&pod, running_pods).await?;
             }
             WatchEvent::Modified(pod) => {
                 info!("Pod was modified: {}", pod.name());
 
-                let maybe_pod = running_pods.get(&pod.name());
-
-                if let Some(running_pod) = maybe_pod {
-                    let maybe_newly_finished_containers =
-                        extract_newly_finished_container_states(&pod);
-
-                    if let Some(newly_finished_pods) = maybe_newly_finished_containers {
-                        for (finished_container_name, finished_container_state) in
-                            newly_finished_pods
-                        {
-                            let maybe_finished_container = pod
-                                .spec
-                                .as_ref()
-                                .map(|pod_spec| pod_spec.containers.as_ref())
-                                .map(|containers: &Vec<Container>| {
-                                    containers
-                                        .iter()
-                                        .find(|container| container.name == finished_container_name)
-                                })
-                                .flatten();
-
-                            if let Some(finished_container) = maybe_finished_container {
-                                let logs = self
-                                    .get_container_logs(&pod.name(), &finished_container.name)
-                                    .await?;
-
-                                let check_run_id = finished_container
-                                    .env
-                                    .as_ref()
-                                    .map(|env| env.iter().find(|env| env.name == "CHECK_RUN_ID"))
-                                    .flatten()
-                                    .unwrap()
-                                    .value
-                                    .as_ref()
-                                    .unwrap();
-
-                                let Time(finished_at) = finished_container_state
-                                    .finished_at
-                                    .unwrap_or_else(|| Time(Utc::now()));
-
-                                self.mark_step_complete(
-                                    running_pod.installation_id,
-                                    check_run_id.parse().unwrap(),
-                                    &running_pod.repo_name,
-                                    &logs,
-                                    &finished_at.to_rfc3339(),
-                                    finished_container_state.exit_code == 0,
-                                )
-                                .await?;
-                            }
-                        }
-                    }
+                if let Some(running_pod) = running_pods.get_mut(&pod.name()) {
+                    let completed_containers =
+                        self.complete_finished_containers(&pod, running_pod).await?;
+
+                    let running_pod = running_pod.clone();
 
-                    if let Some(pod_phase) = pod
-                        .status
-                        .as_ref()
-                        .map(|status| status.phase.as_ref())
-                        .flatten()
+                    if is_pod_finished(&pod) {
+                        self.finish_pod(&pod, &running_pod).await?;
+
+                        running_pods.remove(&pod.name());
+                    } else if completed_containers
+                        && !running_pod.step_rerun
+                        && !has_artifacts_upload_container(&pod)
                     {
-                        if pod_phase == "Succeeded" || pod_phase == "Failed" {
-                            self.pipeline_service
-                                .start_step_section(
-                                    running_pod.installation_id,
-                                    &running_pod.repo_name,
-                                    &running_pod.commit_sha,
-                                    &running_pod.branch_name,
-                                    Some(running_pod.step_section),
-                                )
-                                .await?;
-
-                            running_pods.remove(&pod.name());
-
-                            self.delete_pod(&pod.name()).await?;
-                        }
+                        // Steps can depend on a single step rather than the whole pod, unless
+                        // they'd miss artifacts that are still being uploaded
+                        self.start_dependent_steps(&running_pod).await?;
                     }
                 }
             }
@@ -155,6 +176,181 @@ impl PodInformer {
         Ok(())
     }
 
+    async fn complete_finished_containers(
+        &self,
+        pod: &Pod,
+        running_pod: &mut RunningPod,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let finished_container_states: HashMap<String, ContainerStateTerminated> =
+            extract_newly_finished_container_states(pod)
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+
+        // Kubernetes stops the whole pod once the pipeline deadline passes
+        let pod_timed_out = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.reason.as_deref())
+            == Some("DeadlineExceeded");
+
+        let mut completed_containers = false;
+
+        let repo_name = running_pod.repo_name.clone();
+        // Only created once a container has finished, and then shared by all of them
+        let mut maybe_github_installation_client = None;
+
+        // Services and sidecars, such as the artifacts upload, aren't steps
+        for container in step_containers(pod) {
+            let (finished_at, exit_code, conclusion) =
+                match finished_container_states.get(&container.name) {
+                    Some(finished_container_state) => {
+                        let Time(finished_at) = finished_container_state
+                            .finished_at
+                            .clone()
+                            .unwrap_or_else(|| Time(Utc::now()));
+
+                        let step_timed_out = finished_container_state.message.as_deref()
+                            == Some(STEP_TIMED_OUT_MESSAGE);
+
+                        let conclusion = container_conclusion(
+                            finished_container_state.exit_code,
+                            step_timed_out || pod_timed_out,
+                        );
+
+                        (
+                            finished_at,
+                            Some(finished_container_state.exit_code),
+                            conclusion,
+                        )
+                    }
+                    // Containers that never got to run because the pod was stopped before them
+                    None if is_pod_finished(pod) => {
+                        let conclusion = if pod_timed_out {
+                            "timed_out"
+                        } else {
+                            "failure"
+                        };
+
+                        (Utc::now(), None, conclusion)
+                    }
+                    None => continue,
+                };
+
+            let check_run_id = match container_env(container, "CHECK_RUN_ID")
+                .and_then(|check_run_id| parse_label(&container.name, "CHECK_RUN_ID", check_run_id))
+            {
+                Some(check_run_id) => check_run_id,
+                None => {
+                    error!(
+                        "Container {} in pod {} has no valid CHECK_RUN_ID, skipping it",
+                        container.name,
+                        pod.name()
+                    );
+                    continue;
+                }
+            };
+
+            if running_pod.handled_check_run_ids.contains(&check_run_id) {
+                continue;
+            }
+
+            // Pods from before retries were added won't have an attempt
+            let attempt = container_env(container, "STEP_ATTEMPT")
+                .and_then(|attempt| parse_label(&container.name, "STEP_ATTEMPT", attempt))
+                .unwrap_or(1);
+
+            let soft_fail: Option<SoftFail> = container_env(container, "STEP_SOFT_FAIL")
+                .and_then(|soft_fail| parse_label(&container.name, "STEP_SOFT_FAIL", soft_fail));
+
+            let github_installation_client = match maybe_github_installation_client.take() {
+                Some(github_installation_client) => github_installation_client,
+                None => {
+                    self.github_installation_client(running_pod.installation_id, &repo_name)
+                        .await?
+                }
+            };
+
+            let completed_container = self
+                .mark_step_complete(
+                    &github_installation_client,
+                    running_pod,
+                    &pod.name(),
+                    &container.name,
+                    check_run_id,
+                    attempt,
+                    &finished_at.to_rfc3339(),
+                    exit_code,
+                    conclusion,
+                    soft_fail.as_ref(),
+                )
+                .await?;
+
+            maybe_github_installation_client = Some(github_installation_client);
+
+            running_pod.handled_check_run_ids.insert(check_run_id);
+            completed_containers |= completed_container;
+        }
+
+        Ok(completed_containers)
+    }
+
+    async fn finish_pod(
+        &self,
+        pod: &Pod,
+        running_pod: &RunningPod,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if has_advanced_pipeline(pod) {
+            info!("Pod {} has already moved the pipeline on", pod.name());
+        } else if running_pod.step_rerun {
+            // Rerunning a single step shouldn't run the rest of the pipeline again
+            info!(
+                "Pod {} was a step rerun, not continuing pipeline",
+                pod.name()
+            );
+        } else {
+            if let StepLocation::Section(step_section) = running_pod.step_section {
+                self.pipeline_service
+                    .finish_step_section(
+                        running_pod.installation_id,
+                        &running_pod.repo_name,
+                        &running_pod.commit_sha,
+                        &running_pod.branch_name,
+                        step_section,
+                        running_pod.pull_request.as_ref(),
+                        &running_pod.pipeline_path,
+                        running_pod.pipeline_deadline,
+                    )
+                    .await?;
+            }
+
+            self.start_dependent_steps(running_pod).await?;
+
+            self.mark_pipeline_advanced(&pod.name()).await?;
+        }
+
+        self.delete_pod(&pod.name()).await?;
+
+        Ok(())
+    }
+
+    async fn start_dependent_steps(
+        &self,
+        running_pod: &RunningPod,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.pipeline_service
+            .start_dependent_steps(
+                running_pod.installation_id,
+                &running_pod.repo_name,
+                &running_pod.commit_sha,
+                &running_pod.branch_name,
+                running_pod.pull_request.as_ref(),
+                &running_pod.pipeline_path,
+                running_pod.pipeline_deadline,
+            )
+            .await
+    }
+
     async fn get_container_logs(
         &self,
         pod_name: &str,
@@ -168,46 +364,164 @@ impl PodInformer {
         self.pods_api.logs(pod_name, &lp).await
     }
 
+    async fn mark_pipeline_advanced(
+        &self,
+        pod_name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let annotation_patch = json!({
+            "metadata": {
+                "annotations": {
+                    PIPELINE_ADVANCED_ANNOTATION: "true"
+                }
+            }
+        });
+
+        self.pods_api
+            .patch(
+                pod_name,
+                &PatchParams::default(),
+                serde_json::to_vec(&annotation_patch)?,
+            )
+            .await?;
+
+        Ok(())
+    }
+
     async fn delete_pod(&self, pod_name: &str) -> Result<(), kube::error::Error> {
         let dp = DeleteParams::default();
 
         self.pods_api.delete(pod_name, &dp).await.map(|_result| ())
     }
 
-    async fn mark_step_complete(
-        &self,
+    async fn github_installation_client<'a>(
+        &'a self,
         installation_id: u32,
-        check_run_id: i32,
-        repo_name: &str,
-        logs: &str,
-        finished_at: &str,
-        successful: bool,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let github_authorisation_client =
-            GithubAuthorisationClient::new(&self.github_private_key, &self.application_id)?;
+        repo_name: &'a str,
+    ) -> Result<GithubInstallationClient<'a>, Box<dyn std::error::Error>> {
+        let github_authorisation_client = GithubAuthorisationClient::new(
+            &self.github_private_key,
+            &self.application_id,
+            &self.github_base_url,
+        )?;
 
         let installation_access_token = github_authorisation_client
             .get_installation_access_token(installation_id)
             .await?;
 
-        let github_installation_client = GithubInstallationClient {
+        Ok(GithubInstallationClient {
             repository_name: repo_name,
             github_installation_token: installation_access_token,
             base_url: &self.github_base_url,
-        };
+        })
+    }
+
+    // Whether the step's check run was completed, rather than already being complete or retried
+    #[allow(clippy::too_many_arguments)]
+    async fn mark_step_complete(
+        &self,
+        github_installation_client: &GithubInstallationClient<'_>,
+        running_pod: &RunningPod,
+        pod_name: &str,
+        container_name: &str,
+        check_run_id: i32,
+        attempt: u32,
+        finished_at: &str,
+        exit_code: Option<i32>,
+        conclusion: &str,
+        soft_fail: Option<&SoftFail>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let repo_name = &running_pod.repo_name;
 
         let check_run = github_installation_client
             .get_check_run(check_run_id)
             .await?;
 
-        let conclusion = if successful { "success" } else { "failure" };
+        if check_run.status == "completed" {
+            info!("Check run {} has already been completed", check_run_id);
+
+            return Ok(false);
+        }
+
+        if attempt < started_attempt(check_run.output.summary.as_deref()) {
+            info!(
+                "Check run {} has already been retried after attempt {}",
+                check_run_id, attempt
+            );
+
+            return Ok(false);
+        }
+
+        // Containers that never got to run may have no logs to get
+        let logs = if exit_code.is_some() {
+            self.get_container_logs(pod_name, container_name).await?
+        } else {
+            self.get_container_logs(pod_name, container_name)
+                .await
+                .unwrap_or_default()
+        };
+
+        if conclusion != "success" {
+            let retried = self
+                .pipeline_service
+                .retry_step(
+                    running_pod.installation_id,
+                    repo_name,
+                    &running_pod.commit_sha,
+                    &running_pod.branch_name,
+                    running_pod.step_section,
+                    running_pod.pull_request.as_ref(),
+                    &running_pod.pipeline_path,
+                    running_pod.step_rerun,
+                    running_pod.pipeline_deadline,
+                    &check_run.name,
+                    check_run_id as u32,
+                    attempt,
+                    exit_code,
+                )
+                .await?;
+
+            if retried {
+                github_installation_client
+                    .update_check_run_output(
+                        check_run_id,
+                        &check_run.name,
+                        &format!("{}{}", RETRYING_SUMMARY_PREFIX, attempt + 1),
+                        &attempt_logs(check_run.output.text.as_deref(), attempt, conclusion, &logs),
+                    )
+                    .await?;
+
+                return Ok(false);
+            }
+        }
+
+        // Soft failures are reported as neutral, which doesn't fail the step section
+        let conclusion = match soft_fail {
+            Some(soft_fail) if conclusion != "success" && soft_fail.allows(exit_code) => "neutral",
+            _ => conclusion,
+        };
+
+        // Logs are only split up by attempt once a step has been retried
+        let (summary, logs) = if attempt > 1 {
+            (
+                format!("Complete after {} attempts!", attempt),
+                attempt_logs(check_run.output.text.as_deref(), attempt, conclusion, &logs),
+            )
+        } else {
+            ("Complete!".to_string(), logs)
+        };
+
+        let summary = match cache_summary(&logs) {
+            Some(cache_summary) => format!("{}\n\n{}", summary, cache_summary),
+            None => summary,
+        };
 
         let complete_check_run_request = CompleteCheckRunRequest {
             repo_name: repo_name.to_string(),
             check_run_id,
             status: "completed".to_string(),
             finished_at: Some(finished_at.to_string()),
-            logs: logs.to_string(),
+            summary,
+            logs,
             conclusion: Some(conclusion.to_string()),
         };
 
@@ -220,10 +534,70 @@ impl PodInformer {
             )
             .await?;
 
-        Ok(())
+        Ok(true)
     }
 }
 
+// Retried check runs stay in progress, with the attempt that is running in their summary
+const RETRYING_SUMMARY_PREFIX: &str = "Retrying, attempt ";
+
+fn started_attempt(summary: Option<&str>) -> u32 {
+    summary
+        .and_then(|summary| summary.strip_prefix(RETRYING_SUMMARY_PREFIX))
+        .and_then(|attempt| attempt.parse().ok())
+        .unwrap_or(1)
+}
+
+fn attempt_logs(previous_logs: Option<&str>, attempt: u32, conclusion: &str, logs: &str) -> String {
+    format!(
+        "{}--- Attempt {} ({}) ---\n{}\n",
+        previous_logs.unwrap_or_default(),
+        attempt,
+        conclusion,
+        logs
+    )
+}
+
+fn container_env<'a>(container: &'a Container, name: &str) -> Option<&'a String> {
+    container
+        .env
+        .as_ref()
+        .and_then(|envs| envs.iter().find(|env| env.name == name))
+        .and_then(|env| env.value.as_ref())
+}
+
+fn has_artifacts_upload_container(pod: &Pod) -> bool {
+    pod.spec
+        .as_ref()
+        .map(|pod_spec| {
+            pod_spec
+                .containers
+                .iter()
+                .any(|container| container.name == ARTIFACTS_UPLOAD_CONTAINER_NAME)
+        })
+        .unwrap_or(false)
+}
+
+fn container_conclusion(exit_code: i32, timed_out: bool) -> &'static str {
+    if exit_code == 0 {
+        "success"
+    } else if timed_out {
+        "timed_out"
+    } else {
+        "failure"
+    }
+}
+
+fn pod_phase(pod: &Pod) -> Option<&str> {
+    pod.status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref())
+}
+
+fn is_pod_finished(pod: &Pod) -> bool {
+    matches!(pod_phase(pod), Some("Succeeded") | Some("Failed"))
+}
+
 fn transform_to_running_pod(pod: &Pod) -> Option<RunningPod> {
     pod.meta()
         .labels
@@ -249,14 +623,195 @@ fn transform_to_running_pod(pod: &Pod) -> Option<RunningPod> {
                     Some(commit_sha),
                     Some(step_section),
                 ) => Some(RunningPod {
-                    installation_id: installation_id.clone().parse().unwrap(),
+                    installation_id: parse_label(&pod.name(), "installation_id", installation_id)?,
                     repo_name: repo_name.clone().replace(".", "/"),
                     branch_name: branch_name.clone(),
                     commit_sha: commit_sha.clone(),
-                    step_section: step_section.clone().parse().unwrap(),
+                    step_section: parse_label(&pod.name(), "step_section", step_section)?,
+                    pull_request: transform_to_pull_request(pod),
+                    pipeline_path: transform_to_pipeline_path(pod),
+                    pipeline_deadline: transform_to_pipeline_deadline(pod),
+                    step_rerun: labels
+                        .get("step_rerun")
+                        .map(|step_rerun| step_rerun == "true")
+                        .unwrap_or(false),
+                    handled_check_run_ids: HashSet::new(),
                 }),
                 _ => None,
             }
         })
         .flatten()
 }
+
+// Pods can be edited by hand, so bad values are logged and ignored rather than panicking the
+// informer
+fn parse_label<T>(owner: &str, name: &str, value: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(error) => {
+            error!("{} has an invalid {} of {}: {}", owner, name, value, error);
+            None
+        }
+    }
+}
+
+fn has_advanced_pipeline(pod: &Pod) -> bool {
+    pod.meta()
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(PIPELINE_ADVANCED_ANNOTATION))
+        .is_some()
+}
+
+fn transform_to_pipeline_deadline(pod: &Pod) -> Option<DateTime<Utc>> {
+    pod.meta()
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get("pipeline_deadline"))
+        .and_then(|pipeline_deadline| DateTime::parse_from_rfc3339(pipeline_deadline).ok())
+        .map(|pipeline_deadline| pipeline_deadline.with_timezone(&Utc))
+}
+
+// Only pods of the repo's other pipelines have the annotation
+fn transform_to_pipeline_path(pod: &Pod) -> PipelinePath {
+    pod.meta()
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get("pipeline_path"))
+        .map(|path| PipelinePath { path: path.clone() })
+        .unwrap_or_else(PipelinePath::root)
+}
+
+fn transform_to_pull_request(pod: &Pod) -> Option<PullRequest> {
+    pod.meta().annotations.as_ref().and_then(|annotations| {
+        let maybe_number = annotations.get("pull_request_number");
+        let maybe_base_branch = annotations.get("pull_request_base_branch");
+        let maybe_head_branch = annotations.get("pull_request_head_branch");
+
+        match (maybe_number, maybe_base_branch, maybe_head_branch) {
+            (Some(number), Some(base_branch), Some(head_branch)) => Some(PullRequest {
+                number: parse_label(&pod.name(), "pull_request_number", number)?,
+                base_branch: base_branch.clone(),
+                head_branch: head_branch.clone(),
+            }),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    #[test]
+    fn should_read_started_attempt_from_retrying_summary() {
+        assert_eq!(started_attempt(None), 1);
+        assert_eq!(started_attempt(Some("Complete!")), 1);
+        assert_eq!(started_attempt(Some("Retrying, attempt 3")), 3);
+    }
+
+    #[test]
+    fn should_only_report_steps_as_timed_out_when_they_were_stopped() {
+        assert_eq!(container_conclusion(0, false), "success");
+        assert_eq!(container_conclusion(124, false), "failure");
+        assert_eq!(container_conclusion(124, true), "timed_out");
+        assert_eq!(container_conclusion(137, true), "timed_out");
+    }
+
+    #[test]
+    fn should_know_when_a_pod_has_moved_the_pipeline_on() {
+        let mut pod = Pod {
+            metadata: Some(Default::default()),
+            ..Default::default()
+        };
+
+        assert!(!has_advanced_pipeline(&pod));
+
+        pod.metadata.as_mut().unwrap().annotations = Some(
+            vec![(PIPELINE_ADVANCED_ANNOTATION.to_string(), "true".to_string())]
+                .into_iter()
+                .collect(),
+        );
+
+        assert!(has_advanced_pipeline(&pod));
+    }
+
+    #[test]
+    fn should_ignore_pods_with_invalid_labels() {
+        let labels = |installation_id: &str| {
+            vec![
+                ("installation_id", installation_id),
+                ("repo_name", "some.repo"),
+                ("branch_name", "master"),
+                ("commit_sha", "abc123"),
+                ("step_section", "0"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+        };
+
+        let mut pod = Pod {
+            metadata: Some(ObjectMeta {
+                name: Some("some-pod".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        pod.metadata.as_mut().unwrap().labels = Some(labels("1234"));
+        assert_eq!(
+            transform_to_running_pod(&pod).unwrap().installation_id,
+            1234
+        );
+
+        pod.metadata.as_mut().unwrap().labels = Some(labels("not-a-number"));
+        assert!(transform_to_running_pod(&pod).is_none());
+    }
+
+    // Finished pods are annotated once they've moved the pipeline on, which the role has to allow
+    #[test]
+    fn should_be_allowed_to_annotate_pods_by_the_shipped_role() {
+        let install = include_str!("../../deployment/resources/install.yaml");
+
+        let role: serde_yaml::Value = install
+            .split("\n---\n")
+            .map(|document| serde_yaml::from_str::<serde_yaml::Value>(document).unwrap())
+            .find(|document| document["kind"] == "Role")
+            .unwrap();
+
+        let pod_verbs: Vec<&str> = role["rules"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .filter(|rule| {
+                rule["resources"]
+                    .as_sequence()
+                    .unwrap()
+                    .contains(&serde_yaml::Value::from("pods"))
+            })
+            .flat_map(|rule| rule["verbs"].as_sequence().unwrap())
+            .filter_map(|verb| verb.as_str())
+            .collect();
+
+        for verb in &["get", "watch", "list", "create", "delete", "patch"] {
+            assert!(pod_verbs.contains(verb), "The role can't {} pods", verb);
+        }
+    }
+
+    #[test]
+    fn should_keep_logs_of_every_attempt() {
+        let first_attempt = attempt_logs(None, 1, "failure", "killed");
+        let second_attempt = attempt_logs(Some(&first_attempt), 2, "success", "passed");
+
+        assert_eq!(
+            second_attempt,
+            "--- Attempt 1 (failure) ---\nkilled\n--- Attempt 2 (success) ---\npassed\n"
+        );
+    }
+}
@@ -1,10 +1,38 @@
+use crate::artifacts::{
+    uri_decode, verify_artifact_token, verify_cache_token, verify_pipeline_upload_token,
+    ContentStream,
+};
+use crate::github::signature::verify_signature;
+use futures::{Stream, TryStreamExt};
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
-use warp::{filters::BoxedFilter, Filter};
+use std::pin::Pin;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::path::Tail;
+use warp::{filters::BoxedFilter, reject::Reject, Buf, Filter, Rejection, Reply};
+
+#[derive(Debug)]
+pub struct InvalidSignature;
+
+impl Reject for InvalidSignature {}
+
+#[derive(Debug)]
+pub struct InvalidBody;
+
+impl Reject for InvalidBody {}
+
+#[derive(Debug)]
+pub struct InvalidToken;
+
+impl Reject for InvalidToken {}
 
 #[derive(Deserialize)]
 pub struct CheckSuite {
     pub head_sha: String,
     pub head_branch: String,
+    #[serde(default)]
+    pub pull_requests: Vec<PullRequest>,
 }
 
 #[derive(Deserialize)]
@@ -13,6 +41,33 @@ pub struct CheckRun {
     pub check_suite: CheckSuite,
     pub started_at: String,
     pub name: String,
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub pull_requests: Vec<PullRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct PullRequestBranch {
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub sha: String,
+}
+
+#[derive(Deserialize)]
+pub struct PullRequest {
+    pub number: u64,
+    pub head: PullRequestBranch,
+    pub base: PullRequestBranch,
+}
+
+impl PullRequest {
+    pub fn to_pull_request(&self) -> crate::github::pull_request::PullRequest {
+        crate::github::pull_request::PullRequest {
+            number: self.number,
+            base_branch: self.base.branch.clone(),
+            head_branch: self.head.branch.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -47,12 +102,21 @@ pub struct GithubCheckRunRequest {
     pub requested_action: Option<RequestedAction>,
 }
 
+#[derive(Deserialize)]
+pub struct GithubPullRequestRequest {
+    pub action: String,
+    pub pull_request: PullRequest,
+    pub installation: Installation,
+    pub repository: Repository,
+}
+
 #[derive(Deserialize)]
 pub struct CompleteCheckRunRequest {
     pub repo_name: String,
     pub check_run_id: i32,
     pub status: String,
     pub finished_at: Option<String>,
+    pub summary: String,
     pub logs: String,
     pub conclusion: Option<String>,
 }
@@ -65,23 +129,203 @@ pub struct PodSuccessfullyFinishedRequest {
     pub commit_sha: String,
 }
 
-pub fn check_suite_route() -> BoxedFilter<(GithubCheckSuiteRequest,)> {
+#[derive(Deserialize)]
+pub struct ListArtifactsQuery {
+    pub glob: Option<String>,
+    // Pods list artifacts with plain shell tools, so can ask for one path per line
+    pub format: Option<String>,
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct CacheQuery {
+    token: String,
+    fallback_branch: Option<String>,
+}
+
+pub fn check_suite_route(webhook_secret: String) -> BoxedFilter<(GithubCheckSuiteRequest,)> {
     let check_suite_header = warp::header::exact("X-GitHub-Event", "check_suite");
 
     warp::post()
         .and(warp::path("webhook"))
         .and(check_suite_header)
-        .and(warp::body::json::<GithubCheckSuiteRequest>())
+        .and(signed_json_body::<GithubCheckSuiteRequest>(webhook_secret))
         .boxed()
 }
 
-pub fn check_run_route() -> BoxedFilter<(GithubCheckRunRequest,)> {
+pub fn check_run_route(webhook_secret: String) -> BoxedFilter<(GithubCheckRunRequest,)> {
     let check_run_header = warp::header::exact("X-GitHub-Event", "check_run");
 
     warp::post()
         .and(warp::path("webhook"))
         .and(check_run_header)
-        .and(warp::body::json::<GithubCheckRunRequest>())
+        .and(signed_json_body::<GithubCheckRunRequest>(webhook_secret))
+        .boxed()
+}
+
+pub fn pull_request_route(webhook_secret: String) -> BoxedFilter<(GithubPullRequestRequest,)> {
+    let pull_request_header = warp::header::exact("X-GitHub-Event", "pull_request");
+
+    warp::post()
+        .and(warp::path("webhook"))
+        .and(pull_request_header)
+        .and(signed_json_body::<GithubPullRequestRequest>(webhook_secret))
+        .boxed()
+}
+
+// GitHub caps webhook payloads at 25MB, so anything bigger isn't worth reading to check
+const MAX_WEBHOOK_BODY_SIZE: u64 = 25 * 1024 * 1024;
+
+// The signature has to be checked against the raw bytes GitHub sent, so the body is only
+// deserialized once it has been verified
+fn signed_json_body<T>(webhook_secret: String) -> BoxedFilter<(T,)>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    warp::header::optional::<String>("X-Hub-Signature-256")
+        .and(warp::body::content_length_limit(MAX_WEBHOOK_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and_then(move |maybe_signature: Option<String>, body: Bytes| {
+            let is_signature_valid = maybe_signature
+                .map(|signature| verify_signature(&webhook_secret, &signature, &body))
+                .unwrap_or(false);
+
+            async move {
+                if !is_signature_valid {
+                    return Err(warp::reject::custom(InvalidSignature));
+                }
+
+                serde_json::from_slice::<T>(&body).map_err(|_| warp::reject::custom(InvalidBody))
+            }
+        })
+        .boxed()
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<InvalidSignature>().is_some() {
+        Ok(warp::reply::with_status(
+            "Invalid webhook signature".to_string(),
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else if rejection.find::<InvalidToken>().is_some() {
+        Ok(warp::reply::with_status(
+            "Invalid token".to_string(),
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else if rejection.find::<InvalidBody>().is_some() {
+        Ok(warp::reply::with_status(
+            "Unable to decode webhook body".to_string(),
+            StatusCode::BAD_REQUEST,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+// Artifacts can hold secrets, so listing and downloading them needs the commit's token too
+pub fn list_artifacts_route(
+    webhook_secret: String,
+) -> BoxedFilter<(String, String, ListArtifactsQuery)> {
+    warp::get()
+        .and(warp::path!("artifacts" / String / String))
+        .and(warp::query::<ListArtifactsQuery>())
+        .and_then(
+            move |repo_name: String, commit_sha: String, query: ListArtifactsQuery| {
+                let is_token_valid =
+                    verify_artifact_token(&webhook_secret, &repo_name, &commit_sha, &query.token);
+
+                async move {
+                    if is_token_valid {
+                        Ok((repo_name, commit_sha, query))
+                    } else {
+                        Err(warp::reject::custom(InvalidToken))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .boxed()
+}
+
+pub fn get_artifact_route(webhook_secret: String) -> BoxedFilter<(String, String, Tail)> {
+    warp::get()
+        .and(warp::path("artifacts"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail())
+        .and(warp::query::<TokenQuery>())
+        .and_then(
+            move |repo_name: String, commit_sha: String, path: Tail, query: TokenQuery| {
+                let is_token_valid =
+                    verify_artifact_token(&webhook_secret, &repo_name, &commit_sha, &query.token);
+
+                async move {
+                    if is_token_valid {
+                        Ok((repo_name, commit_sha, path))
+                    } else {
+                        Err(warp::reject::custom(InvalidToken))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .boxed()
+}
+
+// Uploads over the max size are turned away before any of their body is read
+fn content_stream(
+    max_size: u64,
+) -> impl Filter<Extract = (ContentStream,), Error = Rejection> + Copy {
+    warp::body::content_length_limit(max_size)
+        .and(warp::header::<u64>("content-length"))
+        .and(warp::body::stream())
+        .map(|length: u64, body| ContentStream {
+            length,
+            chunks: body_chunks(body),
+        })
+}
+
+fn body_chunks(
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync>> {
+    Box::pin(
+        body.map_ok(|mut chunk| chunk.to_bytes())
+            .map_err(std::io::Error::other),
+    )
+}
+
+// Uploads come from step pods, which are given a token for their commit
+pub fn upload_artifact_route(
+    webhook_secret: String,
+    max_artifact_size: u64,
+) -> BoxedFilter<(String, String, Tail, ContentStream)> {
+    warp::post()
+        .and(warp::path("artifacts"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail())
+        .and(warp::query::<TokenQuery>())
+        .and_then(
+            move |repo_name: String, commit_sha: String, path: Tail, query: TokenQuery| {
+                let is_token_valid =
+                    verify_artifact_token(&webhook_secret, &repo_name, &commit_sha, &query.token);
+
+                async move {
+                    if is_token_valid {
+                        Ok((repo_name, commit_sha, path))
+                    } else {
+                        Err(warp::reject::custom(InvalidToken))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .and(content_stream(max_artifact_size))
         .boxed()
 }
 
@@ -97,10 +341,118 @@ pub fn get_pipeline_steps_route() -> BoxedFilter<(String, String)> {
     warp::path!("pipelines" / String / String).boxed()
 }
 
+// Restores can fall back to another branch of the repo, as reading its caches can't change them
+pub fn get_cache_route(
+    webhook_secret: String,
+) -> BoxedFilter<(String, String, Option<String>, String)> {
+    warp::get()
+        .and(warp::path!("caches" / String / String / String))
+        .and(warp::query::<CacheQuery>())
+        .and_then(
+            move |repo_name: String, branch_name: String, cache_key: String, query: CacheQuery| {
+                let branch_name = uri_decode(&branch_name);
+
+                let is_token_valid =
+                    verify_cache_token(&webhook_secret, &repo_name, &branch_name, &query.token);
+
+                let fallback_branch_name = query
+                    .fallback_branch
+                    .filter(|fallback_branch| !fallback_branch.is_empty());
+
+                async move {
+                    if is_token_valid {
+                        Ok((repo_name, branch_name, fallback_branch_name, cache_key))
+                    } else {
+                        Err(warp::reject::custom(InvalidToken))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .boxed()
+}
+
+// Saves come from step pods, which are given a token for their repo and branch. A cache bigger
+// than all of them are allowed to be would only evict every other one before being evicted itself.
+pub fn upload_cache_route(
+    webhook_secret: String,
+    max_cache_size: u64,
+) -> BoxedFilter<(String, String, String, ContentStream)> {
+    warp::post()
+        .and(warp::path!("caches" / String / String / String))
+        .and(warp::query::<TokenQuery>())
+        .and_then(
+            move |repo_name: String, branch_name: String, cache_key: String, query: TokenQuery| {
+                let branch_name = uri_decode(&branch_name);
+
+                let is_token_valid =
+                    verify_cache_token(&webhook_secret, &repo_name, &branch_name, &query.token);
+
+                async move {
+                    if is_token_valid {
+                        Ok((repo_name, branch_name, cache_key))
+                    } else {
+                        Err(warp::reject::custom(InvalidToken))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .and(content_stream(max_cache_size))
+        .boxed()
+}
+
+// Uploads come from step pods, which are given a token for each of their steps. They're parsed
+// before being saved, so are read whole, up to the max size.
+pub fn upload_pipeline_route(
+    webhook_secret: String,
+    max_pipeline_upload_size: u64,
+) -> BoxedFilter<(String, String, String, usize, String, Bytes)> {
+    warp::post()
+        .and(warp::path!(
+            "pipeline-uploads" / String / String / String / usize / String
+        ))
+        .and(warp::query::<TokenQuery>())
+        .and_then(
+            move |repo_name: String,
+                  commit_sha: String,
+                  build: String,
+                  step_section: usize,
+                  step_id: String,
+                  query: TokenQuery| {
+                let is_token_valid = verify_pipeline_upload_token(
+                    &webhook_secret,
+                    &repo_name,
+                    &commit_sha,
+                    &build,
+                    step_section,
+                    &step_id,
+                    &query.token,
+                );
+
+                async move {
+                    if is_token_valid {
+                        Ok((repo_name, commit_sha, build, step_section, step_id))
+                    } else {
+                        Err(warp::reject::custom(InvalidToken))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .and(warp::body::content_length_limit(max_pipeline_upload_size))
+        .and(warp::body::bytes())
+        .boxed()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hmac::{Hmac, Mac, NewMac};
     use serde_json::json;
+    use sha2::Sha256;
+
+    const WEBHOOK_SECRET: &str = "some-webhook-secret";
 
     async fn check_suite_test_handler(
         _check_suite_request: GithubCheckSuiteRequest,
@@ -108,11 +460,15 @@ mod tests {
         Ok(warp::reply())
     }
 
-    #[tokio::test]
-    async fn should_respond_to_check_suite_request() {
-        let route = check_suite_route().and_then(check_suite_test_handler);
+    fn sign(body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(WEBHOOK_SECRET.as_bytes()).unwrap();
+        mac.update(body);
 
-        let body = json!({
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn check_suite_body() -> String {
+        json!({
             "action": "complete",
             "check_suite": {
                 "head_sha": "asnkqf1",
@@ -124,27 +480,71 @@ mod tests {
             "repository": {
                 "full_name": "test-repo"
             }
-        });
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_check_suite_request() {
+        let route = check_suite_route(WEBHOOK_SECRET.to_string())
+            .and_then(check_suite_test_handler)
+            .recover(handle_rejection);
+
+        let body = check_suite_body();
 
         let response = warp::test::request()
             .method("POST")
             .path("/webhook")
             .header("X-GitHub-Event", "check_suite")
-            .json(&body)
+            .header("X-Hub-Signature-256", sign(body.as_bytes()))
+            .body(&body)
             .reply(&route)
             .await;
 
         assert_eq!(response.status(), 200)
     }
 
+    #[test]
+    fn should_read_the_pull_requests_of_a_check_suite() {
+        let request: GithubCheckSuiteRequest = serde_json::from_value(json!({
+            "action": "rerequested",
+            "check_suite": {
+                "head_sha": "asnkqf1",
+                "head_branch": "test",
+                "pull_requests": [{
+                    "number": 4,
+                    "head": { "ref": "test", "sha": "asnkqf1" },
+                    "base": { "ref": "main", "sha": "bcd1234" }
+                }]
+            },
+            "installation": {
+                "id": 12345
+            },
+            "repository": {
+                "full_name": "test-repo"
+            }
+        }))
+        .unwrap();
+
+        let pull_request = request.check_suite.pull_requests[0].to_pull_request();
+
+        assert_eq!(pull_request.number, 4);
+        assert_eq!(pull_request.base_branch, "main");
+        assert_eq!(pull_request.head_branch, "test");
+    }
+
     #[tokio::test]
     async fn should_respond_with_bad_request_if_check_suite_request_not_in_body() {
-        let route = check_suite_route().and_then(check_suite_test_handler);
+        let route = check_suite_route(WEBHOOK_SECRET.to_string())
+            .and_then(check_suite_test_handler)
+            .recover(handle_rejection);
 
         let response = warp::test::request()
             .method("POST")
             .path("/webhook")
             .header("X-GitHub-Event", "check_suite")
+            .header("X-Hub-Signature-256", sign(b""))
+            .body("")
             .reply(&route)
             .await;
 
@@ -153,13 +553,107 @@ mod tests {
 
     #[tokio::test]
     async fn should_respond_with_bad_request_if_no_check_suite_header() {
-        let route = check_suite_route().and_then(check_suite_test_handler);
+        let route = check_suite_route(WEBHOOK_SECRET.to_string())
+            .and_then(check_suite_test_handler)
+            .recover(handle_rejection);
+
+        let body = check_suite_body();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/webhook")
+            .header("X-Hub-Signature-256", sign(body.as_bytes()))
+            .body(&body)
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 400)
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_unauthorized_if_signature_does_not_match() {
+        let route = check_suite_route(WEBHOOK_SECRET.to_string())
+            .and_then(check_suite_test_handler)
+            .recover(handle_rejection);
+
+        let body = check_suite_body();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/webhook")
+            .header("X-GitHub-Event", "check_suite")
+            .header("X-Hub-Signature-256", sign(b"some other body"))
+            .body(&body)
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 401)
+    }
+
+    #[tokio::test]
+    async fn should_turn_away_webhooks_over_the_max_size() {
+        let route = check_suite_route(WEBHOOK_SECRET.to_string())
+            .and_then(check_suite_test_handler)
+            .recover(handle_rejection);
+
+        let body = check_suite_body();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/webhook")
+            .header("X-GitHub-Event", "check_suite")
+            .header("X-Hub-Signature-256", sign(body.as_bytes()))
+            .body(&body)
+            .header("content-length", (MAX_WEBHOOK_BODY_SIZE + 1).to_string())
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 413)
+    }
+
+    #[tokio::test]
+    async fn should_respond_with_unauthorized_if_no_signature_header() {
+        let route = check_suite_route(WEBHOOK_SECRET.to_string())
+            .and_then(check_suite_test_handler)
+            .recover(handle_rejection);
+
+        let body = check_suite_body();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/webhook")
+            .header("X-GitHub-Event", "check_suite")
+            .body(&body)
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 401)
+    }
+
+    #[tokio::test]
+    async fn should_respond_to_pull_request_request() {
+        async fn pull_request_test_handler(
+            _pull_request_request: GithubPullRequestRequest,
+        ) -> std::result::Result<impl warp::reply::Reply, warp::Rejection> {
+            Ok(warp::reply())
+        }
+
+        let route = pull_request_route(WEBHOOK_SECRET.to_string())
+            .and_then(pull_request_test_handler)
+            .recover(handle_rejection);
 
         let body = json!({
-            "action": "complete",
-            "check_suite": {
-                "head_sha": "asnkqf1",
-                "head_branch": "test"
+            "action": "opened",
+            "pull_request": {
+                "number": 12,
+                "head": {
+                    "ref": "some-feature",
+                    "sha": "asnkqf1"
+                },
+                "base": {
+                    "ref": "master",
+                    "sha": "bsnkqf2"
+                }
             },
             "installation": {
                 "id": 12345
@@ -167,15 +661,238 @@ mod tests {
             "repository": {
                 "full_name": "test-repo"
             }
-        });
+        })
+        .to_string();
 
         let response = warp::test::request()
             .method("POST")
             .path("/webhook")
-            .json(&body)
+            .header("X-GitHub-Event", "pull_request")
+            .header("X-Hub-Signature-256", sign(body.as_bytes()))
+            .body(&body)
             .reply(&route)
             .await;
 
-        assert_eq!(response.status(), 400)
+        assert_eq!(response.status(), 200)
+    }
+
+    async fn cache_upload_test_handler(
+        _repo_name: String,
+        branch_name: String,
+        _cache_key: String,
+        _contents: ContentStream,
+    ) -> std::result::Result<impl warp::reply::Reply, warp::Rejection> {
+        Ok(branch_name)
+    }
+
+    #[tokio::test]
+    async fn should_only_accept_cache_uploads_with_the_branch_token() {
+        let route = upload_cache_route(WEBHOOK_SECRET.to_string(), 8)
+            .and_then(cache_upload_test_handler)
+            .recover(handle_rejection);
+
+        let token = crate::artifacts::cache_token(WEBHOOK_SECRET, "some.repo", "feature/x");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/caches/some.repo/feature%2Fx/cargo-abc?token={}",
+                token
+            ))
+            .body("cache")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "feature/x");
+
+        for path in &[
+            "/caches/other.repo/feature%2Fx/cargo-abc",
+            "/caches/some.repo/main/cargo-abc",
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path(&format!("{}?token={}", path, token))
+                .body("cache")
+                .reply(&route)
+                .await;
+
+            assert_eq!(response.status(), 401);
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/caches/some.repo/feature%2Fx/cargo-abc?token={}",
+                token
+            ))
+            .body("a much bigger cache")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 413);
+    }
+
+    async fn get_artifact_test_handler(
+        _repo_name: String,
+        _commit_sha: String,
+        _path: Tail,
+    ) -> std::result::Result<impl warp::reply::Reply, warp::Rejection> {
+        Ok(warp::reply())
+    }
+
+    #[tokio::test]
+    async fn should_only_serve_artifacts_with_the_commit_token() {
+        let route = get_artifact_route(WEBHOOK_SECRET.to_string())
+            .and_then(get_artifact_test_handler)
+            .recover(handle_rejection);
+
+        let commit_sha = "0123456789abcdef0123456789abcdef01234567";
+        let token = crate::artifacts::artifact_token(WEBHOOK_SECRET, "some/repo", commit_sha);
+
+        let response = warp::test::request()
+            .path(&format!(
+                "/artifacts/some.repo/{}/dist/app?token={}",
+                commit_sha, token
+            ))
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/artifacts/../../etc/passwd")
+            .reply(&route)
+            .await;
+
+        assert_ne!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path(&format!("/artifacts/../..?token={}", token))
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 401);
+    }
+
+    async fn upload_artifact_test_handler(
+        _repo_name: String,
+        _commit_sha: String,
+        _path: Tail,
+        contents: ContentStream,
+    ) -> std::result::Result<impl warp::reply::Reply, warp::Rejection> {
+        let chunks: Vec<Bytes> = contents.chunks.try_collect().await.unwrap();
+
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn should_turn_away_artifacts_over_the_max_size() {
+        let route = upload_artifact_route(WEBHOOK_SECRET.to_string(), 8)
+            .and_then(upload_artifact_test_handler)
+            .recover(handle_rejection);
+
+        let commit_sha = "0123456789abcdef0123456789abcdef01234567";
+        let token = crate::artifacts::artifact_token(WEBHOOK_SECRET, "some/repo", commit_sha);
+        let path = format!(
+            "/artifacts/some.repo/{}/report.txt?token={}",
+            commit_sha, token
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .body("report")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "report");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .body("a much longer report")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 413);
+    }
+
+    async fn pipeline_upload_test_handler(
+        _repo_name: String,
+        _commit_sha: String,
+        _build: String,
+        _step_section: usize,
+        _step_id: String,
+        _steps: Bytes,
+    ) -> std::result::Result<impl warp::reply::Reply, warp::Rejection> {
+        Ok(warp::reply())
+    }
+
+    #[tokio::test]
+    async fn should_only_accept_pipeline_uploads_with_the_step_token() {
+        let route = upload_pipeline_route(WEBHOOK_SECRET.to_string(), 16)
+            .and_then(pipeline_upload_test_handler)
+            .recover(handle_rejection);
+
+        let token = crate::artifacts::pipeline_upload_token(
+            WEBHOOK_SECRET,
+            "some/repo",
+            "abcdef",
+            "push",
+            0,
+            "6c696e74",
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/pipeline-uploads/some.repo/abcdef/push/0/6c696e74?token={}",
+                token
+            ))
+            .body("steps: []")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/pipeline-uploads/some.repo/abcdef/push/0/6c696e74?token={}",
+                token
+            ))
+            .body("steps: [a, b, c, d, e]")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 413);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/pipeline-uploads/some.repo/abcdef/pull-request-1/0/6c696e74?token={}",
+                token
+            ))
+            .body("steps: []")
+            .reply(&route)
+            .await;
+
+        assert_eq!(response.status(), 401);
+
+        for path in &[
+            "/pipeline-uploads/some.repo/abcdef/push/1/6c696e74",
+            "/pipeline-uploads/some.repo/abcdef/push/0/74657374",
+        ] {
+            let response = warp::test::request()
+                .method("POST")
+                .path(&format!("{}?token={}", path, token))
+                .body("steps: []")
+                .reply(&route)
+                .await;
+
+            assert_eq!(response.status(), 401);
+        }
     }
 }